@@ -1,7 +1,47 @@
 #ifndef CoreTextRuns_Bridging_Header_h
 #define CoreTextRuns_Bridging_Header_h
 
+#include <stddef.h>
+#include <stdint.h>
+
 extern void split_str_into_runs(const char *text, double font_size);
 extern void split_and_shape_text(const char *text, double font_size);
+// Same as split_and_shape_text with the base font built from a PostScript name (NULL = system font)
+extern void split_and_shape_text_with_font(const char *text, const char *font_name, double font_size);
+// Returns 0 on success or a negative CT_RUNS_ERR_* code; writes the run count to out_run_count
+extern int split_and_shape_text_with_status(const char *text, double font_size, size_t *out_run_count);
+
+// One glyph of a shaped run; advances and offsets are in 1/position_scale em of its run
+typedef struct {
+    uint32_t id;
+    uint32_t cluster;
+    int32_t x_advance;
+    int32_t y_advance;
+    int32_t x_offset;
+    int32_t y_offset;
+} GlyphInfo;
+
+typedef struct {
+    char *font_name;
+    size_t utf16_start;
+    size_t utf16_length;
+    GlyphInfo *glyphs;
+    size_t glyph_count;
+    double font_size;
+    // Glyph positions are in 1/position_scale em; not necessarily the font's unitsPerEm
+    int32_t position_scale;
+} CShapeRun;
+
+// Itemizes and shapes text into an array of runs; returns 0 or a negative CT_RUNS_ERR_* code.
+// Free the array with ct_shape_runs_free.
+extern int ct_shape_runs(const char *text, double font_size, CShapeRun **out, size_t *out_len);
+extern void ct_shape_runs_free(CShapeRun *runs, size_t len);
+
+// Allocator for the buffers ct_shape_runs returns; pass NULL for either to use the default.
+// Each buffer is freed with the free it was allocated with, so it can change at any time.
+// CT_RUNS_ERR_ALLOCATION (-5) means it failed.
+typedef void *(*CtRunsAllocFn)(size_t size, size_t align);
+typedef void (*CtRunsFreeFn)(void *ptr, size_t size, size_t align);
+extern void ct_runs_set_allocator(CtRunsAllocFn alloc, CtRunsFreeFn free);
 
 #endif /* CoreTextRuns_Bridging_Header_h */
@@ -1,16 +1,28 @@
 use core_foundation::string::CFString;
-use core_foundation::attributed_string::CFMutableAttributedString;
-use core_foundation::base::{TCFType, CFRange};
+use core_foundation::attributed_string::{CFAttributedStringRef, CFMutableAttributedString};
+use core_foundation::array::CFArray;
+use core_foundation::base::{CFType, TCFType, CFRange};
+use core_foundation::data::CFData;
+use core_foundation::number::CFNumber;
 use core_text::font::CTFont;
+use core_text::frame::CTFrame;
 use core_text::framesetter::CTFramesetter;
-use core_text::line::CTLine;
-use core_text::run::CTRun;
-use core_graphics::path::CGPath;
-use core_graphics::geometry::{CGRect, CGPoint, CGSize};
+use core_text::line::{CTLine, CTLineRef};
+use core_text::run::{CTRun, CTRunRef};
+use core_graphics::path::{CGPath, CGPathElementType};
+use core_graphics::geometry::{CGAffineTransform, CGRect, CGPoint, CGSize};
+use foreign_types::ForeignType;
+use std::ops::Range;
 use std::ptr;
 use std::os::raw::c_void;
 // Using harfbuzz_sys directly for low-level HarfBuzz API
 
+// Report a recoverable problem (a dropped run, a fallback taken) on stderr, keeping stdout for
+// the output of the split_and_shape_text functions. Takes a string or format_args!(...).
+fn warn(message: impl std::fmt::Display) {
+    eprintln!("core-text-runs: warning: {}", message);
+}
+
 // CTFontCreateUIFontForLanguage function signature
 #[link(name = "CoreText", kind = "framework")]
 extern "C" {
@@ -21,11 +33,16 @@ extern "C" {
     ) -> *mut c_void;
 }
 
-// kCTFontAttributeName constant declaration
-// This is a CFStringRef constant exported by CoreText framework
+// Attribute name constant declarations (kCTFontAttributeName, kCTLigatureAttributeName, ...)
+// These are CFStringRef constants exported by CoreText framework
 #[link(name = "CoreText", kind = "framework")]
 extern "C" {
     static kCTFontAttributeName: *const c_void;
+    static kCTLigatureAttributeName: *const c_void;
+    static kCTParagraphStyleAttributeName: *const c_void;
+    static kCTUnderlineStyleAttributeName: *const c_void;
+    static kCTBaselineOffsetAttributeName: *const c_void;
+    static kCTLanguageAttributeName: *const c_void;
 }
 
 // Helper function to safely get the font attribute name
@@ -51,7 +68,46 @@ fn get_font_attribute_name() -> Option<*const c_void> {
     }
 }
 
-const K_CTFONT_UIFONT_SYSTEM: u32 = 2;
+// kCTFontAttributeName, for callers building their own attributed strings (e.g. for
+// collect_runs_from_attributed_string). None if the constant fails get_font_attribute_name's checks.
+pub fn font_attribute_key() -> Option<CFString> {
+    // A CoreText constant: wrapping it under the get rule retains it for the CFString's lifetime
+    get_font_attribute_name().map(|key| unsafe { CFString::wrap_under_get_rule(key as *const _) })
+}
+
+// CoreText UI font types (values of CTFontUIFontType)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UIFontType {
+    User = 0,
+    UserFixedPitch = 1,
+    #[default]
+    System = 2,
+    // Bold system font
+    EmphasizedSystem = 3,
+    SmallSystem = 4,
+    SmallEmphasizedSystem = 5,
+    MiniSystem = 6,
+    MiniEmphasizedSystem = 7,
+    Views = 8,
+    Application = 9,
+    Label = 10,
+    MenuTitle = 11,
+    MenuItem = 12,
+    MenuItemMark = 13,
+    MenuItemCmdKey = 14,
+    WindowTitle = 15,
+    PushButton = 16,
+    UtilityWindowTitle = 17,
+    AlertHeader = 18,
+    SystemDetail = 19,
+    EmphasizedSystemDetail = 20,
+    Toolbar = 21,
+    SmallToolbar = 22,
+    Message = 23,
+    Palette = 24,
+    ToolTip = 25,
+    ControlContent = 26,
+}
 
 // Structure to hold run information
 #[derive(Debug, Clone)]
@@ -60,11 +116,393 @@ pub struct TextRun {
     pub font_name: String,
     pub start_utf16: usize,
     pub length_utf16: usize,
-    // CRITICAL: This is a borrowed reference from the attributes dictionary.
-    // The font is retained by the attributed string/run - NEVER release it.
-    // It will be automatically released when the attributed string/run is deallocated.
-    // Stored as u64 to avoid pointer lifetime issues
-    pub font_ptr: u64,
+    // The run's CTFont, retained for as long as the run (or a clone of it) is alive
+    pub font: FontRef,
+    // Point size of the run's CTFont (CTFontGetSize); fallback fonts may differ from the requested size
+    pub font_size: f64,
+    // Underline/strikethrough attributes carried by the run's text
+    pub decorations: TextDecorations,
+    // Value of a caller-registered numeric attribute (see collect_styled_runs), e.g. an app style id
+    pub style_id: Option<u64>,
+    // Vertical shift of the run's baseline in points (kCTBaselineOffsetAttributeName), positive is up.
+    // CoreText does not move the glyphs itself; renderers add it to the line's baseline.
+    pub baseline_offset: f64,
+    // CTRunGetStatus flags of the CTRun this run came from
+    pub status: RunStatus,
+    // CTRunGetGlyphCount of the CTRun this run came from, read during itemization. None when the
+    // run no longer matches a CTRun: pieces from split_at_boundaries and friends, runs whose text
+    // or font changed afterwards (strip_line_terminators, aggressive_fallback, merged marks).
+    pub coretext_glyph_count: Option<usize>,
+}
+
+// Flags CoreText reports for a run (CTRunStatus)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RunStatus {
+    // Glyphs are in right-to-left order (kCTRunStatusRightToLeft)
+    pub right_to_left: bool,
+    // Glyph order does not follow character order in either direction, e.g. a reordered Indic
+    // vowel sign (kCTRunStatusNonMonotonic). Glyph-to-character mappings must not assume that
+    // clusters only increase (or only decrease) across such a run.
+    pub non_monotonic: bool,
+    // The run's glyphs are drawn with a text matrix other than identity (kCTRunStatusHasNonIdentityMatrix)
+    pub non_identity_matrix: bool,
+}
+
+impl RunStatus {
+    fn from_ct(status: u32) -> Self {
+        RunStatus {
+            right_to_left: status & 1 != 0,
+            non_monotonic: status & 2 != 0,
+            non_identity_matrix: status & 4 != 0,
+        }
+    }
+}
+
+// Text decorations read from a run's attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextDecorations {
+    pub underline: UnderlineStyle,
+    // Whether the run has a nonzero strikethrough style (NSStrikethroughStyleAttributeName)
+    pub strikethrough: bool,
+}
+
+// Line style of kCTUnderlineStyleAttributeName (its low byte; pattern bits are ignored)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnderlineStyle {
+    #[default]
+    None,
+    Single,
+    Thick,
+    Double,
+}
+
+impl UnderlineStyle {
+    // Map a CTUnderlineStyle value (style in the low byte, CTUnderlineStyleModifiers pattern above it)
+    fn from_ct(value: i32) -> Self {
+        match value & 0xff {
+            0x00 => UnderlineStyle::None,
+            0x02 => UnderlineStyle::Thick,
+            0x09 => UnderlineStyle::Double,
+            // kCTUnderlineStyleSingle, and any unknown style still draws a line
+            _ => UnderlineStyle::Single,
+        }
+    }
+}
+
+impl TextRun {
+    // True for a run made up only of control/format characters (see is_control_character),
+    // as produced with LayoutOptions::control_runs
+    pub fn is_control(&self) -> bool {
+        !self.text.is_empty() && self.text.chars().all(is_control_character)
+    }
+}
+
+// Invisible format controls with special shaping or bidi behavior: ZWSP, ZWNJ/ZWJ, LRM/RLM/ALM,
+// bidi embeddings/overrides (U+202A..U+202E) and isolates (U+2066..U+2069), word joiner and
+// invisible operators (U+2060..U+2064), and ZWNBSP/BOM
+pub fn is_control_character(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}'
+        | '\u{2066}'..='\u{2069}' | '\u{061C}' | '\u{FEFF}')
+}
+
+// Bidi embedding/override (U+202A..U+202E) and isolate (U+2066..U+2069) controls, which change
+// the direction of the text after them until they are closed
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// Whether `text` has bidi embedding, override or isolate controls that could leak into the text
+// around it when embedded (LRM/RLM/ALM marks only affect neighbors and are not counted)
+pub fn contains_bidi_controls(text: &str) -> bool {
+    text.chars().any(is_bidi_control)
+}
+
+// Text sanitized by isolate_bidi, with the way back to the original's UTF-16 offsets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsolatedText {
+    pub text: String,
+    // original_utf16[i] is the original UTF-16 offset of UTF-16 offset i of `text`, for every
+    // offset from 0 to the length. The opening isolate maps to 0 and the closing one to the
+    // original length; stripped controls have no offset of their own.
+    pub original_utf16: Vec<usize>,
+}
+
+// Prepare `text` for embedding in a template: strip its embedding, override and isolate controls
+// and wrap it in an isolate (LRI for LeftToRight, RLI for RightToLeft, FSI to take the direction of
+// its first strong character) closed by PDI, so it cannot reorder the surrounding text.
+pub fn isolate_bidi(text: &str, direction: Option<TextDirection>) -> IsolatedText {
+    let opening = match direction {
+        Some(TextDirection::LeftToRight) => '\u{2066}',
+        Some(TextDirection::RightToLeft) => '\u{2067}',
+        None => '\u{2068}',
+    };
+    let mut out = String::with_capacity(text.len() + 6);
+    let mut original_utf16 = Vec::with_capacity(utf16_len(text) + 3);
+    out.push(opening);
+    original_utf16.push(0);
+    let mut offset = 0;
+    for c in text.chars() {
+        if !is_bidi_control(c) {
+            out.push(c);
+            original_utf16.extend(offset..offset + c.len_utf16());
+        }
+        offset += c.len_utf16();
+    }
+    out.push('\u{2069}');
+    original_utf16.push(offset);
+    original_utf16.push(offset);
+    IsolatedText { text: out, original_utf16 }
+}
+
+// A retained CTFont. Cloning retains it again and dropping releases it, so runs can be freely
+// copied, shaped and dropped without manual CFRetain/CFRelease. May be null for synthetic runs.
+pub struct FontRef(*const c_void);
+
+// CTFont is immutable and safe to use from any thread
+unsafe impl Send for FontRef {}
+unsafe impl Sync for FontRef {}
+
+impl FontRef {
+    // Take over one retain the caller already owns on `font` (e.g. from a create-rule call)
+    pub(crate) unsafe fn from_retained(font: *const c_void) -> Self {
+        FontRef(font)
+    }
+    
+    // Retain `font` and wrap it; for fonts borrowed under the get rule
+    pub(crate) unsafe fn retain(font: *const c_void) -> Self {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFRetain(cf: *const c_void) -> *const c_void;
+        }
+        if font.is_null() {
+            FontRef(font)
+        } else {
+            FontRef(CFRetain(font))
+        }
+    }
+    
+    pub fn is_null(&self) -> bool {
+        self.0.is_null()
+    }
+    
+    // The CTFontRef, for FFI. Borrowed: valid only while this FontRef is alive.
+    pub fn as_ptr(&self) -> *const c_void {
+        self.0
+    }
+    
+    // PostScript name; None for a null font
+    pub fn postscript_name(&self) -> Option<String> {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
+        }
+        if self.is_null() {
+            return None;
+        }
+        unsafe {
+            let name_ref = CTFontCopyPostScriptName(self.0);
+            if name_ref.is_null() {
+                return None;
+            }
+            Some(CFString::wrap_under_create_rule(name_ref as *mut _).to_string())
+        }
+    }
+    
+    // Point size (CTFontGetSize); 0.0 for a null font
+    pub fn size(&self) -> f64 {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontGetSize(font: *const c_void) -> f64;
+        }
+        if self.is_null() {
+            return 0.0;
+        }
+        unsafe { CTFontGetSize(self.0) }
+    }
+    
+    // Design units per em (CTFontGetUnitsPerEm); 0 for a null font
+    pub fn units_per_em(&self) -> u32 {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontGetUnitsPerEm(font: *const c_void) -> u32;
+        }
+        if self.is_null() {
+            return 0;
+        }
+        unsafe { CTFontGetUnitsPerEm(self.0) }
+    }
+    
+    // Ascent in points (CTFontGetAscent); 0.0 for a null font
+    pub fn ascent(&self) -> f64 {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontGetAscent(font: *const c_void) -> f64;
+        }
+        if self.is_null() {
+            return 0.0;
+        }
+        unsafe { CTFontGetAscent(self.0) }
+    }
+    
+    // Descent in points, positive below the baseline (CTFontGetDescent); 0.0 for a null font
+    pub fn descent(&self) -> f64 {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontGetDescent(font: *const c_void) -> f64;
+        }
+        if self.is_null() {
+            return 0.0;
+        }
+        unsafe { CTFontGetDescent(self.0) }
+    }
+}
+
+impl Default for FontRef {
+    fn default() -> Self {
+        FontRef(ptr::null())
+    }
+}
+
+impl Clone for FontRef {
+    fn clone(&self) -> Self {
+        unsafe { FontRef::retain(self.0) }
+    }
+}
+
+impl Drop for FontRef {
+    fn drop(&mut self) {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFRelease(cf: *const c_void);
+        }
+        if !self.0.is_null() {
+            unsafe { CFRelease(self.0) };
+        }
+    }
+}
+
+impl PartialEq for FontRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl std::fmt::Debug for FontRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FontRef({:p})", self.0)
+    }
+}
+
+impl From<CTFont> for FontRef {
+    // Take over the CTFont wrapper's retain
+    fn from(font: CTFont) -> Self {
+        let font_ptr = font.as_concrete_TypeRef() as *const c_void;
+        std::mem::forget(font);
+        FontRef(font_ptr)
+    }
+}
+
+// CoreText ligature level (values of kCTLigatureAttributeName)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LigatureMode {
+    // Only ligatures essential for proper rendering
+    Essential = 0,
+    // Standard ligatures (CoreText's default)
+    Standard = 1,
+    // All available ligatures
+    All = 2,
+}
+
+// Attributes applied to the attributed string before CoreText itemization
+#[derive(Debug, Clone, Default)]
+pub struct LayoutOptions {
+    // Ligature level for the whole string; None leaves CoreText's default
+    pub ligatures: Option<LigatureMode>,
+    // Wrap lines at this width in points; None lays out on unbounded lines
+    pub max_width: Option<f64>,
+    // UTF-16 ranges where CoreText must not break lines. Spaces inside them are laid out
+    // as U+00A0 (same UTF-16 length, so run offsets and run text are unaffected).
+    // NBSP, U+2060 WORD JOINER and U+FEFF ZWNBSP already in the text are honored without this.
+    pub non_breaking_ranges: Vec<Range<usize>>,
+    // When measuring empty text, report one line height of the base font instead of zero
+    pub empty_text_line_height: bool,
+    // What to do with a run whose UTF-16 range does not decode (e.g. a split surrogate pair)
+    pub invalid_utf16: InvalidUtf16,
+    // Left-aligned tab stop locations in points, set through the paragraph style.
+    // See column_tab_stops for computing stops that align tab-separated columns.
+    pub tab_stops: Vec<f64>,
+    // PostScript name of the base font; None (or a name that isn't installed) uses the UI font of `ui_font`
+    pub font_name: Option<String>,
+    // UI font type used as the base font when `font_name` is not set
+    pub ui_font: UIFontType,
+    // Report control/format characters (see is_control_character) as separate runs instead of
+    // mixing them into the visible text around them; check them with TextRun::is_control
+    pub control_runs: bool,
+    // After itemization, retry runs whose font still lacks glyphs with CoreText's best font
+    // for their text (see retry_tofu_runs). Opt-in since it costs an extra lookup per such run.
+    pub aggressive_fallback: bool,
+    // Baseline offsets in points for UTF-16 ranges, e.g. a positive offset for a superscript.
+    // Reported back on the affected runs as TextRun::baseline_offset.
+    pub baseline_offsets: Vec<(Range<usize>, f64)>,
+    // Lay out at most this many lines, see collect_truncated_runs
+    pub max_lines: Option<usize>,
+    // Where collect_truncated_runs elides text from the last line
+    pub truncation: TruncationMode,
+    // Leave each line's trailing whitespace out of measured widths (CTLineGetTrailingWhitespaceWidth),
+    // e.g. for right or center alignment; by default it counts, as in CTLineGetTypographicBounds
+    pub exclude_trailing_whitespace: bool,
+    // Transform applied to the layout rectangle (max_width wide, or the container of line_origins)
+    // before the frame is created. CoreText then lays lines out inside the transformed rectangle's
+    // bounding box, and line origins are reported in that space; glyphs themselves are not scaled or
+    // rotated, and run ranges and shaping are unchanged, so apply the same transform when drawing.
+    // Paths passed to collect_runs_in_path are used as given.
+    pub path_transform: Option<CGAffineTransform>,
+    // Remove trailing line terminators (CR, LF, NEL, LS, PS, VT, FF) from run text, shrinking
+    // length_utf16 to match. Runs made up only of terminators are dropped.
+    pub strip_line_terminators: bool,
+    // Line height as a multiple of the font's natural line height (kCTParagraphStyleSpecifierLineHeightMultiple)
+    pub line_height_multiple: Option<f64>,
+    // Extra space in points after each paragraph (kCTParagraphStyleSpecifierParagraphSpacing)
+    pub paragraph_spacing: Option<f64>,
+    // Bounds in points on each line's height (kCTParagraphStyleSpecifierMinimumLineHeight/
+    // MaximumLineHeight); set both to the same value to pin line height, e.g. for a baseline grid
+    pub min_line_height: Option<f64>,
+    pub max_line_height: Option<f64>,
+    // Preferred fonts per script: (ISO 15924 tag such as *b"Arab", PostScript name). Text of a
+    // listed script (with the spaces and punctuation that follow it) is set in that font before
+    // itemization, overriding CoreText's fallback; names that aren't installed are ignored.
+    pub script_fonts: Vec<([u8; 4], String)>,
+    // BCP 47 language of the text (e.g. "tr", "ja"), set as kCTLanguageAttributeName so CoreText
+    // picks language-appropriate fallback fonts. Pass the same tag as ShapeOptions::language
+    // (shape_options_for_layout does, and the split_and_shape_text functions use it).
+    pub locale: Option<String>,
+    // Return runs in logical (character) order, sorted by start_utf16, instead of CoreText's visual
+    // order within each line. collect_run_order gives the visual order and the mapping between them.
+    pub logical_order: bool,
+}
+
+// Where text is elided when it does not fit (values of CTLineTruncationType)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TruncationMode {
+    // "…end of text"
+    Start = 0,
+    // "Start of text…"
+    #[default]
+    End = 1,
+    // "Start…end"
+    Middle = 2,
+}
+
+// Handling of run text that is not valid UTF-16
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf16 {
+    // Replace undecodable units with U+FFFD so no text is silently dropped
+    #[default]
+    Lossy,
+    // Keep the run with empty text
+    Empty,
+    // Drop the run entirely
+    Skip,
 }
 
 // Structure to hold shaping results
@@ -77,17 +515,236 @@ pub struct ShapingResult {
     pub cluster_indices: Vec<u32>,
     pub x_advances: Vec<i32>,
     pub y_advances: Vec<i32>,
+    pub x_offsets: Vec<i32>,
+    pub y_offsets: Vec<i32>,
+    // Point size of the run's CTFont
+    pub font_size: f64,
+    // Position units per em: advances and offsets are in 1/position_scale em. This is the hb_font
+    // x scale by default, the face's units per em with ShapeOptions::design_units, and 64 units per
+    // device pixel with ShapeOptions::pixel_scale; it is not necessarily the font's unitsPerEm.
+    pub position_scale: i32,
+    // ISO 15924 tag of the script the run was shaped as (e.g. *b"Latn")
+    pub script: [u8; 4],
+}
+
+// One glyph packed for GPU upload, see ShapingResult::to_vertex_buffer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlyphVertex {
+    pub glyph_id: u32,
+    pub x: f32,
+    pub y: f32,
+    pub advance: f32,
+}
+
+// One glyph's shaping data interleaved in a single struct, so FFI callers get one array
+// instead of six; see ShapingResult::to_glyph_infos. Positions are in HarfBuzz units.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlyphInfo {
+    pub id: u32,
+    pub cluster: u32,
+    pub x_advance: i32,
+    pub y_advance: i32,
+    pub x_offset: i32,
+    pub y_offset: i32,
+}
+
+// Borrowed views of a ShapingResult's glyph arrays, see ShapingResult::as_glyph_slices
+#[derive(Debug, Clone, Copy)]
+pub struct GlyphSlices<'a> {
+    pub glyph_ids: &'a [u32],
+    pub cluster_indices: &'a [u32],
+    pub x_advances: &'a [i32],
+    pub y_advances: &'a [i32],
+    pub x_offsets: &'a [i32],
+    pub y_offsets: &'a [i32],
+}
+
+impl ShapingResult {
+    // Borrow all glyph arrays at once, without copying, e.g. to hand them to a renderer
+    pub fn as_glyph_slices(&self) -> GlyphSlices<'_> {
+        GlyphSlices {
+            glyph_ids: &self.glyph_ids,
+            cluster_indices: &self.cluster_indices,
+            x_advances: &self.x_advances,
+            y_advances: &self.y_advances,
+            x_offsets: &self.x_offsets,
+            y_offsets: &self.y_offsets,
+        }
+    }
+    
+    // Points per HarfBuzz position unit for this result
+    fn points_per_unit(&self) -> f64 {
+        if self.position_scale == 0 {
+            0.0
+        } else {
+            self.font_size / self.position_scale as f64
+        }
+    }
+    
+    // Human-readable table of the glyphs (id, cluster, advance and offsets in points) for logs
+    pub fn pretty(&self) -> String {
+        use std::fmt::Write;
+        
+        let scale = self.points_per_unit();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:?} in {} at {}pt: {} glyphs",
+            self.run_text, self.font_name, self.font_size, self.glyph_count
+        );
+        let _ = writeln!(out, "{:>6} {:>8} {:>7} {:>10} {:>10} {:>10}", "index", "glyph", "cluster", "advance", "x_offset", "y_offset");
+        for i in 0..self.glyph_count {
+            let _ = writeln!(
+                out,
+                "{:>6} {:>8} {:>7} {:>10.3} {:>10.3} {:>10.3}",
+                i,
+                self.glyph_ids[i],
+                self.cluster_indices[i],
+                self.x_advances[i] as f64 * scale,
+                self.x_offsets[i] as f64 * scale,
+                self.y_offsets[i] as f64 * scale,
+            );
+        }
+        out
+    }
+    
+    // True if the glyphs are not in input order, i.e. cluster_indices ever decrease. This is the
+    // case for right-to-left runs, whose glyphs come out in visual order. HarfBuzz merges the
+    // clusters of glyphs it reorders within a syllable (e.g. an Indic pre-base vowel sign), so
+    // those keep monotonic clusters and are not reported.
+    pub fn is_reordered(&self) -> bool {
+        self.cluster_indices.windows(2).any(|pair| pair[0] > pair[1])
+    }
+    
+    // X position of each glyph in points (pen position from the previous advances plus its own
+    // x offset), followed by the final pen position: glyph_count + 1 values
+    pub fn cumulative_x(&self) -> Vec<f64> {
+        let scale = self.points_per_unit();
+        let mut pen_x: i64 = 0;
+        let mut out = Vec::with_capacity(self.glyph_count + 1);
+        
+        for i in 0..self.glyph_count {
+            out.push((pen_x + self.x_offsets[i] as i64) as f64 * scale);
+            pen_x += self.x_advances[i] as i64;
+        }
+        out.push(pen_x as f64 * scale);
+        
+        out
+    }
+    
+    // Hash of what the result draws, for render cache keys: the font (name, size, position units),
+    // glyph ids, advances and offsets, but not the source text or clusters, so the same glyphs from
+    // different text hash equally. FNV-1a, so the value is stable across runs and Rust versions.
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        let mut hash = FNV_OFFSET;
+        let mut write = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+        
+        write(self.font_name.as_bytes());
+        // Terminate the name so it cannot run into the numbers after it
+        write(&[0]);
+        write(&self.font_size.to_bits().to_le_bytes());
+        write(&self.position_scale.to_le_bytes());
+        write(&(self.glyph_count as u64).to_le_bytes());
+        for i in 0..self.glyph_count {
+            write(&self.glyph_ids[i].to_le_bytes());
+            write(&self.x_advances[i].to_le_bytes());
+            write(&self.y_advances[i].to_le_bytes());
+            write(&self.x_offsets[i].to_le_bytes());
+            write(&self.y_offsets[i].to_le_bytes());
+        }
+        hash
+    }
+    
+    // The glyph arrays interleaved into one GlyphInfo per glyph, e.g. to hand C callers a single buffer
+    pub fn to_glyph_infos(&self) -> Vec<GlyphInfo> {
+        (0..self.glyph_count)
+            .map(|i| GlyphInfo {
+                id: self.glyph_ids[i],
+                cluster: self.cluster_indices[i],
+                x_advance: self.x_advances[i],
+                y_advance: self.y_advances[i],
+                x_offset: self.x_offsets[i],
+                y_offset: self.y_offsets[i],
+            })
+            .collect()
+    }
+    
+    // Pack the glyphs into a flat buffer for GPU upload.
+    // Coordinates are in points relative to the run's pen origin (the start of its baseline),
+    // with y pointing up as in CoreGraphics. Each glyph's (x, y) is the pen position accumulated
+    // from the previous glyphs' advances plus its own offset; `advance` is its x advance.
+    pub fn to_vertex_buffer(&self) -> Vec<GlyphVertex> {
+        let scale = self.points_per_unit();
+        let mut pen_x: i64 = 0;
+        let mut pen_y: i64 = 0;
+        let mut out = Vec::with_capacity(self.glyph_count);
+        
+        for i in 0..self.glyph_count {
+            out.push(GlyphVertex {
+                glyph_id: self.glyph_ids[i],
+                x: ((pen_x + self.x_offsets[i] as i64) as f64 * scale) as f32,
+                y: ((pen_y + self.y_offsets[i] as i64) as f64 * scale) as f32,
+                advance: (self.x_advances[i] as f64 * scale) as f32,
+            });
+            pen_x += self.x_advances[i] as i64;
+            pen_y += self.y_advances[i] as i64;
+        }
+        
+        out
+    }
 }
 
 fn create_base_font(size: f64) -> CTFont {
+    create_ui_ct_font(UIFontType::System, size).expect("The system UI font is always available")
+}
+
+// The UI font of `font_type` at `size` points (0.0 uses the type's default size).
+// None if CoreText has no font for the type.
+pub fn create_ui_font(font_type: UIFontType, size: f64) -> Option<FontRef> {
+    create_ui_ct_font(font_type, size).map(FontRef::from)
+}
+
+fn create_ui_ct_font(font_type: UIFontType, size: f64) -> Option<CTFont> {
     unsafe {
         let font_ref = CTFontCreateUIFontForLanguage(
-            K_CTFONT_UIFONT_SYSTEM,
+            font_type as u32,
             size,
             ptr::null(),
         );
+        if font_ref.is_null() {
+            return None;
+        }
         // Use the TCFType trait method
-        <CTFont as TCFType>::wrap_under_create_rule(font_ref as *mut _)
+        Some(<CTFont as TCFType>::wrap_under_create_rule(font_ref as *mut _))
+    }
+}
+
+// Create the base font for layout: options.font_name if it is installed, otherwise the UI font of options.ui_font.
+// CTFontCreateWithName substitutes a default font for unknown names, so the result is checked by name.
+fn create_layout_font(font_size: f64, options: &LayoutOptions) -> CTFont {
+    if let Some(name) = &options.font_name {
+        match installed_font(name, font_size) {
+            Some(font) => return font,
+            None => warn(format_args!("font \"{}\" not found, falling back to system font", name)),
+        }
+    }
+    create_ui_ct_font(options.ui_font, font_size).unwrap_or_else(|| create_base_font(font_size))
+}
+
+// The font with PostScript name `name`, or None if CoreText would substitute another one
+fn installed_font(name: &str, font_size: f64) -> Option<CTFont> {
+    match core_text::font::new_from_name(name, font_size) {
+        Ok(font) if font.postscript_name().eq_ignore_ascii_case(name) => Some(font),
+        _ => None,
     }
 }
 
@@ -107,10 +764,21 @@ pub extern "C" fn split_str_into_runs(text: *const i8, font_size: f64) {
 
 // Internal structure for collecting runs (before UTF-8 conversion)
 struct RunRaw {
-    utf16_location: isize,
-    utf16_length: isize,
+    // Validated against the text by validated_run_range, so never negative
+    utf16_location: usize,
+    utf16_length: usize,
     postscript_name: String,
     font_ptr: *const c_void,
+    // Borrowed from the frame's line; only valid while the frame is alive
+    ct_run: *const c_void,
+    // Index of the frame line the run belongs to
+    line_index: usize,
+    // CTFontGetSize of the run's font
+    font_size: f64,
+    decorations: TextDecorations,
+    baseline_offset: f64,
+    status: RunStatus,
+    glyph_count: usize,
 }
 
 // Collect runs from a CTFrame - following the pattern from the reference implementation
@@ -121,17 +789,12 @@ fn collect_runs_from_frame(text: &str, frame: *const c_void) -> Vec<RunRaw> {
         #[link(name = "CoreText", kind = "framework")]
         extern "C" {
             fn CTFrameGetLines(frame: *const c_void) -> *const c_void;
-            fn CTLineGetGlyphRuns(line: *const c_void) -> *const c_void;
-            fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
-            fn CTRunGetStringRange(run: *const c_void) -> CFRange;
-            fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
         }
         
         #[link(name = "CoreFoundation", kind = "framework")]
         extern "C" {
             fn CFArrayGetCount(array: *const c_void) -> isize;
             fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
-            fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
         }
         
         let lines = CTFrameGetLines(frame);
@@ -139,7 +802,7 @@ fn collect_runs_from_frame(text: &str, frame: *const c_void) -> Vec<RunRaw> {
             return out;
         }
         
-        let utf16_total = text.encode_utf16().count() as isize;
+        let utf16_total = utf16_len(text);
         let line_count = CFArrayGetCount(lines);
         
         for line_idx in 0..line_count {
@@ -147,197 +810,280 @@ fn collect_runs_from_frame(text: &str, frame: *const c_void) -> Vec<RunRaw> {
             if line.is_null() {
                 continue;
             }
-            
-            let runs = CTLineGetGlyphRuns(line);
-            if runs.is_null() {
-                continue;
-            }
-            
-            let run_count = CFArrayGetCount(runs);
-            for run_idx in 0..run_count {
-                let run = CFArrayGetValueAtIndex(runs, run_idx) as *const c_void;
-                if run.is_null() {
-                    continue;
-                }
-                
-                let range = CTRunGetStringRange(run);
-                if range.location < 0 || range.length < 0 || range.location + range.length > utf16_total {
-                    continue;
-                }
-                
-                let attrs = CTRunGetAttributes(run);
-                if attrs.is_null() {
-                    continue;
-                }
-                
-                // Get font pointer from attributes dictionary using kCTFontAttributeName directly
-                // CRITICAL: This is a borrowed reference from the attributes dictionary.
-                // We need to retain it to ensure it stays valid after the frame is dropped.
-                let font_ptr = CFDictionaryGetValue(attrs, kCTFontAttributeName as *const c_void) as *const c_void;
-                if font_ptr.is_null() {
-                    continue;
-                }
-                
-                // Retain the font to ensure it stays valid after the frame is dropped
-                #[link(name = "CoreFoundation", kind = "framework")]
-                extern "C" {
-                    fn CFRetain(cf: *const c_void) -> *const c_void;
-                }
-                let retained_font_ptr = CFRetain(font_ptr);
-                if retained_font_ptr.is_null() {
-                    continue;
-                }
-                
-                // Get PostScript name from font
-                let ps_name_ref = CTFontCopyPostScriptName(retained_font_ptr);
-                if ps_name_ref.is_null() {
-                    continue;
-                }
-                let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
-                let ps_name = ps_name_cf.to_string();
-                if ps_name.is_empty() {
-                    continue;
-                }
-                
-                out.push(RunRaw {
-                    utf16_location: range.location,
-                    utf16_length: range.length,
-                    postscript_name: ps_name,
-                    font_ptr: retained_font_ptr, // Retained reference - must be released later
-                });
-            }
+            collect_runs_from_line(line, line_idx as usize, utf16_total, &mut out);
         }
     }
     
     out
 }
 
-// Function to collect runs from text
-fn collect_runs(text: &str, font_size: f64) -> Vec<TextRun> {
-    // Create base font using system UI font
-    let font = create_base_font(font_size);
+// Convert a CTRun's string range to (location, length) in UTF-16 units, or None (with a warning) if it is negative
+// or reaches past `utf16_total`. The only place CFIndex ranges become usize, so no cast can wrap.
+fn validated_run_range(range: CFRange, utf16_total: usize) -> Option<(usize, usize)> {
+    let valid = match (usize::try_from(range.location), usize::try_from(range.length)) {
+        (Ok(location), Ok(length)) => match location.checked_add(length) {
+            Some(end) if end <= utf16_total => Some((location, length)),
+            _ => None,
+        },
+        _ => None,
+    };
+    if valid.is_none() {
+        warn(format_args!(
+            "run range start {} + length {} is outside the text's {} UTF-16 units, dropping run",
+            range.location, range.length, utf16_total
+        ));
+    }
+    valid
+}
+
+// Whether a run at UTF-16 `range` (location, length) is an artifact to skip: zero-length, or an
+// exact repeat of the `previous` run's range. CoreText occasionally emits both; keeping them would
+// double-count text in coverage and width sums.
+fn is_degenerate_run(range: (usize, usize), previous: Option<(usize, usize)>) -> bool {
+    if range.1 == 0 {
+        warn(format_args!("skipping zero-length run at UTF-16 {}", range.0));
+        return true;
+    }
+    if previous == Some(range) {
+        warn(format_args!("skipping duplicate run at UTF-16 {}..{}", range.0, range.0 + range.1));
+        return true;
+    }
+    false
+}
+
+// Attribute marking the runs of a truncation token (see collect_truncated_runs); such runs have
+// no range in the source text and are skipped by collect_runs_from_line
+const TRUNCATION_TOKEN_KEY: &str = "CoreTextRunsTruncationToken";
+
+// Append the runs of one CTLine to `out`, tagged with `line_idx`.
+// Runs whose range falls outside the first `utf16_total` units of the text are skipped.
+unsafe fn collect_runs_from_line(line: *const c_void, line_idx: usize, utf16_total: usize, out: &mut Vec<RunRaw>) {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTLineGetGlyphRuns(line: *const c_void) -> *const c_void;
+        fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
+        fn CTRunGetStringRange(run: *const c_void) -> CFRange;
+        fn CTRunGetStatus(run: *const c_void) -> u32;
+        fn CTRunGetGlyphCount(run: *const c_void) -> isize;
+        fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
+        fn CTFontGetSize(font: *const c_void) -> f64;
+    }
     
-    // Create CFString from Rust string
-    let cf_string = CFString::new(text);
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFArrayGetCount(array: *const c_void) -> isize;
+        fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    }
     
-    // Create mutable attributed string
-    let mut attributed_string = CFMutableAttributedString::new();
-    attributed_string.replace_str(&cf_string, CFRange::init(0, 0));
+    let runs = CTLineGetGlyphRuns(line);
+    if runs.is_null() {
+        return;
+    }
     
-    // Set the font attribute for the entire string using C API
-    unsafe {
+    // CoreText has no constant for strikethrough; the attribute is the UIKit/AppKit one
+    let strikethrough_key = CFString::from_static_string("NSStrikethrough");
+    let truncation_key = CFString::from_static_string(TRUNCATION_TOKEN_KEY);
+    
+    let run_count = CFArrayGetCount(runs);
+    for run_idx in 0..run_count {
+        let run = CFArrayGetValueAtIndex(runs, run_idx) as *const c_void;
+        if run.is_null() {
+            continue;
+        }
+        
+        let (utf16_location, utf16_length) = match validated_run_range(CTRunGetStringRange(run), utf16_total) {
+            Some(range) => range,
+            None => continue,
+        };
+        
+        let attrs = CTRunGetAttributes(run);
+        if attrs.is_null() {
+            continue;
+        }
+        if run_attribute_number(attrs, truncation_key.as_concrete_TypeRef() as *const c_void).is_some() {
+            continue;
+        }
+        let previous = out.last().map(|raw| (raw.utf16_location, raw.utf16_length));
+        if is_degenerate_run((utf16_location, utf16_length), previous) {
+            continue;
+        }
+        
+        // Get font pointer from attributes dictionary using kCTFontAttributeName directly
+        // CRITICAL: This is a borrowed reference from the attributes dictionary.
+        // We need to retain it to ensure it stays valid after the frame is dropped.
+        let font_ptr = CFDictionaryGetValue(attrs, kCTFontAttributeName as *const c_void) as *const c_void;
+        if font_ptr.is_null() {
+            continue;
+        }
+        
+        // Retain the font to ensure it stays valid after the frame is dropped
         #[link(name = "CoreFoundation", kind = "framework")]
         extern "C" {
-            fn CFAttributedStringSetAttribute(
-                aStr: *mut c_void,
-                range: CFRange,
-                attrName: *const c_void,
-                value: *const c_void,
-            );
-            fn CFAttributedStringGetLength(aStr: *const c_void) -> isize;
+            fn CFRetain(cf: *const c_void) -> *const c_void;
+        }
+        let retained_font_ptr = CFRetain(font_ptr);
+        if retained_font_ptr.is_null() {
+            continue;
         }
         
-        let text_length = CFAttributedStringGetLength(attributed_string.as_concrete_TypeRef() as *const c_void);
-        let attr_str_ptr = attributed_string.as_concrete_TypeRef() as *mut c_void;
-        let font_ptr = font.as_concrete_TypeRef() as *const c_void;
-        
-        if !attr_str_ptr.is_null() && !font_ptr.is_null() {
-            if let Some(font_key_ptr) = get_font_attribute_name() {
-                // CFAttributedStringSetAttribute will retain the font object
-                // This prevents the font from being released when the Rust wrapper is dropped
-                CFAttributedStringSetAttribute(
-                    attr_str_ptr,
-                    CFRange::init(0, text_length as isize),
-                    font_key_ptr,
-                    font_ptr,
-                );
-            }
+        // Get PostScript name from font
+        let ps_name_ref = CTFontCopyPostScriptName(retained_font_ptr);
+        if ps_name_ref.is_null() {
+            continue;
+        }
+        let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
+        let ps_name = ps_name_cf.to_string();
+        if ps_name.is_empty() {
+            continue;
         }
         
-        // Prevent the font Rust wrapper from releasing the Core Foundation font object
-        // CFAttributedStringSetAttribute has retained it, so it's now owned by the attributed string
-        std::mem::forget(font);
+        let font_size = CTFontGetSize(retained_font_ptr);
+        let decorations = run_decorations(attrs, &strikethrough_key);
+        let baseline_offset = run_attribute_f64(attrs, kCTBaselineOffsetAttributeName).unwrap_or(0.0);
+        let status = RunStatus::from_ct(CTRunGetStatus(run));
+        let glyph_count = CTRunGetGlyphCount(run).max(0) as usize;
+        
+        out.push(RunRaw {
+            utf16_location,
+            utf16_length,
+            postscript_name: ps_name,
+            font_ptr: retained_font_ptr, // Retained reference - must be released later
+            ct_run: run,
+            line_index: line_idx,
+            font_size,
+            decorations,
+            baseline_offset,
+            status,
+            glyph_count,
+        });
+    }
+}
+
+// Read underline and strikethrough styles from a run's attributes dictionary
+unsafe fn run_decorations(attrs: *const c_void, strikethrough_key: &CFString) -> TextDecorations {
+    // Style attributes are CFNumbers; anything else (or a missing attribute) is treated as 0
+    let style = |key: *const c_void| run_attribute_number(attrs, key).unwrap_or(0) as i32;
+    
+    TextDecorations {
+        underline: UnderlineStyle::from_ct(style(kCTUnderlineStyleAttributeName)),
+        strikethrough: style(strikethrough_key.as_concrete_TypeRef() as *const c_void) != 0,
+    }
+}
+
+// Integer value of the attribute `key` in a run's attributes dictionary; None if it is missing or not a CFNumber
+unsafe fn run_attribute_number(attrs: *const c_void, key: *const c_void) -> Option<i64> {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    }
+    
+    if attrs.is_null() || key.is_null() {
+        return None;
+    }
+    let value = CFDictionaryGetValue(attrs, key);
+    if value.is_null() {
+        return None;
+    }
+    CFType::wrap_under_get_rule(value as _)
+        .downcast::<CFNumber>()
+        .and_then(|number| number.to_i64())
+}
+
+// Floating-point value of the attribute `key` in a run's attributes dictionary; None if it is missing or not a CFNumber
+unsafe fn run_attribute_f64(attrs: *const c_void, key: *const c_void) -> Option<f64> {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    }
+    
+    if attrs.is_null() || key.is_null() {
+        return None;
+    }
+    let value = CFDictionaryGetValue(attrs, key);
+    if value.is_null() {
+        return None;
     }
+    CFType::wrap_under_get_rule(value as _)
+        .downcast::<CFNumber>()
+        .and_then(|number| number.to_f64())
+}
+
+// Build the attributed string for `text` (base font plus layout options) and wrap it in a framesetter
+fn create_framesetter(text: &str, font_size: f64, options: &LayoutOptions) -> CTFramesetter {
+    let attributed_string = create_layout_attributed_string(text, font_size, options);
     
     // Create framesetter
     let framesetter = CTFramesetter::new_with_attributed_string(attributed_string.as_concrete_TypeRef());
     // Prevent the attributed_string Rust wrapper from releasing the Core Foundation object
     std::mem::forget(attributed_string);
     
-    // Create a path (rectangular path for layout)
-    let bounds = CGRect::new(
-        &CGPoint::new(0.0, 0.0),
-        &CGSize::new(f64::MAX, f64::MAX),
-    );
-    let path = CGPath::from_rect(bounds, None);
-    
-    // Create frame
-    let frame = framesetter.create_frame(
-        CFRange::init(0, 0),
-        &path,
-    );
-    
-    // Collect runs from frame using the new pattern
-    let raw_runs = collect_runs_from_frame(text, frame.as_concrete_TypeRef() as *const c_void);
-    
-    // Convert RunRaw to TextRun with UTF-8 text extraction
-    let mut runs = Vec::new();
-    let text_utf16: Vec<u16> = text.encode_utf16().collect();
-    
-    for raw_run in raw_runs {
-        let start_utf16 = raw_run.utf16_location as usize;
-        let length_utf16 = raw_run.utf16_length as usize;
-        
-        // Convert UTF-16 indices to UTF-8 string
-        let run_text = if start_utf16 + length_utf16 <= text_utf16.len() {
-            let utf16_slice = &text_utf16[start_utf16..start_utf16 + length_utf16];
-            match String::from_utf16(utf16_slice) {
-                Ok(s) => s,
-                Err(_) => String::from(""),
-            }
-        } else {
-            String::from("")
-        };
-        
-        runs.push(TextRun {
-            text: run_text,
-            font_name: raw_run.postscript_name,
-            start_utf16,
-            length_utf16,
-            font_ptr: raw_run.font_ptr as u64, // Borrowed reference - NEVER release, stored as u64
-        });
+    framesetter
+}
+
+// Like create_framesetter, but report a null framesetter instead of panicking on it
+fn try_create_framesetter(text: &str, font_size: f64, options: &LayoutOptions) -> Result<CTFramesetter, ItemizeError> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFramesetterCreateWithAttributedString(string: *const c_void) -> *const c_void;
     }
     
-    runs
+    let attributed_string = create_layout_attributed_string(text, font_size, options);
+    let framesetter_ref = unsafe {
+        CTFramesetterCreateWithAttributedString(attributed_string.as_concrete_TypeRef() as *const c_void)
+    };
+    if framesetter_ref.is_null() {
+        return Err(ItemizeError::FramesetterCreation);
+    }
+    // The framesetter retains the attributed string, so the wrapper can release its reference
+    Ok(unsafe { CTFramesetter::wrap_under_create_rule(framesetter_ref as *mut _) })
 }
 
-pub fn split_str_into_runs_impl(text: &str, font_size: f64) {
-    // Create base font using system UI font
-    let font = create_base_font(font_size);
+// Like create_frame_with_framesetter, but report a null frame instead of panicking on it
+fn try_create_frame_with_framesetter(framesetter: &CTFramesetter, path: &CGPath) -> Result<CTFrame, ItemizeError> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFramesetterCreateFrame(
+            framesetter: *const c_void,
+            string_range: CFRange,
+            path: *const c_void,
+            frame_attributes: *const c_void,
+        ) -> *const c_void;
+    }
     
-    // Debug: Print the font name to see what we got
-    unsafe {
-        #[link(name = "CoreText", kind = "framework")]
-        extern "C" {
-            fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
-        }
-        let ps_name_ref = CTFontCopyPostScriptName(font.as_concrete_TypeRef() as *const c_void);
-        if !ps_name_ref.is_null() {
-            let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
-            println!("DEBUG: Created base font: {}", ps_name_cf.to_string());
-        }
+    let frame_ref = unsafe {
+        CTFramesetterCreateFrame(
+            framesetter.as_concrete_TypeRef() as *const c_void,
+            CFRange::init(0, 0),
+            path.as_ptr() as *const c_void,
+            ptr::null(),
+        )
+    };
+    if frame_ref.is_null() {
+        return Err(ItemizeError::FrameCreation);
     }
+    Ok(unsafe { CTFrame::wrap_under_create_rule(frame_ref as *mut _) })
+}
+
+// Attributed string for `text`: the base font over the whole string, then the layout options
+fn create_layout_attributed_string(text: &str, font_size: f64, options: &LayoutOptions) -> CFMutableAttributedString {
+    // Create base font (system UI font unless options name one)
+    let font = create_layout_font(font_size, options);
     
-    // Create CFString from Rust string
-    let cf_string = CFString::new(text);
+    // Create CFString from Rust string, protecting non-breaking ranges first.
+    // A string that fails validation is laid out as empty rather than with shifted offsets.
+    let cf_string = if options.non_breaking_ranges.is_empty() {
+        create_cf_string(text)
+    } else {
+        create_cf_string(&protect_non_breaking_ranges(text, &options.non_breaking_ranges))
+    };
+    let cf_string = cf_string.unwrap_or_else(|| CFString::new(""));
     
     // Create mutable attributed string
     let mut attributed_string = CFMutableAttributedString::new();
     attributed_string.replace_str(&cf_string, CFRange::init(0, 0));
     
     // Set the font attribute for the entire string using C API
-    // This forces Core Text to use our font, though it may still create separate runs
-    // for characters that need fallback fonts (Chinese, emoji, etc.)
     unsafe {
         #[link(name = "CoreFoundation", kind = "framework")]
         extern "C" {
@@ -350,457 +1096,5801 @@ pub fn split_str_into_runs_impl(text: &str, font_size: f64) {
             fn CFAttributedStringGetLength(aStr: *const c_void) -> isize;
         }
         
-        // Get the actual length of the attributed string (in UTF-16 code units)
         let text_length = CFAttributedStringGetLength(attributed_string.as_concrete_TypeRef() as *const c_void);
-        
-        // Validate inputs before calling CFAttributedStringSetAttribute
         let attr_str_ptr = attributed_string.as_concrete_TypeRef() as *mut c_void;
         let font_ptr = font.as_concrete_TypeRef() as *const c_void;
         
-        // Check that pointers are valid
-        if attr_str_ptr.is_null() {
-            println!("DEBUG: Error - attributed string pointer is null!");
-            return;
+        if !attr_str_ptr.is_null() && !font_ptr.is_null() {
+            if let Some(font_key_ptr) = get_font_attribute_name() {
+                // CFAttributedStringSetAttribute will retain the font object
+                // This prevents the font from being released when the Rust wrapper is dropped
+                CFAttributedStringSetAttribute(
+                    attr_str_ptr,
+                    CFRange::init(0, text_length),
+                    font_key_ptr,
+                    font_ptr,
+                );
+            }
         }
-        if font_ptr.is_null() {
-            println!("DEBUG: Error - font pointer is null!");
-            return;
+        
+        if !attr_str_ptr.is_null() {
+            apply_layout_options(attr_str_ptr, text_length, options);
+            if !options.script_fonts.is_empty() && text_length as usize == utf16_len(text) {
+                apply_script_fonts(attr_str_ptr, text, font_size, &options.script_fonts);
+            }
         }
         
-        // Get the font attribute name key
-        if let Some(font_key_ptr) = get_font_attribute_name() {
-            println!("DEBUG: Font key pointer: {:p}", font_key_ptr);
-            println!("DEBUG: Font pointer: {:p}", font_ptr);
-            println!("DEBUG: Attributed string pointer: {:p}", attr_str_ptr);
-            println!("DEBUG: Text length: {}", text_length);
-            
-            // Set the font attribute - the font must be retained, which TCFType handles
-            // kCTFontAttributeName is already a CFStringRef, so we can use it directly
+        // Prevent the font Rust wrapper from releasing the Core Foundation font object
+        // CFAttributedStringSetAttribute has retained it, so it's now owned by the attributed string
+        std::mem::forget(font);
+    }
+    
+    attributed_string
+}
+
+// Create a CFString for `text`, checking that it holds exactly the text's UTF-16 units.
+// Every run offset is a UTF-16 index into this string, so a mismatch would silently corrupt them.
+fn create_cf_string(text: &str) -> Option<CFString> {
+    let cf_string = CFString::new(text);
+    if cf_string_length_matches(cf_string.char_len(), utf16_len(text)) {
+        Some(cf_string)
+    } else {
+        None
+    }
+}
+
+fn cf_string_length_matches(cf_length: isize, expected_utf16: usize) -> bool {
+    if cf_length < 0 || cf_length as usize != expected_utf16 {
+        warn(format_args!(
+            "CFString length {} does not match expected UTF-16 length {}",
+            cf_length, expected_utf16
+        ));
+        return false;
+    }
+    true
+}
+
+// Replace U+0020 SPACE with U+00A0 NO-BREAK SPACE inside the given UTF-16 ranges.
+// Both are a single UTF-16 unit, so every offset in the result matches `text`.
+fn protect_non_breaking_ranges(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut text_utf16: Vec<u16> = text.encode_utf16().collect();
+    for range in ranges {
+        let end = range.end.min(text_utf16.len());
+        for unit in text_utf16.iter_mut().take(end).skip(range.start) {
+            if *unit == 0x0020 {
+                *unit = 0x00A0;
+            }
+        }
+    }
+    // Only BMP non-surrogates were swapped in, so the buffer is as valid as `text` was
+    String::from_utf16_lossy(&text_utf16)
+}
+
+// Split `text` into UTF-16 ranges by script, as (ISO 15924 tag, range). Common and inherited
+// characters (spaces, punctuation, marks) join the preceding range; leading ones belong to none.
+fn script_ranges(text: &str) -> Vec<([u8; 4], Range<usize>)> {
+    let mut ranges: Vec<([u8; 4], Range<usize>)> = Vec::new();
+    let mut offset = 0;
+    unsafe {
+        let funcs = harfbuzz_sys::hb_unicode_funcs_get_default();
+        for c in text.chars() {
+            let end = offset + c.len_utf16();
+            let script = harfbuzz_sys::hb_unicode_script(funcs, c as u32);
+            let neutral = script == harfbuzz_sys::HB_SCRIPT_COMMON
+                || script == harfbuzz_sys::HB_SCRIPT_INHERITED
+                || script == harfbuzz_sys::HB_SCRIPT_UNKNOWN;
+            let tag = harfbuzz_sys::hb_script_to_iso15924_tag(script).to_be_bytes();
+            match ranges.last_mut() {
+                Some(last) if neutral || last.0 == tag => last.1.end = end,
+                _ if neutral => {}
+                _ => ranges.push((tag, offset..end)),
+            }
+            offset = end;
+        }
+    }
+    ranges
+}
+
+// Set the font attribute on the ranges of each script listed in `script_fonts`
+unsafe fn apply_script_fonts(attr_str_ptr: *mut c_void, text: &str, font_size: f64, script_fonts: &[([u8; 4], String)]) {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFAttributedStringSetAttribute(
+            aStr: *mut c_void,
+            range: CFRange,
+            attrName: *const c_void,
+            value: *const c_void,
+        );
+    }
+    
+    let font_key_ptr = match get_font_attribute_name() {
+        Some(key) => key,
+        None => return,
+    };
+    let fonts: Vec<([u8; 4], Option<CTFont>)> = script_fonts
+        .iter()
+        .map(|(tag, name)| {
+            let font = installed_font(name, font_size);
+            if font.is_none() {
+                warn(format_args!("font \"{}\" for script {:?} not found, ignoring it", name, String::from_utf8_lossy(tag)));
+            }
+            (*tag, font)
+        })
+        .collect();
+    for (tag, range) in script_ranges(text) {
+        let font = match fonts.iter().find(|(script, _)| *script == tag) {
+            Some((_, Some(font))) => font,
+            _ => continue,
+        };
+        // The attributed string retains the font
+        CFAttributedStringSetAttribute(
+            attr_str_ptr,
+            CFRange::init(range.start as isize, (range.end - range.start) as isize),
+            font_key_ptr,
+            font.as_concrete_TypeRef() as *const c_void,
+        );
+    }
+}
+
+// Set the attributes requested in `options` on the whole attributed string
+// Must run before the framesetter is created so CoreText itemizes with them
+unsafe fn apply_layout_options(attr_str_ptr: *mut c_void, text_length: isize, options: &LayoutOptions) {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFAttributedStringSetAttribute(
+            aStr: *mut c_void,
+            range: CFRange,
+            attrName: *const c_void,
+            value: *const c_void,
+        );
+    }
+    
+    if let Some(ligatures) = options.ligatures {
+        if kCTLigatureAttributeName.is_null() {
+            warn("kCTLigatureAttributeName is null, skipping ligature setting");
+        } else {
+            // The attributed string retains the number, so the wrapper can be dropped afterwards
+            let value = CFNumber::from(ligatures as i32);
             CFAttributedStringSetAttribute(
                 attr_str_ptr,
-                CFRange::init(0, text_length as isize),
-                font_key_ptr,
-                font_ptr,
+                CFRange::init(0, text_length),
+                kCTLigatureAttributeName,
+                value.as_concrete_TypeRef() as *const c_void,
             );
-            println!("DEBUG: Font attribute set on attributed string");
-        } else {
-            println!("DEBUG: Warning - Could not get font attribute name, skipping font setting");
         }
     }
     
-    // Create framesetter
-    let framesetter = CTFramesetter::new_with_attributed_string(attributed_string.as_concrete_TypeRef());
-    
-    // Create a path (rectangular path for layout)
-    let bounds = CGRect::new(
-        &CGPoint::new(0.0, 0.0),
-        &CGSize::new(f64::MAX, f64::MAX),
-    );
-    let path = CGPath::from_rect(bounds, None);
+    if let Some(locale) = options.locale.as_deref().filter(|locale| !locale.is_empty()) {
+        if kCTLanguageAttributeName.is_null() {
+            warn("kCTLanguageAttributeName is null, skipping locale setting");
+        } else {
+            let value = CFString::new(locale);
+            CFAttributedStringSetAttribute(
+                attr_str_ptr,
+                CFRange::init(0, text_length),
+                kCTLanguageAttributeName,
+                value.as_concrete_TypeRef() as *const c_void,
+            );
+        }
+    }
     
-    // Create frame
-    let frame = framesetter.create_frame(
-        CFRange::init(0, 0),
-        &path,
-    );
+    for (range, offset) in &options.baseline_offsets {
+        // Ranges are clamped to the string; the attributed string retains the number
+        let start = range.start.min(text_length as usize);
+        let end = range.end.min(text_length as usize);
+        if start >= end || kCTBaselineOffsetAttributeName.is_null() {
+            continue;
+        }
+        let value = CFNumber::from(*offset);
+        CFAttributedStringSetAttribute(
+            attr_str_ptr,
+            CFRange::init(start as isize, (end - start) as isize),
+            kCTBaselineOffsetAttributeName,
+            value.as_concrete_TypeRef() as *const c_void,
+        );
+    }
     
-    // Get lines from frame using C API
+    let paragraph_style = create_paragraph_style(options);
+    if !paragraph_style.is_null() {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFRelease(cf: *const c_void);
+        }
+        // The attributed string retains the style; drop our create-rule reference
+        CFAttributedStringSetAttribute(
+            attr_str_ptr,
+            CFRange::init(0, text_length),
+            kCTParagraphStyleAttributeName,
+            paragraph_style,
+        );
+        CFRelease(paragraph_style);
+    }
+}
+
+// CTParagraphStyleSetting as laid out by CoreText
+#[repr(C)]
+struct CTParagraphStyleSetting {
+    spec: u32,
+    value_size: usize,
+    value: *const c_void,
+}
+
+// CTParagraphStyleSpecifier values
+const K_CTPARAGRAPH_STYLE_SPECIFIER_TAB_STOPS: u32 = 4;
+const K_CTPARAGRAPH_STYLE_SPECIFIER_LINE_HEIGHT_MULTIPLE: u32 = 7;
+const K_CTPARAGRAPH_STYLE_SPECIFIER_MAXIMUM_LINE_HEIGHT: u32 = 8;
+const K_CTPARAGRAPH_STYLE_SPECIFIER_MINIMUM_LINE_HEIGHT: u32 = 9;
+const K_CTPARAGRAPH_STYLE_SPECIFIER_PARAGRAPH_SPACING: u32 = 10;
+
+// kCTTextAlignmentLeft
+const K_CTTEXT_ALIGNMENT_LEFT: u8 = 0;
+
+// Build the CTParagraphStyle requested by `options` (create rule, caller releases),
+// or null if no paragraph-level option is set
+unsafe fn create_paragraph_style(options: &LayoutOptions) -> *const c_void {
     #[link(name = "CoreText", kind = "framework")]
     extern "C" {
-        fn CTFrameGetLines(frame: *const c_void) -> *const c_void;
-        fn CFArrayGetCount(array: *const c_void) -> isize;
-        fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
+        fn CTParagraphStyleCreate(settings: *const CTParagraphStyleSetting, count: usize) -> *const c_void;
+        fn CTTextTabCreate(alignment: u8, location: f64, options: *const c_void) -> *const c_void;
     }
     
-    #[link(name = "CoreFoundation", kind = "framework")]
-    extern "C" {
-        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    let mut settings: Vec<CTParagraphStyleSetting> = Vec::new();
+    
+    // Setting values are read through pointers, so they must outlive CTParagraphStyleCreate
+    let tab_array: Option<CFArray<CFType>> = if options.tab_stops.is_empty() {
+        None
+    } else {
+        let tabs: Vec<CFType> = options
+            .tab_stops
+            .iter()
+            .map(|&location| CFType::wrap_under_create_rule(CTTextTabCreate(K_CTTEXT_ALIGNMENT_LEFT, location, ptr::null())))
+            .collect();
+        Some(CFArray::from_CFTypes(&tabs))
+    };
+    let tab_array_ref = tab_array.as_ref().map(|array| array.as_concrete_TypeRef());
+    if let Some(array_ref) = tab_array_ref.as_ref() {
+        settings.push(CTParagraphStyleSetting {
+            spec: K_CTPARAGRAPH_STYLE_SPECIFIER_TAB_STOPS,
+            value_size: std::mem::size_of::<*const c_void>(),
+            value: array_ref as *const _ as *const c_void,
+        });
     }
     
-    unsafe {
-        let lines_array = CTFrameGetLines(frame.as_concrete_TypeRef() as *const c_void);
-        let line_count = CFArrayGetCount(lines_array) as usize;
-        
-        println!("Number of lines: {}", line_count);
-        println!("Text: \"{}\"", text);
-        println!("---");
-        
-        // Iterate through lines
-        for line_idx in 0..line_count {
-            let line_ref = CFArrayGetValueAtIndex(lines_array, line_idx as isize);
-            let line = CTLine::wrap_under_get_rule(line_ref as *mut _);
-            let runs = line.glyph_runs();
-            
-            println!("Line {}: {} runs", line_idx, runs.len());
-            
-            // Iterate through runs in each line
-            for (run_idx, run) in runs.iter().enumerate() {
-                let run = CTRun::wrap_under_get_rule(run.as_concrete_TypeRef());
-                
-                // Get font from run attributes using C API directly
-                #[link(name = "CoreText", kind = "framework")]
-                extern "C" {
-                    fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
-                }
-                
-                let attributes_dict = CTRunGetAttributes(run.as_concrete_TypeRef() as *const c_void);
-                
-                let font_ptr = if !attributes_dict.is_null() {
-                    // Get the font attribute name key
-                    if let Some(font_key_ptr) = get_font_attribute_name() {
-                        // Get the font value from the attributes dictionary
-                        let font_value_ref = CFDictionaryGetValue(
-                            attributes_dict,
-                            font_key_ptr,
-                        );
-                        
-                        if font_value_ref.is_null() {
-                            ptr::null()
-                        } else {
-                            font_value_ref
-                        }
-                    } else {
-                        ptr::null()
-                    }
-                } else {
-                    ptr::null()
-                };
-                
-                // Get PostScript name from font
-                let postscript_name = if !font_ptr.is_null() {
-                    #[link(name = "CoreText", kind = "framework")]
-                    extern "C" {
-                        fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
-                    }
-                    
-                    let ps_name_ref = CTFontCopyPostScriptName(font_ptr);
-                    if ps_name_ref.is_null() {
-                        String::from("(null)")
-                    } else {
-                        let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
-                        ps_name_cf.to_string()
-                    }
-                } else {
-                    String::from("(no font)")
-                };
-                
-                // Get run text range using C API
-                // Note: CFRange uses UTF-16 code units, need to convert to UTF-8 byte indices
-                #[link(name = "CoreText", kind = "framework")]
-                extern "C" {
-                    fn CTRunGetStringRange(run: *const c_void) -> CFRange;
-                }
-                let range = CTRunGetStringRange(run.as_concrete_TypeRef() as *const c_void);
-                let start_utf16 = range.location as usize;
-                let length_utf16 = range.length as usize;
-                
-                // Convert UTF-16 indices to UTF-8 byte indices
-                let text_utf16: Vec<u16> = text.encode_utf16().collect();
-                let run_text = if start_utf16 + length_utf16 <= text_utf16.len() {
-                    let utf16_slice = &text_utf16[start_utf16..start_utf16 + length_utf16];
-                    match String::from_utf16(utf16_slice) {
-                        Ok(s) => s,
-                        Err(_) => String::from(""),
-                    }
-                } else {
-                    String::from("")
-                };
-                
-                println!(
-                    "  Run {}: \"{}\" | Font pointer: {:p} | PostScript name: {}",
-                    run_idx, run_text, font_ptr, postscript_name
+    let spacing_settings = [
+        (K_CTPARAGRAPH_STYLE_SPECIFIER_LINE_HEIGHT_MULTIPLE, options.line_height_multiple.as_ref()),
+        (K_CTPARAGRAPH_STYLE_SPECIFIER_PARAGRAPH_SPACING, options.paragraph_spacing.as_ref()),
+        (K_CTPARAGRAPH_STYLE_SPECIFIER_MINIMUM_LINE_HEIGHT, options.min_line_height.as_ref()),
+        (K_CTPARAGRAPH_STYLE_SPECIFIER_MAXIMUM_LINE_HEIGHT, options.max_line_height.as_ref()),
+    ];
+    for (spec, value) in spacing_settings {
+        if let Some(value) = value {
+            // CGFloat settings, read from `options` itself
+            settings.push(CTParagraphStyleSetting {
+                spec,
+                value_size: std::mem::size_of::<f64>(),
+                value: value as *const f64 as *const c_void,
+            });
+        }
+    }
+    
+    if settings.is_empty() {
+        return ptr::null();
+    }
+    CTParagraphStyleCreate(settings.as_ptr(), settings.len())
+}
+
+// Lay out `text` into a single frame of unbounded height, wrapping at options.max_width if set
+fn create_frame(text: &str, font_size: f64, options: &LayoutOptions) -> CTFrame {
+    let framesetter = create_framesetter(text, font_size, options);
+    
+    // Create a path (rectangular path for layout)
+    let path = layout_rect_path(CGSize::new(options.max_width.unwrap_or(f64::MAX), f64::MAX), options);
+    
+    create_frame_with_framesetter(&framesetter, &path)
+}
+
+// Rectangle of `size` at the origin, transformed by options.path_transform if set
+fn layout_rect_path(size: CGSize, options: &LayoutOptions) -> CGPath {
+    let transform = match options.path_transform.as_ref() {
+        Some(transform) => transform,
+        None => return CGPath::from_rect(CGRect::new(&CGPoint::new(0.0, 0.0), &size), None),
+    };
+    // Unbounded (f64::MAX) dimensions would overflow to infinity when scaled up
+    let bounded = |value: f64| if value == f64::MAX { 1.0e9 } else { value };
+    let bounds = CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(bounded(size.width), bounded(size.height)));
+    CGPath::from_rect(bounds, Some(transform))
+}
+
+// Lay out `text` inside an arbitrary `path` (e.g. a circle or a column with exclusions)
+fn create_frame_in_path(text: &str, font_size: f64, path: &CGPath, options: &LayoutOptions) -> CTFrame {
+    let framesetter = create_framesetter(text, font_size, options);
+    create_frame_with_framesetter(&framesetter, path)
+}
+
+// Create a frame covering the whole string inside `path`
+fn create_frame_with_framesetter(framesetter: &CTFramesetter, path: &CGPath) -> CTFrame {
+    framesetter.create_frame(
+        CFRange::init(0, 0),
+        path,
+    )
+}
+
+// Function to collect runs from text.
+// Whitespace-only text is not special-cased: "   " gives one run of spaces in the base font.
+fn collect_runs(text: &str, font_size: f64) -> Vec<TextRun> {
+    collect_runs_with_options(text, font_size, &LayoutOptions::default())
+}
+
+// Collect runs from text, applying `options` to the attributed string before itemization.
+// Lenient: CoreText failures give empty or partial runs; see try_collect_runs_with_options.
+pub fn collect_runs_with_options(text: &str, font_size: f64, options: &LayoutOptions) -> Vec<TextRun> {
+    let frame = create_frame(text, font_size, options);
+    let runs = text_runs_from_frame(text, &frame, options.invalid_utf16);
+    finish_runs(runs, options)
+}
+
+// Why itemization failed, see try_collect_runs_with_options
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemizeError {
+    // The text could not be converted to a CFString holding exactly its UTF-16 units
+    InvalidText,
+    // CoreText could not create a framesetter for the attributed string
+    FramesetterCreation,
+    // CoreText could not create a frame from the framesetter
+    FrameCreation,
+    // CoreText returned no line array for the frame
+    NoLines,
+    // The runs CoreText produced do not cover the text, e.g. because runs with an invalid range
+    // or without a font were dropped
+    Incomplete(CoverageError),
+}
+
+impl std::fmt::Display for ItemizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ItemizeError::InvalidText => write!(f, "cannot itemize: text does not convert to a CFString"),
+            ItemizeError::FramesetterCreation => write!(f, "cannot itemize: CoreText could not create a framesetter"),
+            ItemizeError::FrameCreation => write!(f, "cannot itemize: CoreText could not create a frame"),
+            ItemizeError::NoLines => write!(f, "cannot itemize: CoreText returned no lines"),
+            ItemizeError::Incomplete(error) => write!(f, "cannot itemize: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ItemizeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ItemizeError::Incomplete(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// Like collect_runs_with_options, but report CoreText failures instead of returning empty or
+// partial runs. Coverage is checked on CoreText's runs, before options that drop or shrink runs
+// (such as strip_line_terminators) are applied.
+pub fn try_collect_runs_with_options(text: &str, font_size: f64, options: &LayoutOptions) -> Result<Vec<TextRun>, ItemizeError> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFrameGetLines(frame: *const c_void) -> *const c_void;
+    }
+    
+    if create_cf_string(text).is_none() {
+        return Err(ItemizeError::InvalidText);
+    }
+    let framesetter = try_create_framesetter(text, font_size, options)?;
+    let path = layout_rect_path(CGSize::new(options.max_width.unwrap_or(f64::MAX), f64::MAX), options);
+    let frame = try_create_frame_with_framesetter(&framesetter, &path)?;
+    if unsafe { CTFrameGetLines(frame.as_concrete_TypeRef() as *const c_void) }.is_null() {
+        return Err(ItemizeError::NoLines);
+    }
+    let runs = check_itemization(text, text_runs_from_frame(text, &frame, options.invalid_utf16))?;
+    Ok(finish_runs(runs, options))
+}
+
+fn check_itemization(text: &str, runs: Vec<TextRun>) -> Result<Vec<TextRun>, ItemizeError> {
+    match verify_coverage(text, &runs) {
+        Ok(()) => Ok(runs),
+        Err(error) => Err(ItemizeError::Incomplete(error)),
+    }
+}
+
+// Apply the run-level options that follow itemization
+fn finish_runs(runs: Vec<TextRun>, options: &LayoutOptions) -> Vec<TextRun> {
+    let runs = if options.control_runs { split_control_runs(runs) } else { runs };
+    let runs = if options.strip_line_terminators { strip_line_terminators(runs) } else { runs };
+    let mut runs = if options.aggressive_fallback { retry_tofu_runs(runs) } else { runs };
+    if options.logical_order {
+        runs.sort_by_key(|run| run.start_utf16);
+    }
+    runs
+}
+
+fn strip_line_terminators(runs: Vec<TextRun>) -> Vec<TextRun> {
+    let is_terminator = |c: char| matches!(c, '\n' | '\r' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}');
+    runs.into_iter()
+        .filter_map(|mut run| {
+            let kept = run.text.trim_end_matches(is_terminator).len();
+            if kept == 0 && !run.text.is_empty() {
+                return None;
+            }
+            // Terminators are all single UTF-16 units
+            let stripped = run.text[kept..].chars().count();
+            if stripped > 0 {
+                run.text.truncate(kept);
+                run.length_utf16 = run.length_utf16.saturating_sub(stripped);
+                run.coretext_glyph_count = None;
+            }
+            Some(run)
+        })
+        .collect()
+}
+
+// Collect runs of at most options.max_lines lines. If the text needs more, the last line is laid out
+// from the rest of the text and truncated to options.max_width (or, without one, to its own width)
+// at options.truncation. The ellipsis comes back as a run with text "…" whose UTF-16 range is the
+// elided text. Returns the runs and whether any text was cut.
+pub fn collect_truncated_runs(text: &str, font_size: f64, options: &LayoutOptions) -> (Vec<TextRun>, bool) {
+    let max_lines = match options.max_lines {
+        Some(max_lines) => max_lines,
+        None => return (collect_runs_with_options(text, font_size, options), false),
+    };
+    
+    let framesetter = create_framesetter(text, font_size, options);
+    let path = layout_rect_path(CGSize::new(options.max_width.unwrap_or(f64::MAX), f64::MAX), options);
+    let frame = create_frame_with_framesetter(&framesetter, &path);
+    let mut lines = text_lines_from_frame(text, &frame, options.invalid_utf16);
+    if lines.len() <= max_lines {
+        return (finish_runs(lines.into_iter().flatten().collect(), options), false);
+    }
+    if max_lines == 0 {
+        return (Vec::new(), true);
+    }
+    
+    let text_utf16: Vec<u16> = text.encode_utf16().collect();
+    let utf16_total = text_utf16.len();
+    let ct_lines = frame.get_lines();
+    let last_line = &ct_lines[max_lines - 1];
+    let last_start = last_line.get_string_range().location.max(0) as usize;
+    let width = options.max_width.unwrap_or_else(|| last_line.get_typographic_bounds().width);
+    
+    // The ellipsis is laid out in the base font, tagged so its runs are not read as source text
+    let token_font = create_layout_font(font_size, options);
+    let mut token_string = CFMutableAttributedString::new();
+    token_string.replace_str(&CFString::new("\u{2026}"), CFRange::init(0, 0));
+    unsafe {
+        token_string.set_attribute(CFRange::init(0, 1), kCTFontAttributeName as *const _, &token_font);
+    }
+    let truncation_key = CFString::from_static_string(TRUNCATION_TOKEN_KEY);
+    token_string.set_attribute(CFRange::init(0, 1), truncation_key.as_concrete_TypeRef(), &CFNumber::from(1i32));
+    let token = CTLine::new_with_attributed_string(token_string.as_concrete_TypeRef());
+    
+    let mut last_runs = Vec::new();
+    unsafe {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFramesetterGetTypesetter(framesetter: *const c_void) -> *const c_void;
+            fn CTTypesetterCreateLine(typesetter: *const c_void, range: CFRange) -> *const c_void;
+            fn CTLineCreateTruncatedLine(
+                line: *const c_void,
+                width: f64,
+                truncation_type: u32,
+                token: *const c_void,
+            ) -> *const c_void;
+        }
+        
+        let typesetter = CTFramesetterGetTypesetter(framesetter.as_concrete_TypeRef() as *const c_void);
+        if !typesetter.is_null() {
+            let rest = CFRange::init(last_start as isize, (utf16_total - last_start) as isize);
+            let rest_line = CTTypesetterCreateLine(typesetter, rest);
+            if !rest_line.is_null() {
+                let rest_line = CTLine::wrap_under_create_rule(rest_line as CTLineRef);
+                let truncated = CTLineCreateTruncatedLine(
+                    rest_line.as_concrete_TypeRef() as *const c_void,
+                    width,
+                    options.truncation as u32,
+                    token.as_concrete_TypeRef() as *const c_void,
                 );
+                if !truncated.is_null() {
+                    let truncated = CTLine::wrap_under_create_rule(truncated as CTLineRef);
+                    let mut raw_runs = Vec::new();
+                    collect_runs_from_line(truncated.as_concrete_TypeRef() as *const c_void, max_lines - 1, utf16_total, &mut raw_runs);
+                    last_runs = raw_runs
+                        .into_iter()
+                        .filter_map(|raw_run| text_run_from_raw(raw_run, &text_utf16, options.invalid_utf16))
+                        .collect();
+                }
             }
-            println!("---");
         }
     }
-}
+    
+    // The elided text is the first part of the rest that no run of the truncated line covers.
+    // If CoreText elided nothing (e.g. the rest fit), keep the frame's last line and elide what follows it.
+    let mut covered: Vec<Range<usize>> = last_runs.iter().map(|r| r.start_utf16..r.start_utf16 + r.length_utf16).collect();
+    covered.sort_by_key(|range| range.start);
+    let mut hole_start = last_start;
+    let mut hole_end = utf16_total;
+    for range in &covered {
+        if range.start > hole_start {
+            hole_end = range.start;
+            break;
+        }
+        hole_start = hole_start.max(range.end);
+    }
+    if last_runs.is_empty() || hole_start >= hole_end {
+        last_runs = lines.swap_remove(max_lines - 1);
+        hole_start = last_runs.iter().map(|r| r.start_utf16 + r.length_utf16).max().unwrap_or(last_start);
+        hole_end = utf16_total;
+    }
+    lines.truncate(max_lines - 1);
+    
+    let ellipsis = TextRun {
+        text: String::from("\u{2026}"),
+        font_name: token_font.postscript_name(),
+        start_utf16: hole_start,
+        length_utf16: hole_end - hole_start,
+        font_size: token_font.pt_size(),
+        font: FontRef::from(token_font),
+        decorations: TextDecorations::default(),
+        style_id: None,
+        baseline_offset: 0.0,
+        status: RunStatus::default(),
+        coretext_glyph_count: Some(token.glyph_runs().iter().map(|run| run.glyph_count().max(0) as usize).sum()),
+    };
+    let position = last_runs.iter().position(|r| r.start_utf16 >= hole_end).unwrap_or(last_runs.len());
+    last_runs.insert(position, ellipsis);
+    lines.push(last_runs);
+    
+    (finish_runs(lines.into_iter().flatten().collect(), options), true)
+}
+
+// Collect runs like collect_runs_with_options, itemizing one paragraph (split after each '\n') at a
+// time so memory stays bounded by the longest paragraph rather than the whole text. CoreText
+// resolves bidi and line breaks per paragraph anyway. Offsets are relative to the full text.
+pub fn collect_runs_chunked(text: &str, font_size: f64, options: &LayoutOptions) -> Vec<TextRun> {
+    let mut out = Vec::new();
+    let mut paragraph_start_utf16 = 0;
+    for paragraph in text.split_inclusive('\n') {
+        // Options that carry UTF-16 ranges must be rebased onto the paragraph
+        let paragraph_utf16 = utf16_len(paragraph);
+        let paragraph_end_utf16 = paragraph_start_utf16 + paragraph_utf16;
+        let rebase = |range: &Range<usize>| {
+            if range.start < paragraph_end_utf16 && range.end > paragraph_start_utf16 {
+                Some(Range {
+                    start: range.start.saturating_sub(paragraph_start_utf16),
+                    end: range.end.min(paragraph_end_utf16) - paragraph_start_utf16,
+                })
+            } else {
+                None
+            }
+        };
+        let mut paragraph_options = options.clone();
+        paragraph_options.non_breaking_ranges = options.non_breaking_ranges.iter().filter_map(rebase).collect();
+        paragraph_options.baseline_offsets = options
+            .baseline_offsets
+            .iter()
+            .filter_map(|(range, offset)| rebase(range).map(|range| (range, *offset)))
+            .collect();
+        
+        for mut run in collect_runs_with_options(paragraph, font_size, &paragraph_options) {
+            run.start_utf16 += paragraph_start_utf16;
+            out.push(run);
+        }
+        paragraph_start_utf16 = paragraph_end_utf16;
+    }
+    out
+}
+
+// Collect runs from text laid out inside `path` instead of an unbounded rectangle.
+// options.max_width is ignored since the path defines the line widths.
+pub fn collect_runs_in_path(text: &str, font_size: f64, path: &CGPath, options: &LayoutOptions) -> Vec<TextRun> {
+    let frame = create_frame_in_path(text, font_size, path, options);
+    let runs = text_runs_from_frame(text, &frame, options.invalid_utf16);
+    finish_runs(runs, options)
+}
+
+// Collect runs from a caller-built attributed string, skipping the crate's own attributed-string
+// construction: its attributes are used as-is and it is laid out in a rectangle of `bounds`.
+pub fn collect_runs_from_attributed_string(attr_str: CFAttributedStringRef, bounds: CGSize) -> Vec<TextRun> {
+    runs_from_attributed_string(attr_str, bounds, None)
+}
+
+// Like collect_runs_from_attributed_string, also reading the caller's own numeric attribute
+// `style_key` (e.g. a style id set with CFAttributedStringSetAttribute) into each run's style_id.
+// Runs are split wherever the attribute's value changes, so every run carries a single id.
+pub fn collect_styled_runs(attr_str: CFAttributedStringRef, bounds: CGSize, style_key: &str) -> Vec<TextRun> {
+    runs_from_attributed_string(attr_str, bounds, Some(&CFString::new(style_key)))
+}
+
+fn runs_from_attributed_string(attr_str: CFAttributedStringRef, bounds: CGSize, style_key: Option<&CFString>) -> Vec<TextRun> {
+    if attr_str.is_null() {
+        warn("attributed string pointer is null");
+        return Vec::new();
+    }
+    
+    // The attributed string owns its backing string; take it under the get rule
+    let text = unsafe {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFAttributedStringGetString(aStr: *const c_void) -> *const c_void;
+        }
+        let string_ref = CFAttributedStringGetString(attr_str as *const c_void);
+        if string_ref.is_null() {
+            return Vec::new();
+        }
+        CFString::wrap_under_get_rule(string_ref as *const _).to_string()
+    };
+    
+    let framesetter = CTFramesetter::new_with_attributed_string(attr_str);
+    let path = CGPath::from_rect(CGRect::new(&CGPoint::new(0.0, 0.0), &bounds), None);
+    let frame = create_frame_with_framesetter(&framesetter, &path);
+    let style_key = match style_key {
+        Some(style_key) => style_key,
+        None => return text_runs_from_frame(&text, &frame, InvalidUtf16::default()),
+    };
+    
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
+    }
+    let text_utf16: Vec<u16> = text.encode_utf16().collect();
+    collect_runs_from_frame(&text, frame.as_concrete_TypeRef() as *const c_void)
+        .into_iter()
+        .filter_map(|raw_run| {
+            // The CTRun is only valid while the frame is alive, so read the attribute now
+            let style_id = unsafe {
+                run_attribute_number(CTRunGetAttributes(raw_run.ct_run), style_key.as_concrete_TypeRef() as *const c_void)
+            };
+            let mut run = text_run_from_raw(raw_run, &text_utf16, InvalidUtf16::default())?;
+            run.style_id = style_id.map(|id| id as u64);
+            Some(run)
+        })
+        .collect()
+}
+
+// A TextRun that also retains the live CTRun it came from, for CoreText-only queries
+#[derive(Debug, Clone)]
+pub struct CoreTextRun {
+    pub run: TextRun,
+    pub ct_run: CTRun,
+}
+
+impl CoreTextRun {
+    // Glyph ids CoreText chose for this run (CTRunGetGlyphs), without involving HarfBuzz
+    pub fn coretext_glyphs(&self) -> Vec<u16> {
+        self.ct_run.glyphs().into_owned()
+    }
+    
+    // Glyphs, positions and string indices exactly as CoreText laid them out, the CoreText
+    // counterpart of shape_run_with_harfbuzz. Does not consume the run's font.
+    pub fn coretext_shaping(&self) -> CoreTextShapingResult {
+        let glyph_ids = self.coretext_glyphs();
+        CoreTextShapingResult {
+            run_text: self.run.text.clone(),
+            font_name: self.run.font_name.clone(),
+            glyph_count: glyph_ids.len(),
+            glyph_ids,
+            string_indices: self.ct_run.string_indices().iter().map(|&i| i.max(0) as usize).collect(),
+            positions: self.ct_run.positions().into_owned(),
+            font_size: self.run.font_size,
+        }
+    }
+}
+
+// Glyph data CoreText computed for a run (CTRunGetGlyphs/GetPositions/GetStringIndices)
+#[derive(Debug, Clone)]
+pub struct CoreTextShapingResult {
+    pub run_text: String,
+    pub font_name: String,
+    pub glyph_count: usize,
+    pub glyph_ids: Vec<u16>,
+    // UTF-16 offset in the laid-out text of the character each glyph came from
+    pub string_indices: Vec<usize>,
+    // Glyph origins in points relative to the line origin (not the run), y up
+    pub positions: Vec<CGPoint>,
+    pub font_size: f64,
+}
+
+// Collect runs like collect_runs_with_options, keeping each run's CTRun alive alongside it.
+// This gives a HarfBuzz-free shaping path using only CoreText.
+pub fn collect_coretext_runs(text: &str, font_size: f64, options: &LayoutOptions) -> Vec<CoreTextRun> {
+    let frame = create_frame(text, font_size, options);
+    let raw_runs = collect_runs_from_frame(text, frame.as_concrete_TypeRef() as *const c_void);
+    let text_utf16: Vec<u16> = text.encode_utf16().collect();
+    
+    let mut out = Vec::new();
+    for raw_run in raw_runs {
+        // Retain the CTRun while the frame still owns it
+        let ct_run = unsafe { CTRun::wrap_under_get_rule(raw_run.ct_run as CTRunRef) };
+        if let Some(run) = text_run_from_raw(raw_run, &text_utf16, options.invalid_utf16) {
+            out.push(CoreTextRun { run, ct_run });
+        }
+    }
+    out
+}
+
+// Collect the runs of every line in `frame` as TextRuns
+fn text_runs_from_frame(text: &str, frame: &CTFrame, invalid_utf16: InvalidUtf16) -> Vec<TextRun> {
+    // Collect runs from frame using the new pattern
+    let raw_runs = collect_runs_from_frame(text, frame.as_concrete_TypeRef() as *const c_void);
+    
+    // Convert RunRaw to TextRun with UTF-8 text extraction
+    let text_utf16: Vec<u16> = text.encode_utf16().collect();
+    raw_runs
+        .into_iter()
+        .filter_map(|raw_run| text_run_from_raw(raw_run, &text_utf16, invalid_utf16))
+        .collect()
+}
+
+// Collect the runs of `frame` grouped by line, each line's runs in CoreText's (visual) order
+fn text_lines_from_frame(text: &str, frame: &CTFrame, invalid_utf16: InvalidUtf16) -> Vec<Vec<TextRun>> {
+    let raw_runs = collect_runs_from_frame(text, frame.as_concrete_TypeRef() as *const c_void);
+    let text_utf16: Vec<u16> = text.encode_utf16().collect();
+    
+    let mut lines: Vec<Vec<TextRun>> = vec![Vec::new(); frame.get_lines().len()];
+    for raw_run in raw_runs {
+        let line_index = raw_run.line_index;
+        if let Some(run) = text_run_from_raw(raw_run, &text_utf16, invalid_utf16) {
+            if line_index < lines.len() {
+                lines[line_index].push(run);
+            }
+        }
+    }
+    lines
+}
+
+// Decode a run's UTF-16 slice, applying `invalid_utf16` if it is not valid UTF-16
+// (e.g. a range that splits a surrogate pair). None means the run should be skipped.
+fn decode_run_utf16(utf16_slice: &[u16], invalid_utf16: InvalidUtf16) -> Option<String> {
+    match String::from_utf16(utf16_slice) {
+        Ok(s) => Some(s),
+        Err(_) => match invalid_utf16 {
+            InvalidUtf16::Lossy => Some(String::from_utf16_lossy(utf16_slice)),
+            InvalidUtf16::Empty => Some(String::from("")),
+            InvalidUtf16::Skip => {
+                warn("run text is not valid UTF-16, skipping run");
+                None
+            }
+        },
+    }
+}
+
+// Convert a single RunRaw into a TextRun, extracting its text from the UTF-16 buffer.
+// Returns None (dropping the retained font) if start + length overflows usize,
+// which can only happen if CoreText handed back a corrupt range, or if the run's text
+// is undecodable and `invalid_utf16` is InvalidUtf16::Skip.
+fn text_run_from_raw(raw_run: RunRaw, text_utf16: &[u16], invalid_utf16: InvalidUtf16) -> Option<TextRun> {
+    // The raw run's retain is now owned here and released on every early return
+    let font = unsafe { FontRef::from_retained(raw_run.font_ptr) };
+    let start_utf16 = raw_run.utf16_location;
+    let length_utf16 = raw_run.utf16_length;
+    
+    // Convert UTF-16 indices to UTF-8 string
+    let run_text = match start_utf16.checked_add(length_utf16) {
+        Some(end_utf16) if end_utf16 <= text_utf16.len() => {
+            decode_run_utf16(&text_utf16[start_utf16..end_utf16], invalid_utf16)
+        }
+        Some(_) => Some(String::from("")),
+        None => {
+            warn(format_args!(
+                "UTF-16 range overflows: start {} + length {}, dropping run",
+                start_utf16, length_utf16
+            ));
+            None
+        }
+    };
+    
+    let run_text = run_text?;
+    
+    Some(TextRun {
+        text: run_text,
+        font_name: raw_run.postscript_name,
+        start_utf16,
+        length_utf16,
+        font,
+        font_size: raw_run.font_size,
+        decorations: raw_run.decorations,
+        style_id: None,
+        baseline_offset: raw_run.baseline_offset,
+        status: raw_run.status,
+        coretext_glyph_count: Some(raw_run.glyph_count),
+    })
+}
+
+// The runs of one line with the mapping between their visual and logical order
+#[derive(Debug, Clone)]
+pub struct LineRunOrder {
+    // Runs in visual (display) order, as CoreText returns them
+    pub runs: Vec<TextRun>,
+    // logical_to_visual[i] is the index in `runs` of the i-th run in logical (character) order.
+    // Identity for pure LTR lines; differs when bidi reordering moved runs around.
+    pub logical_to_visual: Vec<usize>,
+}
+
+impl LineRunOrder {
+    fn new(runs: Vec<TextRun>) -> Self {
+        let mut logical_to_visual: Vec<usize> = (0..runs.len()).collect();
+        logical_to_visual.sort_by_key(|&visual| runs[visual].start_utf16);
+        LineRunOrder { runs, logical_to_visual }
+    }
+    
+    // Inverse permutation: visual_to_logical()[v] is the logical position of the v-th visual run
+    pub fn visual_to_logical(&self) -> Vec<usize> {
+        let mut out = vec![0; self.logical_to_visual.len()];
+        for (logical, &visual) in self.logical_to_visual.iter().enumerate() {
+            out[visual] = logical;
+        }
+        out
+    }
+}
+
+// Collect runs per line along with their logical-to-visual permutation, for editors that
+// need to map selections across bidi text
+pub fn collect_run_order(text: &str, font_size: f64, options: &LayoutOptions) -> Vec<LineRunOrder> {
+    let frame = create_frame(text, font_size, options);
+    text_lines_from_frame(text, &frame, options.invalid_utf16)
+        .into_iter()
+        .map(LineRunOrder::new)
+        .collect()
+}
+
+// Collect runs grouped by line, wrapping at `max_width` points. Each line's runs are in
+// CoreText's (visual) order and a line's text keeps its trailing newline, if any.
+pub fn collect_lines(text: &str, font_size: f64, max_width: f64) -> Vec<Vec<TextRun>> {
+    let options = LayoutOptions { max_width: Some(max_width), ..Default::default() };
+    let frame = create_frame(text, font_size, &options);
+    text_lines_from_frame(text, &frame, options.invalid_utf16)
+}
+
+// The CoreText lines of `text` laid out with `options`, for calling CoreText line APIs this crate
+// doesn't wrap. Each CTLine is retained by its wrapper and released on drop; it stays valid after
+// the frame is gone. Line string ranges are UTF-16 offsets into `text`, as for runs.
+pub fn collect_ct_lines(text: &str, font_size: f64, options: &LayoutOptions) -> Vec<CTLine> {
+    create_frame(text, font_size, options).get_lines()
+}
+
+// Unique fonts (PostScript name, font) used to lay out `text`, in first-use order
+pub fn fonts_used(text: &str, font_size: f64) -> Vec<(String, FontRef)> {
+    let mut fonts: Vec<(String, FontRef)> = Vec::new();
+    for run in collect_runs(text, font_size) {
+        // Runs in a font already listed are dropped along with their retain
+        if !fonts.iter().any(|(name, _)| *name == run.font_name) {
+            fonts.push((run.font_name, run.font));
+        }
+    }
+    fonts
+}
+
+// Cheap overview of how `text` lays out, see summarize
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LayoutSummary {
+    pub line_count: usize,
+    pub run_count: usize,
+    // PostScript names of the fonts used, in first-use order
+    pub font_names: Vec<String>,
+    // UTF-16 units covered by the runs; the text's UTF-16 length when itemization is complete
+    pub utf16_length: usize,
+}
+
+// Lay out `text` with default options and count lines, runs and fonts, without decoding run
+// text, shaping or keeping fonts retained. For quick diagnostics over large amounts of text.
+pub fn summarize(text: &str, font_size: f64) -> LayoutSummary {
+    let frame = create_frame(text, font_size, &LayoutOptions::default());
+    let raw_runs = collect_runs_from_frame(text, frame.as_concrete_TypeRef() as *const c_void);
+    
+    let mut summary = LayoutSummary {
+        line_count: frame.get_lines().len(),
+        run_count: raw_runs.len(),
+        ..Default::default()
+    };
+    for raw_run in raw_runs {
+        // Release the retain collect_runs_from_frame took on the font
+        drop(unsafe { FontRef::from_retained(raw_run.font_ptr) });
+        summary.utf16_length += raw_run.utf16_length;
+        if !summary.font_names.contains(&raw_run.postscript_name) {
+            summary.font_names.push(raw_run.postscript_name);
+        }
+    }
+    summary
+}
+
+// How much of a text CoreText laid out in its base font versus fallback fonts, see fallback_stats
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FallbackStats {
+    // PostScript name of the base font the text was laid out with
+    pub base_font: String,
+    // UTF-16 units laid out per font (PostScript name), in first-use order
+    pub per_font: Vec<(String, usize)>,
+    pub total_utf16: usize,
+}
+
+impl FallbackStats {
+    // UTF-16 units laid out in the base font
+    pub fn base_utf16(&self) -> usize {
+        self.per_font
+            .iter()
+            .filter(|(name, _)| *name == self.base_font)
+            .map(|(_, units)| units)
+            .sum()
+    }
+    
+    // UTF-16 units CoreText had to lay out in some other font
+    pub fn fallback_utf16(&self) -> usize {
+        self.total_utf16 - self.base_utf16()
+    }
+    
+    // Share of the text in the base font, from 0.0 to 1.0 (1.0 for empty text)
+    pub fn base_fraction(&self) -> f64 {
+        if self.total_utf16 == 0 {
+            1.0
+        } else {
+            self.base_utf16() as f64 / self.total_utf16 as f64
+        }
+    }
+}
+
+// Per-font coverage of `text` laid out in the font named `font_name` (PostScript name; None or an
+// unknown name uses the system UI font), for catching shipped content the base font cannot render
+pub fn fallback_stats(text: &str, font_name: Option<&str>, font_size: f64) -> FallbackStats {
+    let options = LayoutOptions { font_name: font_name.map(String::from), ..Default::default() };
+    let mut stats = FallbackStats {
+        base_font: create_layout_font(font_size, &options).postscript_name(),
+        ..Default::default()
+    };
+    for run in collect_runs_with_options(text, font_size, &options) {
+        stats.total_utf16 += run.length_utf16;
+        match stats.per_font.iter_mut().find(|(name, _)| *name == run.font_name) {
+            Some((_, units)) => *units += run.length_utf16,
+            None => stats.per_font.push((run.font_name, run.length_utf16)),
+        }
+    }
+    stats
+}
+
+// Tab-stop positions that align the columns of tab-separated `rows`.
+// Stop i is where column i + 1 starts: the widest cell of each column up to i, plus `gap` after each.
+pub fn column_tab_stops(rows: &[&str], font_size: f64, gap: f64) -> Vec<f64> {
+    let options = LayoutOptions::default();
+    let mut column_widths: Vec<f64> = Vec::new();
+    
+    for row in rows {
+        for (column, cell) in row.split('\t').enumerate() {
+            let width = measure_width(cell, font_size, &options);
+            if column == column_widths.len() {
+                column_widths.push(width);
+            } else {
+                column_widths[column] = column_widths[column].max(width);
+            }
+        }
+    }
+    
+    // The last column needs no stop after it
+    column_widths.pop();
+    let mut position = 0.0;
+    column_widths
+        .iter()
+        .map(|width| {
+            position += width + gap;
+            position
+        })
+        .collect()
+}
+
+// Ways in which a set of runs fails to cover a text exactly once (UTF-16 ranges)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CoverageError {
+    // Ranges of the text not covered by any run
+    pub gaps: Vec<Range<usize>>,
+    // Ranges covered by more than one run
+    pub overlaps: Vec<Range<usize>>,
+    // Parts of runs that extend past the end of the text
+    pub out_of_bounds: Vec<Range<usize>>,
+}
+
+impl std::fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "runs do not cover the text exactly: gaps {:?}, overlaps {:?}, out of bounds {:?}",
+            self.gaps, self.overlaps, self.out_of_bounds
+        )
+    }
+}
+
+impl std::error::Error for CoverageError {}
+
+// Check that `runs` cover every UTF-16 unit of `text` exactly once, in any order
+pub fn verify_coverage(text: &str, runs: &[TextRun]) -> Result<(), CoverageError> {
+    let total_utf16 = utf16_len(text);
+    let mut ranges: Vec<Range<usize>> = runs
+        .iter()
+        .map(|run| run.start_utf16..run.start_utf16.saturating_add(run.length_utf16))
+        .collect();
+    ranges.sort_by_key(|range| (range.start, range.end));
+    
+    let mut error = CoverageError::default();
+    let mut covered_to = 0;
+    for range in ranges {
+        if range.start > covered_to {
+            error.gaps.push(covered_to..range.start.min(total_utf16));
+        } else if range.start < covered_to {
+            error.overlaps.push(range.start..range.end.min(covered_to));
+        }
+        if range.end > total_utf16 {
+            error.out_of_bounds.push(range.start.max(total_utf16)..range.end);
+        }
+        covered_to = covered_to.max(range.end);
+    }
+    if covered_to < total_utf16 {
+        error.gaps.push(covered_to..total_utf16);
+    }
+    
+    // Gaps that start past the end of the text are already reported as out of bounds
+    error.gaps.retain(|gap| !gap.is_empty());
+    error.overlaps.retain(|overlap| !overlap.is_empty());
+    if error == CoverageError::default() {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+// PostScript names of the installed fonts in `family` (e.g. "Helvetica"), sorted; empty for an unknown family
+pub fn fonts_in_family(family: &str) -> Vec<String> {
+    let descriptors = match core_text::font_collection::create_for_family(family).and_then(|c| c.get_descriptors()) {
+        Some(descriptors) => descriptors,
+        None => return Vec::new(),
+    };
+    let mut names: Vec<String> = descriptors.iter().map(|descriptor| descriptor.font_name()).collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// PostScript names of the fallback fonts CoreText cascades to from `font`, in the order it tries
+// them, for the preferred `languages` (e.g. ["zh-Hans", "en"]; empty uses the user's languages)
+pub fn cascade_list(font: &FontRef, languages: &[&str]) -> Vec<String> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        static kCTFontNameAttribute: *const c_void;
+        fn CTFontCopyDefaultCascadeListForLanguages(font: *const c_void, languages: *const c_void) -> *const c_void;
+        fn CTFontDescriptorCopyAttribute(descriptor: *const c_void, attribute: *const c_void) -> *const c_void;
+    }
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFArrayGetCount(array: *const c_void) -> isize;
+        fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
+        fn CFRelease(cf: *const c_void);
+    }
+    
+    let mut out = Vec::new();
+    if font.is_null() {
+        return out;
+    }
+    let language_strings: Vec<CFString> = languages.iter().map(|language| CFString::new(language)).collect();
+    let language_array = CFArray::from_CFTypes(&language_strings);
+    let language_ptr = if languages.is_empty() {
+        ptr::null()
+    } else {
+        language_array.as_concrete_TypeRef() as *const c_void
+    };
+    
+    unsafe {
+        let descriptors = CTFontCopyDefaultCascadeListForLanguages(font.as_ptr(), language_ptr);
+        if descriptors.is_null() {
+            return out;
+        }
+        for i in 0..CFArrayGetCount(descriptors) {
+            let descriptor = CFArrayGetValueAtIndex(descriptors, i);
+            let name_ref = CTFontDescriptorCopyAttribute(descriptor, kCTFontNameAttribute);
+            if name_ref.is_null() {
+                continue;
+            }
+            out.push(CFString::wrap_under_create_rule(name_ref as *mut _).to_string());
+        }
+        CFRelease(descriptors);
+    }
+    out
+}
+
+// Human-readable name of `glyph_id` in `font` (e.g. "A"), read through its CGFont.
+// None for a null font or a font without glyph names (no post table names).
+pub fn glyph_name(font: &FontRef, glyph_id: u16) -> Option<String> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontCopyGraphicsFont(font: *const c_void, attributes: *mut *const c_void) -> *const c_void;
+    }
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGFontCopyGlyphNameForGlyph(font: *const c_void, glyph: u16) -> *const c_void;
+    }
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFRelease(cf: *const c_void);
+    }
+    
+    if font.is_null() {
+        return None;
+    }
+    unsafe {
+        let cg_font = CTFontCopyGraphicsFont(font.as_ptr(), ptr::null_mut());
+        if cg_font.is_null() {
+            return None;
+        }
+        let name_ref = CGFontCopyGlyphNameForGlyph(cg_font, glyph_id);
+        CFRelease(cg_font);
+        if name_ref.is_null() {
+            return None;
+        }
+        let name = CFString::wrap_under_create_rule(name_ref as *mut _).to_string();
+        if name.is_empty() { None } else { Some(name) }
+    }
+}
+
+// Raw bytes of the OpenType table `tag` (e.g. *b"head", *b"GSUB") of `font`, via CTFontCopyTable.
+// None for a null font or a table the font does not have.
+pub fn font_table(font: &FontRef, tag: [u8; 4]) -> Option<Vec<u8>> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontCopyTable(font: *const c_void, table: u32, options: u32) -> *const c_void;
+    }
+    
+    if font.is_null() {
+        return None;
+    }
+    unsafe {
+        // kCTFontTableOptionNoOptions
+        let data_ref = CTFontCopyTable(font.as_ptr(), u32::from_be_bytes(tag), 0);
+        if data_ref.is_null() {
+            return None;
+        }
+        Some(CFData::wrap_under_create_rule(data_ref as *const _).bytes().to_vec())
+    }
+}
+
+// Code points `font` has glyphs for (CTFontCopyCharacterSet), as sorted inclusive (first, last)
+// ranges. Empty for a null font.
+pub fn font_charset(font: &FontRef) -> Vec<(u32, u32)> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontCopyCharacterSet(font: *const c_void) -> *const c_void;
+    }
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFCharacterSetCreateBitmapRepresentation(alloc: *const c_void, set: *const c_void) -> *const c_void;
+        fn CFRelease(cf: *const c_void);
+    }
+    
+    if font.is_null() {
+        return Vec::new();
+    }
+    let bitmap = unsafe {
+        let charset = CTFontCopyCharacterSet(font.as_ptr());
+        if charset.is_null() {
+            return Vec::new();
+        }
+        let data_ref = CFCharacterSetCreateBitmapRepresentation(ptr::null(), charset);
+        CFRelease(charset);
+        if data_ref.is_null() {
+            return Vec::new();
+        }
+        CFData::wrap_under_create_rule(data_ref as *const _).bytes().to_vec()
+    };
+    charset_ranges(&bitmap)
+}
+
+// Convert a CFCharacterSet bitmap representation to ranges: 8192 bytes for the BMP, then for each
+// other plane with members, its plane number followed by 8192 bytes. Bits are LSB-first.
+fn charset_ranges(bitmap: &[u8]) -> Vec<(u32, u32)> {
+    const PLANE_BYTES: usize = 8192;
+    let mut planes: Vec<(u32, &[u8])> = Vec::new();
+    if bitmap.len() >= PLANE_BYTES {
+        planes.push((0, &bitmap[..PLANE_BYTES]));
+        for chunk in bitmap[PLANE_BYTES..].chunks_exact(PLANE_BYTES + 1) {
+            planes.push((chunk[0] as u32, &chunk[1..]));
+        }
+    }
+    
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for (plane, bits) in planes {
+        for (byte_index, &byte) in bits.iter().enumerate() {
+            if byte == 0 {
+                continue;
+            }
+            for bit in 0..8 {
+                if byte & (1 << bit) == 0 {
+                    continue;
+                }
+                let code_point = (plane << 16) | ((byte_index as u32) << 3) | bit;
+                match ranges.last_mut() {
+                    Some(last) if last.1 + 1 == code_point => last.1 = code_point,
+                    _ => ranges.push((code_point, code_point)),
+                }
+            }
+        }
+    }
+    ranges
+}
+
+// Fast width of a single-line ASCII string in `font`: nominal glyph advances
+// summed directly, without itemization, layout or kerning, and without heap allocation.
+// None if the text isn't ASCII or the font lacks a glyph for one of its characters.
+pub fn measure_ascii_width(text: &str, font: &FontRef) -> Option<f64> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontGetGlyphsForCharacters(
+            font: *const c_void,
+            characters: *const u16,
+            glyphs: *mut u16,
+            count: isize,
+        ) -> bool;
+        fn CTFontGetAdvancesForGlyphs(
+            font: *const c_void,
+            orientation: u32,
+            glyphs: *const u16,
+            advances: *mut CGSize,
+            count: isize,
+        ) -> f64;
+    }
+    const CHUNK: usize = 64;
+    const K_CTFONT_ORIENTATION_HORIZONTAL: u32 = 1;
+    
+    if font.is_null() || !text.is_ascii() {
+        return None;
+    }
+    let font = font.as_ptr();
+    
+    // ASCII bytes are their own UTF-16 units; convert through stack buffers
+    let mut characters = [0u16; CHUNK];
+    let mut glyphs = [0u16; CHUNK];
+    let mut width = 0.0;
+    for chunk in text.as_bytes().chunks(CHUNK) {
+        for (unit, &byte) in characters.iter_mut().zip(chunk) {
+            *unit = byte as u16;
+        }
+        let count = chunk.len() as isize;
+        unsafe {
+            if !CTFontGetGlyphsForCharacters(font, characters.as_ptr(), glyphs.as_mut_ptr(), count) {
+                return None;
+            }
+            width += CTFontGetAdvancesForGlyphs(
+                font,
+                K_CTFONT_ORIENTATION_HORIZONTAL,
+                glyphs.as_ptr(),
+                ptr::null_mut(),
+                count,
+            );
+        }
+    }
+    Some(width)
+}
+
+// UTF-16 offsets where `text` may be hyphenated, ascending, using the system hyphenation
+// dictionary for `locale` (e.g. "en_US"). Empty if the locale has no hyphenation support.
+// Each offset is where a hyphen would go: the break falls before the character at that index.
+pub fn hyphenation_points(text: &str, locale: &str) -> Vec<usize> {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFLocaleCreate(allocator: *const c_void, identifier: *const c_void) -> *const c_void;
+        fn CFStringIsHyphenationAvailableForLocale(locale: *const c_void) -> bool;
+        fn CFStringGetHyphenationLocationBeforeIndex(
+            string: *const c_void,
+            location: isize,
+            limit_range: CFRange,
+            options: usize,
+            locale: *const c_void,
+            character: *mut u32,
+        ) -> isize;
+        fn CFRelease(cf: *const c_void);
+    }
+    
+    let mut out = Vec::new();
+    let cf_string = match create_cf_string(text) {
+        Some(cf_string) => cf_string,
+        None => return out,
+    };
+    let length = cf_string.char_len();
+    let locale_id = CFString::new(locale);
+    
+    unsafe {
+        let cf_locale = CFLocaleCreate(ptr::null(), locale_id.as_concrete_TypeRef() as *const c_void);
+        if cf_locale.is_null() {
+            warn(format_args!("could not create locale {:?}", locale));
+            return out;
+        }
+        if CFStringIsHyphenationAvailableForLocale(cf_locale) {
+            // Walk backwards from the end; each call finds the nearest point before `index`
+            let mut index = length;
+            loop {
+                let location = CFStringGetHyphenationLocationBeforeIndex(
+                    cf_string.as_concrete_TypeRef() as *const c_void,
+                    index,
+                    CFRange::init(0, length),
+                    0,
+                    cf_locale,
+                    ptr::null_mut(),
+                );
+                // kCFNotFound is -1; also stop if CoreFoundation fails to make progress
+                if location <= 0 || location >= index {
+                    break;
+                }
+                out.push(location as usize);
+                index = location;
+            }
+            out.reverse();
+        }
+        CFRelease(cf_locale);
+    }
+    out
+}
+
+// Why a run starts where it does, inferred by comparing it with the run before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitReason {
+    // First run of the text
+    Start,
+    // First run of a new line
+    LineBreak,
+    // CoreText picked a different font, e.g. a fallback for emoji
+    FontChange,
+    // Same font, but the text switches between left-to-right and right-to-left
+    DirectionChange,
+    // Same font and direction, but a different script
+    ScriptChange,
+    // Same font, direction and script, but different decorations or style
+    AttributeChange,
+    // Nothing that differs between the two runs explains the split
+    Unknown,
+}
+
+// For each run of `text`, in the order collect_runs returns them, the reason CoreText split
+// there. For debugging unexpected run boundaries; the reasons are inferred, not reported by CoreText.
+pub fn explain_splits(text: &str, font_size: f64) -> Vec<SplitReason> {
+    let options = LayoutOptions::default();
+    let frame = create_frame(text, font_size, &options);
+    let mut reasons = Vec::new();
+    let mut previous: Option<TextRun> = None;
+    for line in text_lines_from_frame(text, &frame, options.invalid_utf16) {
+        for (index, run) in line.into_iter().enumerate() {
+            let reason = match &previous {
+                None => SplitReason::Start,
+                Some(_) if index == 0 => SplitReason::LineBreak,
+                Some(prev) => split_reason(prev, &run),
+            };
+            reasons.push(reason);
+            previous = Some(run);
+        }
+    }
+    reasons
+}
+
+fn split_reason(prev: &TextRun, run: &TextRun) -> SplitReason {
+    if prev.font_name != run.font_name || prev.font_size != run.font_size {
+        SplitReason::FontChange
+    } else if prev.status.right_to_left != run.status.right_to_left {
+        SplitReason::DirectionChange
+    } else if detect_script(&prev.text) != detect_script(&run.text) {
+        SplitReason::ScriptChange
+    } else if prev.decorations != run.decorations
+        || prev.style_id != run.style_id
+        || prev.baseline_offset != run.baseline_offset
+    {
+        SplitReason::AttributeChange
+    } else {
+        SplitReason::Unknown
+    }
+}
+
+// Which runs changed between two itemizations of edited text, see diff_runs
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RunDiff {
+    // (old index, new index) of runs with the same text and font; offsets may have shifted by the edit
+    pub unchanged: Vec<(usize, usize)>,
+    // Indices into `old` of runs that are gone
+    pub removed: Range<usize>,
+    // Indices into `new` of runs that need shaping
+    pub added: Range<usize>,
+    // UTF-16 range (in the old text) and font name of each removed run
+    pub removed_runs: Vec<(Range<usize>, String)>,
+    // UTF-16 range (in the new text) and font name of each added run
+    pub added_runs: Vec<(Range<usize>, String)>,
+}
+
+// Compare the itemization of text before and after a single edit. Runs before the edit must match
+// exactly; runs after it may have moved by the edit's length change. Everything in between is
+// reported as removed from `old` and added in `new`, so only `added` needs re-shaping.
+pub fn diff_runs(old: &[TextRun], new: &[TextRun]) -> RunDiff {
+    let same = |a: &TextRun, b: &TextRun| {
+        a.length_utf16 == b.length_utf16 && a.text == b.text && a.font_name == b.font_name && a.font_size == b.font_size
+    };
+    let end = |runs: &[TextRun]| runs.iter().map(|r| r.start_utf16 + r.length_utf16).max().unwrap_or(0) as isize;
+    let shift = end(new) - end(old);
+    
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(a, b)| a.start_utf16 == b.start_utf16 && same(a, b))
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a.start_utf16 as isize + shift == b.start_utf16 as isize && same(a, b))
+        .count();
+    
+    let mut unchanged: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    let old_suffix = old.len() - suffix;
+    let new_suffix = new.len() - suffix;
+    unchanged.extend((0..suffix).map(|i| (old_suffix + i, new_suffix + i)));
+    let ranges_and_fonts = |runs: &[TextRun]| -> Vec<(Range<usize>, String)> {
+        runs.iter()
+            .map(|run| (run.start_utf16..run.start_utf16 + run.length_utf16, run.font_name.clone()))
+            .collect()
+    };
+    
+    RunDiff {
+        unchanged,
+        removed: prefix..old_suffix,
+        added: prefix..new_suffix,
+        removed_runs: ranges_and_fonts(&old[prefix..old_suffix]),
+        added_runs: ranges_and_fonts(&new[prefix..new_suffix]),
+    }
+}
+
+// Measure the typographic width of `text` (its widest line) with `options` applied
+pub fn measure_width(text: &str, font_size: f64, options: &LayoutOptions) -> f64 {
+    let frame = create_frame(text, font_size, options);
+    frame
+        .get_lines()
+        .iter()
+        .map(|line| line_width(line, !options.exclude_trailing_whitespace))
+        .fold(0.0, f64::max)
+}
+
+// Typographic width of `line`, optionally without its trailing whitespace
+fn line_width(line: &CTLine, include_trailing_whitespace: bool) -> f64 {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTLineGetTrailingWhitespaceWidth(line: *const c_void) -> f64;
+    }
+    
+    let width = line.get_typographic_bounds().width;
+    if include_trailing_whitespace {
+        return width;
+    }
+    let trailing = unsafe { CTLineGetTrailingWhitespaceWidth(line.as_concrete_TypeRef() as *const c_void) };
+    (width - trailing).max(0.0)
+}
+
+// Measure the height `text` occupies when wrapped at `width` (0.0 for empty text)
+pub fn measure_height(text: &str, font_size: f64, width: f64) -> f64 {
+    measure_height_with_options(text, font_size, width, &LayoutOptions::default())
+}
+
+// Measure the wrapped height of `text` with `options` applied, using
+// CTFramesetterSuggestFrameSizeWithConstraints with unbounded height
+pub fn measure_height_with_options(text: &str, font_size: f64, width: f64, options: &LayoutOptions) -> f64 {
+    if text.is_empty() {
+        if options.empty_text_line_height {
+            let font = create_base_font(font_size);
+            return font.ascent() + font.descent() + font.leading();
+        }
+        return 0.0;
+    }
+    
+    let (size, _fit_range) = suggest_size_with_options(text, font_size, CGSize::new(width, f64::MAX), options);
+    size.height
+}
+
+// Suggest the frame size `text` needs within `constraints`, e.g. for label auto-sizing.
+// Returns the fitted size and the UTF-16 range of text that fits in it.
+pub fn suggest_size(text: &str, font_size: f64, constraints: CGSize) -> (CGSize, Range<usize>) {
+    suggest_size_with_options(text, font_size, constraints, &LayoutOptions::default())
+}
+
+// suggest_size with `options` applied to the attributed string
+pub fn suggest_size_with_options(
+    text: &str,
+    font_size: f64,
+    constraints: CGSize,
+    options: &LayoutOptions,
+) -> (CGSize, Range<usize>) {
+    let framesetter = create_framesetter(text, font_size, options);
+    let (size, fit_range) = framesetter.suggest_frame_size_with_constraints(
+        CFRange::init(0, 0),
+        ptr::null(),
+        constraints,
+    );
+    
+    // CoreText never reports a negative range, but clamp rather than wrap if it did
+    let start = fit_range.location.max(0) as usize;
+    let end = start.saturating_add(fit_range.length.max(0) as usize);
+    (size, start..end)
+}
+
+// A run boundary that separates a base character from the combining mark attached to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitCombiningMark {
+    // Index of the run that starts with the combining mark
+    pub run_index: usize,
+    // UTF-16 offset of the mark in the full text
+    pub mark_utf16: usize,
+}
+
+// Whether `c` is a combining mark (Mn, Mc or Me), per CoreFoundation's non-base character set
+fn is_combining_mark(c: char) -> bool {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFCharacterSetGetPredefined(set_identifier: isize) -> *const c_void;
+        fn CFCharacterSetIsLongCharacterMember(set: *const c_void, the_char: u32) -> u8;
+    }
+    const K_CFCHARACTERSET_NON_BASE: isize = 8;
+    
+    unsafe {
+        let non_base = CFCharacterSetGetPredefined(K_CFCHARACTERSET_NON_BASE);
+        !non_base.is_null() && CFCharacterSetIsLongCharacterMember(non_base, c as u32) != 0
+    }
+}
+
+// Whether the CTFont at `font_ptr` has a glyph for every character in `text`
+fn font_has_glyphs(font_ptr: *const c_void, text: &str) -> bool {
+    missing_glyph_count(font_ptr, text) == Some(0)
+}
+
+// Number of characters in `text` the CTFont at `font_ptr` has no glyph for; None for a null font
+fn missing_glyph_count(font_ptr: *const c_void, text: &str) -> Option<usize> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontGetGlyphsForCharacters(
+            font: *const c_void,
+            characters: *const u16,
+            glyphs: *mut u16,
+            count: isize,
+        ) -> bool;
+    }
+    
+    if font_ptr.is_null() {
+        return None;
+    }
+    if text.is_empty() {
+        return Some(0);
+    }
+    let characters: Vec<u16> = text.encode_utf16().collect();
+    let mut glyphs = vec![0u16; characters.len()];
+    unsafe {
+        CTFontGetGlyphsForCharacters(font_ptr, characters.as_ptr(), glyphs.as_mut_ptr(), characters.len() as isize);
+    }
+    // Missing characters get glyph 0; the second unit of a surrogate pair is also left at 0
+    let mut units = characters.iter().zip(&glyphs);
+    let mut missing = 0;
+    while let Some((&unit, &glyph)) = units.next() {
+        if glyph == 0 {
+            missing += 1;
+        }
+        if (0xD800..0xDC00).contains(&unit) {
+            units.next();
+        }
+    }
+    Some(missing)
+}
+
+// Give runs their font cannot render (tofu) one more chance: ask CoreText for the best font
+// for the run's text (CTFontCreateForStringWithLanguage) and switch to it when it covers more
+// characters. Runs that are already covered, or where no better font exists, are unchanged.
+pub fn retry_tofu_runs(runs: Vec<TextRun>) -> Vec<TextRun> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontCreateForStringWithLanguage(
+            current_font: *const c_void,
+            string: *const c_void,
+            range: CFRange,
+            language: *const c_void,
+        ) -> *const c_void;
+    }
+    
+    runs.into_iter()
+        .map(|mut run| {
+            let missing = match missing_glyph_count(run.font.as_ptr(), &run.text) {
+                Some(missing) if missing > 0 => missing,
+                _ => return run,
+            };
+            let cf_string = match create_cf_string(&run.text) {
+                Some(cf_string) => cf_string,
+                None => return run,
+            };
+            let candidate = unsafe {
+                FontRef::from_retained(CTFontCreateForStringWithLanguage(
+                    run.font.as_ptr(),
+                    cf_string.as_concrete_TypeRef() as *const c_void,
+                    CFRange::init(0, cf_string.char_len()),
+                    ptr::null(),
+                ))
+            };
+            let better = matches!(missing_glyph_count(candidate.as_ptr(), &run.text), Some(m) if m < missing);
+            if better {
+                if let Some(name) = candidate.postscript_name() {
+                    warn(format_args!("retrying tofu run {:?}: {} -> {}", run.text, run.font_name, name));
+                    run.font_name = name;
+                    run.font_size = candidate.size();
+                    run.font = candidate;
+                    run.coretext_glyph_count = None;
+                }
+            }
+            run
+        })
+        .collect()
+}
+
+// Quick coverage check: whether `font` has a glyph for every character of `text`, without
+// laying it out. Empty text is covered; a null font covers nothing.
+pub fn font_covers(font: &FontRef, text: &str) -> bool {
+    font_has_glyphs(font.as_ptr(), text)
+}
+
+// The glyph `font` maps `c` to (CTFontGetGlyphsForCharacters, passing astral characters as their
+// surrogate pair), without layout or fallback. None for a null font or a character it lacks.
+pub fn glyph_for_char(font: &FontRef, c: char) -> Option<u16> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontGetGlyphsForCharacters(
+            font: *const c_void,
+            characters: *const u16,
+            glyphs: *mut u16,
+            count: isize,
+        ) -> bool;
+    }
+    
+    if font.is_null() {
+        return None;
+    }
+    let mut units = [0u16; 2];
+    let characters = c.encode_utf16(&mut units);
+    // The glyph for a surrogate pair comes back in the first slot
+    let mut glyphs = [0u16; 2];
+    let found = unsafe {
+        CTFontGetGlyphsForCharacters(font.as_ptr(), characters.as_ptr(), glyphs.as_mut_ptr(), characters.len() as isize)
+    };
+    if found && glyphs[0] != 0 {
+        Some(glyphs[0])
+    } else {
+        None
+    }
+}
+
+// Find run boundaries where a run starts with a combining mark right after a run ending in a base
+// character. CoreText does this when the base and mark fall back to different fonts, which
+// breaks mark positioning because the two are shaped separately.
+pub fn find_split_combining_marks(runs: &[TextRun]) -> Vec<SplitCombiningMark> {
+    let mut out = Vec::new();
+    
+    for (run_index, pair) in runs.windows(2).enumerate() {
+        let (prev, run) = (&pair[0], &pair[1]);
+        if prev.start_utf16.checked_add(prev.length_utf16) != Some(run.start_utf16) {
+            continue;
+        }
+        let ends_with_base = prev.text.chars().last().is_some_and(|c| !is_combining_mark(c));
+        let starts_with_mark = run.text.chars().next().is_some_and(is_combining_mark);
+        
+        if ends_with_base && starts_with_mark {
+            warn(format_args!(
+                "combining mark at UTF-16 {} split from its base ({} | {})",
+                run.start_utf16, prev.font_name, run.font_name
+            ));
+            out.push(SplitCombiningMark { run_index: run_index + 1, mark_utf16: run.start_utf16 });
+        }
+    }
+    
+    out
+}
+
+// Move combining marks split off by CoreText back onto the run holding their base character,
+// when that run's font has glyphs for them. Runs emptied by the move are dropped; marks the
+// base font cannot render are left in place.
+pub fn merge_split_combining_marks(mut runs: Vec<TextRun>) -> Vec<TextRun> {
+    // Walk backwards so indices stay valid as runs are removed
+    for split in find_split_combining_marks(&runs).into_iter().rev() {
+        let (head, tail) = runs.split_at_mut(split.run_index);
+        let prev = &mut head[split.run_index - 1];
+        let run = &mut tail[0];
+        
+        let marks: String = run.text.chars().take_while(|&c| is_combining_mark(c)).collect();
+        if !font_has_glyphs(prev.font.as_ptr(), &marks) {
+            continue;
+        }
+        
+        let marks_utf16 = utf16_len(&marks);
+        prev.text.push_str(&marks);
+        prev.length_utf16 += marks_utf16;
+        prev.coretext_glyph_count = None;
+        run.text.drain(..marks.len());
+        run.start_utf16 += marks_utf16;
+        run.length_utf16 -= marks_utf16;
+        run.coretext_glyph_count = None;
+        
+        if run.length_utf16 == 0 {
+            runs.remove(split.run_index);
+        }
+    }
+    
+    runs
+}
+
+// Length of `text` in UTF-16 code units (astral characters count as two), the unit of every run range
+pub fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+// Byte index in `text` of the character containing UTF-16 offset `utf16_index` (the start of its
+// surrogate pair when it points at a low surrogate); offsets past the end give text.len()
+pub fn utf16_to_utf8_index(text: &str, utf16_index: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        units += c.len_utf16();
+        if units > utf16_index {
+            return i;
+        }
+    }
+    text.len()
+}
+
+// UTF-16 offset of byte index `byte_index` in `text`, rounding down to a character boundary;
+// indices past the end give the UTF-16 length of the text
+pub fn utf8_to_utf16_index(text: &str, byte_index: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if i + c.len_utf8() > byte_index {
+            break;
+        }
+        units += c.len_utf16();
+    }
+    units
+}
+
+// The caret position after UTF-16 `index`: the end of the grapheme cluster (emoji sequence,
+// base plus marks, Hangul syllable) containing it, via CFStringGetRangeOfComposedCharactersAtIndex.
+// Indices at or past the end give the text's length.
+pub fn next_caret(text: &str, index: usize) -> usize {
+    let length = utf16_len(text);
+    if index >= length {
+        return length;
+    }
+    match composed_character_range(text, index) {
+        Some(range) => range.end,
+        None => index + 1,
+    }
+}
+
+// The caret position before UTF-16 `index`: the start of the grapheme cluster holding the unit
+// before it. 0 stays at 0; indices past the end are clamped first.
+pub fn prev_caret(text: &str, index: usize) -> usize {
+    let index = index.min(utf16_len(text));
+    if index == 0 {
+        return 0;
+    }
+    match composed_character_range(text, index - 1) {
+        Some(range) => range.start,
+        None => index - 1,
+    }
+}
+
+fn composed_character_range(text: &str, index: usize) -> Option<Range<usize>> {
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFStringGetRangeOfComposedCharactersAtIndex(string: *const c_void, index: isize) -> CFRange;
+    }
+    
+    let cf_string = create_cf_string(text)?;
+    let range = unsafe {
+        CFStringGetRangeOfComposedCharactersAtIndex(cf_string.as_concrete_TypeRef() as *const c_void, index as isize)
+    };
+    if range.location < 0 || range.length <= 0 {
+        return None;
+    }
+    let start = range.location as usize;
+    Some(start..start + range.length as usize)
+}
+
+// Byte offset in `text` of the UTF-16 offset `utf16`; None if it is past the end or inside a surrogate pair
+fn utf8_offset(text: &str, utf16: usize) -> Option<usize> {
+    let index = utf16_to_utf8_index(text, utf16);
+    if utf8_to_utf16_index(text, index) == utf16 {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+// Subdivide runs at the given absolute UTF-16 offsets (e.g. style run edges from a rich-text model)
+// without re-itemizing. Every piece keeps (a clone of) its run's font. Boundaries outside
+// a run's interior are ignored, as are ones that would split a surrogate pair (with a warning).
+pub fn split_at_boundaries(runs: Vec<TextRun>, boundaries: &[usize]) -> Vec<TextRun> {
+    let mut boundaries = boundaries.to_vec();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    
+    let mut out = Vec::with_capacity(runs.len() + boundaries.len());
+    for mut run in runs {
+        let (start, end) = (run.start_utf16, run.start_utf16 + run.length_utf16);
+        for &boundary in boundaries.iter().filter(|&&b| b > start && b < end) {
+            let head_length = boundary - run.start_utf16;
+            let split = match utf8_offset(&run.text, head_length) {
+                Some(split) => split,
+                None => {
+                    warn(format_args!("boundary {} splits a surrogate pair; ignoring", boundary));
+                    continue;
+                }
+            };
+            let rest = run.text.split_off(split);
+            run.coretext_glyph_count = None;
+            out.push(TextRun {
+                text: std::mem::replace(&mut run.text, rest),
+                length_utf16: head_length,
+                ..run.clone()
+            });
+            run.start_utf16 = boundary;
+            run.length_utf16 -= head_length;
+        }
+        out.push(run);
+    }
+    out
+}
+
+// Split runs wherever they switch between control and visible characters
+fn split_control_runs(runs: Vec<TextRun>) -> Vec<TextRun> {
+    let mut boundaries = Vec::new();
+    for run in &runs {
+        let mut offset = run.start_utf16;
+        let mut previous = None;
+        for c in run.text.chars() {
+            let control = is_control_character(c);
+            if previous.is_some_and(|p| p != control) {
+                boundaries.push(offset);
+            }
+            previous = Some(control);
+            offset += c.len_utf16();
+        }
+    }
+    split_at_boundaries(runs, &boundaries)
+}
+
+// Split runs wherever they switch between emoji and other characters, even inside one font
+// (e.g. to draw emoji with a separate renderer). Each piece keeps its run's font.
+// Emoji modifiers, ZWJ, variation selectors, keycap and tag characters stay with the character
+// before them, so sequences like family or flag emoji are not broken up.
+pub fn split_emoji(runs: Vec<TextRun>) -> Vec<TextRun> {
+    let mut boundaries = Vec::new();
+    for run in &runs {
+        let mut offset = run.start_utf16;
+        let mut previous = None;
+        for c in run.text.chars() {
+            if !is_emoji_extender(c) {
+                let emoji = is_emoji_character(c);
+                if previous.is_some_and(|p| p != emoji) {
+                    boundaries.push(offset);
+                }
+                previous = Some(emoji);
+            }
+            offset += c.len_utf16();
+        }
+    }
+    split_at_boundaries(runs, &boundaries)
+}
+
+// Extended_Pictographic characters (approximated by the blocks that hold them) and regional indicators
+fn is_emoji_character(c: char) -> bool {
+    matches!(c,
+        '\u{00A9}' | '\u{00AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{2199}' | '\u{21A9}'..='\u{21AA}' | '\u{231A}'..='\u{231B}' | '\u{2328}'
+        | '\u{2388}' | '\u{23CF}' | '\u{23E9}'..='\u{23F3}' | '\u{23F8}'..='\u{23FA}' | '\u{24C2}'
+        | '\u{25AA}'..='\u{25AB}' | '\u{25B6}' | '\u{25C0}' | '\u{25FB}'..='\u{25FE}'
+        | '\u{2600}'..='\u{27BF}' | '\u{2934}'..='\u{2935}' | '\u{2B05}'..='\u{2B07}'
+        | '\u{2B1B}'..='\u{2B1C}' | '\u{2B50}' | '\u{2B55}' | '\u{3030}' | '\u{303D}' | '\u{3297}'
+        | '\u{3299}' | '\u{1F000}'..='\u{1F3FA}' | '\u{1F400}'..='\u{1FAFF}' | '\u{1FC00}'..='\u{1FFFD}')
+}
+
+// Characters that only modify the emoji before them: ZWJ, VS15/VS16, the combining keycap,
+// skin tone modifiers and tag characters
+fn is_emoji_extender(c: char) -> bool {
+    matches!(c, '\u{200D}' | '\u{FE0E}' | '\u{FE0F}' | '\u{20E3}' | '\u{1F3FB}'..='\u{1F3FF}' | '\u{E0020}'..='\u{E007F}')
+}
+
+pub fn split_str_into_runs_impl(text: &str, font_size: f64) {
+    // Create base font using system UI font
+    let font = create_base_font(font_size);
+    
+    // Debug: Print the font name to see what we got
+    unsafe {
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
+        }
+        let ps_name_ref = CTFontCopyPostScriptName(font.as_concrete_TypeRef() as *const c_void);
+        if !ps_name_ref.is_null() {
+            let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
+            println!("DEBUG: Created base font: {}", ps_name_cf.to_string());
+        }
+    }
+    
+    // Create CFString from Rust string
+    let cf_string = match create_cf_string(text) {
+        Some(cf_string) => cf_string,
+        None => return,
+    };
+    
+    // Create mutable attributed string
+    let mut attributed_string = CFMutableAttributedString::new();
+    attributed_string.replace_str(&cf_string, CFRange::init(0, 0));
+    
+    // Set the font attribute for the entire string using C API
+    // This forces Core Text to use our font, though it may still create separate runs
+    // for characters that need fallback fonts (Chinese, emoji, etc.)
+    unsafe {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFAttributedStringSetAttribute(
+                aStr: *mut c_void,
+                range: CFRange,
+                attrName: *const c_void,
+                value: *const c_void,
+            );
+            fn CFAttributedStringGetLength(aStr: *const c_void) -> isize;
+        }
+        
+        // Get the actual length of the attributed string (in UTF-16 code units)
+        let text_length = CFAttributedStringGetLength(attributed_string.as_concrete_TypeRef() as *const c_void);
+        
+        // Validate inputs before calling CFAttributedStringSetAttribute
+        let attr_str_ptr = attributed_string.as_concrete_TypeRef() as *mut c_void;
+        let font_ptr = font.as_concrete_TypeRef() as *const c_void;
+        
+        // Check that pointers are valid
+        if attr_str_ptr.is_null() {
+            println!("DEBUG: Error - attributed string pointer is null!");
+            return;
+        }
+        if font_ptr.is_null() {
+            println!("DEBUG: Error - font pointer is null!");
+            return;
+        }
+        
+        // Get the font attribute name key
+        if let Some(font_key_ptr) = get_font_attribute_name() {
+            println!("DEBUG: Font key pointer: {:p}", font_key_ptr);
+            println!("DEBUG: Font pointer: {:p}", font_ptr);
+            println!("DEBUG: Attributed string pointer: {:p}", attr_str_ptr);
+            println!("DEBUG: Text length: {}", text_length);
+            
+            // Set the font attribute - the font must be retained, which TCFType handles
+            // kCTFontAttributeName is already a CFStringRef, so we can use it directly
+            CFAttributedStringSetAttribute(
+                attr_str_ptr,
+                CFRange::init(0, text_length as isize),
+                font_key_ptr,
+                font_ptr,
+            );
+            println!("DEBUG: Font attribute set on attributed string");
+        } else {
+            println!("DEBUG: Warning - Could not get font attribute name, skipping font setting");
+        }
+    }
+    
+    // Create framesetter
+    let framesetter = CTFramesetter::new_with_attributed_string(attributed_string.as_concrete_TypeRef());
+    
+    // Create a path (rectangular path for layout)
+    let bounds = CGRect::new(
+        &CGPoint::new(0.0, 0.0),
+        &CGSize::new(f64::MAX, f64::MAX),
+    );
+    let path = CGPath::from_rect(bounds, None);
+    
+    // Create frame
+    let frame = framesetter.create_frame(
+        CFRange::init(0, 0),
+        &path,
+    );
+    
+    // Get lines from frame using C API
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFrameGetLines(frame: *const c_void) -> *const c_void;
+        fn CFArrayGetCount(array: *const c_void) -> isize;
+        fn CFArrayGetValueAtIndex(array: *const c_void, index: isize) -> *const c_void;
+    }
+    
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    }
+    
+    unsafe {
+        let lines_array = CTFrameGetLines(frame.as_concrete_TypeRef() as *const c_void);
+        let line_count = CFArrayGetCount(lines_array) as usize;
+        
+        println!("Number of lines: {}", line_count);
+        println!("Text: \"{}\"", text);
+        println!("---");
+        
+        // Iterate through lines
+        for line_idx in 0..line_count {
+            let line_ref = CFArrayGetValueAtIndex(lines_array, line_idx as isize);
+            let line = CTLine::wrap_under_get_rule(line_ref as *mut _);
+            let runs = line.glyph_runs();
+            
+            println!("Line {}: {} runs", line_idx, runs.len());
+            
+            // Iterate through runs in each line
+            for (run_idx, run) in runs.iter().enumerate() {
+                let run = CTRun::wrap_under_get_rule(run.as_concrete_TypeRef());
+                
+                // Get font from run attributes using C API directly
+                #[link(name = "CoreText", kind = "framework")]
+                extern "C" {
+                    fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
+                }
+                
+                let attributes_dict = CTRunGetAttributes(run.as_concrete_TypeRef() as *const c_void);
+                
+                let font_ptr = if !attributes_dict.is_null() {
+                    // Get the font attribute name key
+                    if let Some(font_key_ptr) = get_font_attribute_name() {
+                        // Get the font value from the attributes dictionary
+                        let font_value_ref = CFDictionaryGetValue(
+                            attributes_dict,
+                            font_key_ptr,
+                        );
+                        
+                        if font_value_ref.is_null() {
+                            ptr::null()
+                        } else {
+                            font_value_ref
+                        }
+                    } else {
+                        ptr::null()
+                    }
+                } else {
+                    ptr::null()
+                };
+                
+                // Get PostScript name from font
+                let postscript_name = if !font_ptr.is_null() {
+                    #[link(name = "CoreText", kind = "framework")]
+                    extern "C" {
+                        fn CTFontCopyPostScriptName(font: *const c_void) -> *const c_void;
+                    }
+                    
+                    let ps_name_ref = CTFontCopyPostScriptName(font_ptr);
+                    if ps_name_ref.is_null() {
+                        String::from("(null)")
+                    } else {
+                        let ps_name_cf = CFString::wrap_under_create_rule(ps_name_ref as *mut _);
+                        ps_name_cf.to_string()
+                    }
+                } else {
+                    String::from("(no font)")
+                };
+                
+                // Get run text range using C API
+                // Note: CFRange uses UTF-16 code units, need to convert to UTF-8 byte indices
+                #[link(name = "CoreText", kind = "framework")]
+                extern "C" {
+                    fn CTRunGetStringRange(run: *const c_void) -> CFRange;
+                }
+                let range = CTRunGetStringRange(run.as_concrete_TypeRef() as *const c_void);
+                let start_utf16 = range.location as usize;
+                let length_utf16 = range.length as usize;
+                
+                // Convert UTF-16 indices to UTF-8 byte indices
+                let text_utf16: Vec<u16> = text.encode_utf16().collect();
+                let end_utf16 = match start_utf16.checked_add(length_utf16) {
+                    Some(end) => end,
+                    None => {
+                        println!(
+                            "  Run {}: skipped, UTF-16 range overflows: start {} + length {}",
+                            run_idx, start_utf16, length_utf16
+                        );
+                        continue;
+                    }
+                };
+                let run_text = if end_utf16 <= text_utf16.len() {
+                    let utf16_slice = &text_utf16[start_utf16..end_utf16];
+                    decode_run_utf16(utf16_slice, InvalidUtf16::default()).unwrap_or_default()
+                } else {
+                    String::from("")
+                };
+                
+                println!(
+                    "  Run {}: \"{}\" | Font pointer: {:p} | PostScript name: {}",
+                    run_idx, run_text, font_ptr, postscript_name
+                );
+            }
+            println!("---");
+        }
+    }
+}
+
+
+// Lay out `run.text` again in the run's own font as a single CTLine and call `f` with each live CTRun.
+// This gives access to what CoreText itself computed for the run, independently of HarfBuzz.
+fn for_each_coretext_run(run: &TextRun, mut f: impl FnMut(&CTRun)) {
+    if run.font.is_null() || run.text.is_empty() {
+        return;
+    }
+    
+    let cf_string = match create_cf_string(&run.text) {
+        Some(cf_string) => cf_string,
+        None => return,
+    };
+    let mut attributed_string = CFMutableAttributedString::new();
+    attributed_string.replace_str(&cf_string, CFRange::init(0, 0));
+    
+    unsafe {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFAttributedStringSetAttribute(
+                aStr: *mut c_void,
+                range: CFRange,
+                attrName: *const c_void,
+                value: *const c_void,
+            );
+        }
+        
+        let font_key_ptr = match get_font_attribute_name() {
+            Some(key) => key,
+            None => return,
+        };
+        // The attributed string retains the font for itself
+        CFAttributedStringSetAttribute(
+            attributed_string.as_concrete_TypeRef() as *mut c_void,
+            CFRange::init(0, attributed_string.char_len()),
+            font_key_ptr,
+            run.font.as_ptr(),
+        );
+    }
+    
+    let line = CTLine::new_with_attributed_string(attributed_string.as_concrete_TypeRef());
+    for ct_run in line.glyph_runs().iter() {
+        f(&ct_run);
+    }
+}
+
+// CoreText's own glyph advances (width, height in points) for a run, read with CTRunGetAdvances.
+// Useful for diagnosing discrepancies between CoreText and HarfBuzz shaping.
+pub fn coretext_advances(run: &TextRun) -> Vec<(f64, f64)> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTRunGetGlyphCount(run: *const c_void) -> isize;
+        fn CTRunGetAdvances(run: *const c_void, range: CFRange, buffer: *mut CGSize);
+    }
+    
+    let mut out = Vec::new();
+    for_each_coretext_run(run, |ct_run| unsafe {
+        let run_ref = ct_run.as_concrete_TypeRef() as *const c_void;
+        let count = CTRunGetGlyphCount(run_ref).max(0) as usize;
+        let mut advances = vec![CGSize::new(0.0, 0.0); count];
+        CTRunGetAdvances(run_ref, CFRange::init(0, 0), advances.as_mut_ptr());
+        out.extend(advances.iter().map(|size| (size.width, size.height)));
+    });
+    out
+}
+
+// Ink bounds of a run as (x, y, width, height) in points, relative to the run's pen origin with y up:
+// the union of its glyphs' bounding boxes at the positions CoreText gives them. Unlike the advance
+// width this includes overhangs such as an italic f or a swash reaching past its neighbors, so it
+// is what to clip or invalidate. (0.0, 0.0, 0.0, 0.0) for a run without inked glyphs.
+// Glyphs are measured in the font CoreText actually used for them, which differs from run.font
+// when it falls back (e.g. emoji in a Latin font).
+pub fn ink_bounds(run: &TextRun) -> (f64, f64, f64, f64) {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFontGetBoundingRectsForGlyphs(
+            font: *const c_void,
+            orientation: u32,
+            glyphs: *const u16,
+            bounding_rects: *mut CGRect,
+            count: isize,
+        ) -> CGRect;
+        fn CTRunGetAttributes(run: *const c_void) -> *const c_void;
+    }
+    
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        fn CFDictionaryGetValue(dict: *const c_void, key: *const c_void) -> *const c_void;
+    }
+    
+    let mut bounds: Option<(f64, f64, f64, f64)> = None;
+    for_each_coretext_run(run, |ct_run| {
+        let glyphs = ct_run.glyphs();
+        let positions = ct_run.positions();
+        let mut rects = vec![CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(0.0, 0.0)); glyphs.len()];
+        unsafe {
+            // Borrowed from the run's attributes, which outlive this call
+            let attrs = CTRunGetAttributes(ct_run.as_concrete_TypeRef() as *const c_void);
+            let font_ptr = if attrs.is_null() {
+                ptr::null()
+            } else {
+                CFDictionaryGetValue(attrs, kCTFontAttributeName as *const c_void)
+            };
+            let font_ptr = if font_ptr.is_null() { run.font.as_ptr() } else { font_ptr };
+            // kCTFontOrientationHorizontal
+            CTFontGetBoundingRectsForGlyphs(font_ptr, 1, glyphs.as_ptr(), rects.as_mut_ptr(), glyphs.len() as isize);
+        }
+        for (rect, position) in rects.iter().zip(positions.iter()) {
+            if rect.size.width <= 0.0 && rect.size.height <= 0.0 {
+                continue;
+            }
+            let min_x = position.x + rect.origin.x;
+            let min_y = position.y + rect.origin.y;
+            let max_x = min_x + rect.size.width;
+            let max_y = min_y + rect.size.height;
+            bounds = Some(match bounds {
+                Some((x0, y0, x1, y1)) => (x0.min(min_x), y0.min(min_y), x1.max(max_x), y1.max(max_y)),
+                None => (min_x, min_y, max_x, max_y),
+            });
+        }
+    });
+    match bounds {
+        Some((x0, y0, x1, y1)) => (x0, y0, x1 - x0, y1 - y0),
+        None => (0.0, 0.0, 0.0, 0.0),
+    }
+}
+
+// For each glyph CoreText produces for a run, the UTF-16 index in the source text of the character it
+// came from (CTRunGetStringIndices plus run.start_utf16). A ligature maps to its first character.
+// Unlike HarfBuzz clusters this is CoreText's own mapping, suitable for caret and selection handling.
+pub fn coretext_string_indices(run: &TextRun) -> Vec<usize> {
+    let mut out = Vec::new();
+    for_each_coretext_run(run, |ct_run| {
+        // Indices are into run.text, which the run was laid out from on its own
+        out.extend(ct_run.string_indices().iter().map(|&i| run.start_utf16 + i.max(0) as usize));
+    });
+    out
+}
+
+// A line stretched to a target width by CoreText (CTLineCreateJustifiedLine), see justify_line
+#[derive(Debug, Clone)]
+pub struct JustifiedLine {
+    pub runs: Vec<JustifiedRun>,
+    // Typographic width before justification
+    pub natural_width: f64,
+    // Typographic width after justification (the target, when CoreText could reach it)
+    pub width: f64,
+    // Justification factor passed to CoreText: 1.0 is full justification
+    pub factor: f64,
+}
+
+// One run of a justified line, in visual order
+#[derive(Debug, Clone)]
+pub struct JustifiedRun {
+    pub start_utf16: usize,
+    pub length_utf16: usize,
+    // Per-glyph x advances in points after justification (stretched mostly at spaces)
+    pub advances: Vec<f64>,
+}
+
+// Fully justify the first line of `text` to `width` points and return the stretched advances.
+// None if the text is empty or CoreText declines to justify (e.g. the line is wider than `width`).
+pub fn justify_line(text: &str, font_size: f64, width: f64, options: &LayoutOptions) -> Option<JustifiedLine> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTLineCreateJustifiedLine(line: *const c_void, factor: f64, width: f64) -> *const c_void;
+        fn CTRunGetGlyphCount(run: *const c_void) -> isize;
+        fn CTRunGetAdvances(run: *const c_void, range: CFRange, buffer: *mut CGSize);
+        fn CTRunGetStringRange(run: *const c_void) -> CFRange;
+    }
+    const FULL_JUSTIFICATION: f64 = 1.0;
+    
+    let frame = create_frame(text, font_size, options);
+    let lines = frame.get_lines();
+    let line = lines.first()?;
+    let natural_width = line.get_typographic_bounds().width;
+    
+    let justified_ref = unsafe {
+        CTLineCreateJustifiedLine(line.as_concrete_TypeRef() as *const c_void, FULL_JUSTIFICATION, width)
+    };
+    if justified_ref.is_null() {
+        warn(format_args!("CoreText could not justify the line to width {}", width));
+        return None;
+    }
+    let justified = unsafe { CTLine::wrap_under_create_rule(justified_ref as *mut _) };
+    
+    let runs = justified
+        .glyph_runs()
+        .iter()
+        .map(|ct_run| unsafe {
+            let run_ref = ct_run.as_concrete_TypeRef() as *const c_void;
+            let count = CTRunGetGlyphCount(run_ref).max(0) as usize;
+            let mut advances = vec![CGSize::new(0.0, 0.0); count];
+            CTRunGetAdvances(run_ref, CFRange::init(0, 0), advances.as_mut_ptr());
+            let range = CTRunGetStringRange(run_ref);
+            JustifiedRun {
+                start_utf16: range.location.max(0) as usize,
+                length_utf16: range.length.max(0) as usize,
+                advances: advances.iter().map(|size| size.width).collect(),
+            }
+        })
+        .collect();
+    
+    Some(JustifiedLine {
+        runs,
+        natural_width,
+        width: justified.get_typographic_bounds().width,
+        factor: FULL_JUSTIFICATION,
+    })
+}
+
+// Options controlling how a run is shaped with HarfBuzz
+#[derive(Debug, Clone, Default)]
+pub struct ShapeOptions {
+    // Always shape left-to-right, ignoring the direction detected from the run's text.
+    // Gives a deterministic glyph order for quick, approximate width measurement. This is also
+    // how every run was shaped before direction detection: RTL runs now shape RTL by default,
+    // while Latin and script-neutral text is still shaped LTR.
+    pub force_ltr: bool,
+    // Return advances and offsets in the font's design units (unitsPerEm) instead of the
+    // hb_font scale, making them independent of the point size. See to_design_units.
+    pub design_units: bool,
+    // Round x/y advances to whole device pixels at this scale (pixels per point, e.g. 1.0 for
+    // non-retina, 2.0 for retina), mirroring grid-fitted rendering. Opt-in: rounding changes
+    // the measured text width. Positions are then in 1/64 pixel units (see position_scale).
+    pub pixel_scale: Option<f64>,
+    // ISO 15924 script tag (e.g. *b"Arab") passed to hb_buffer_set_script instead of the script
+    // detected from the text, for callers that know it better than the heuristic
+    pub script_override: Option<[u8; 4]>,
+    // Digit spacing and case, applied as OpenType features (tnum/pnum, lnum/onum)
+    pub figures: FigureStyle,
+    // Shape in this direction instead of the one detected from the text or script (force_ltr still wins)
+    pub direction: Option<TextDirection>,
+    // Whether the run starts/ends its text, passed to HarfBuzz as HB_BUFFER_FLAG_BOT/EOT. These
+    // control edge behavior such as inserting a dotted circle before a mark that begins the text.
+    // None derives them when shaping in context (a run at the start/end of the context or of a
+    // paragraph in it) and sets neither for a run shaped on its own, which may be a fragment.
+    pub text_edges: Option<TextEdges>,
+    // BCP 47 language passed to hb_buffer_set_language (e.g. "tr" for Turkish locl forms);
+    // None shapes as English
+    pub language: Option<String>,
+    // Fixed x advances in points for specific characters (e.g. PUA icons that must line up on a
+    // grid), applied after shaping: the first glyph of the character's cluster gets the advance and
+    // any other glyphs in that cluster get zero, so the whole cluster is exactly that wide
+    pub advance_overrides: Vec<(char, f64)>,
+    // Extra OpenType features for hb_shape, applied after `figures` (so they win on conflicts),
+    // e.g. from parse_features
+    pub features: Vec<harfbuzz_sys::hb_feature_t>,
+}
+
+// See ShapeOptions::text_edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TextEdges {
+    pub beginning_of_text: bool,
+    pub end_of_text: bool,
+}
+
+impl TextEdges {
+    // Edges of the run at `offset..offset + len` bytes of `context`; paragraph separators count as edges
+    fn in_context(context: &str, offset: usize, len: usize) -> Self {
+        let is_separator = |c: char| matches!(c, '\n' | '\r' | '\u{2029}');
+        TextEdges {
+            beginning_of_text: context[..offset].chars().next_back().is_none_or(is_separator),
+            end_of_text: context[offset + len..].chars().next().is_none_or(is_separator),
+        }
+    }
+    
+    fn hb_flags(&self) -> harfbuzz_sys::hb_buffer_flags_t {
+        let mut flags = harfbuzz_sys::HB_BUFFER_FLAG_DEFAULT;
+        if self.beginning_of_text {
+            flags |= harfbuzz_sys::HB_BUFFER_FLAG_BOT;
+        }
+        if self.end_of_text {
+            flags |= harfbuzz_sys::HB_BUFFER_FLAG_EOT;
+        }
+        flags
+    }
+}
+
+// Horizontal text direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextDirection {
+    LeftToRight,
+    RightToLeft,
+}
+
+// Numeral style requested through OpenType features; None leaves the font's default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FigureStyle {
+    pub spacing: Option<FigureSpacing>,
+    pub case: Option<FigureCase>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FigureSpacing {
+    // Equal-width digits that line up in columns (tnum)
+    Tabular,
+    // Digits with their natural widths (pnum)
+    Proportional,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FigureCase {
+    // Digits of cap height sitting on the baseline (lnum)
+    Lining,
+    // Digits with ascenders and descenders like lowercase text (onum)
+    Oldstyle,
+}
+
+impl FigureStyle {
+    // The HarfBuzz features for this style, enabled over the whole buffer
+    fn features(&self) -> Vec<harfbuzz_sys::hb_feature_t> {
+        let spacing = self.spacing.map(|spacing| match spacing {
+            FigureSpacing::Tabular => *b"tnum",
+            FigureSpacing::Proportional => *b"pnum",
+        });
+        let case = self.case.map(|case| match case {
+            FigureCase::Lining => *b"lnum",
+            FigureCase::Oldstyle => *b"onum",
+        });
+        spacing
+            .into_iter()
+            .chain(case)
+            .map(|tag| harfbuzz_sys::hb_feature_t {
+                tag: u32::from_be_bytes(tag),
+                value: 1,
+                start: 0,
+                end: u32::MAX,
+            })
+            .collect()
+    }
+}
+
+// Horizontal direction of the first character in `text` whose script has one
+// (i.e. skipping Common/Inherited/Unknown characters such as digits and punctuation)
+fn detect_direction(text: &str) -> harfbuzz_sys::hb_direction_t {
+    match detect_script(text) {
+        Some(script) => match unsafe { harfbuzz_sys::hb_script_get_horizontal_direction(script) } {
+            harfbuzz_sys::HB_DIRECTION_INVALID => harfbuzz_sys::HB_DIRECTION_LTR,
+            direction => direction,
+        },
+        None => harfbuzz_sys::HB_DIRECTION_LTR,
+    }
+}
+
+// Base direction of the first paragraph of `text` from its first strong character (UAX #9 rules
+// P2/P3), without itemizing it; None if it has no strong character. Text inside isolates is
+// skipped. Strong characters are those with Bidi_Class L, R or AL (from ICU), so the marks LRM,
+// RLM and ALM count while Arabic-Indic digits (AN) and European digits (EN) do not.
+pub fn paragraph_direction(text: &str) -> Option<TextDirection> {
+    #[link(name = "icucore")]
+    extern "C" {
+        fn u_charDirection(c: i32) -> i32;
+    }
+    // UCharDirection values for the strong classes
+    const U_LEFT_TO_RIGHT: i32 = 0;
+    const U_RIGHT_TO_LEFT: i32 = 1;
+    const U_RIGHT_TO_LEFT_ARABIC: i32 = 13;
+    
+    let mut isolate_depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '\n' | '\r' | '\u{001C}'..='\u{001E}' | '\u{0085}' | '\u{2029}' => break,
+            '\u{2066}'..='\u{2068}' => {
+                isolate_depth += 1;
+                continue;
+            }
+            '\u{2069}' => {
+                isolate_depth = isolate_depth.saturating_sub(1);
+                continue;
+            }
+            _ if isolate_depth > 0 => continue,
+            _ => {}
+        }
+        match unsafe { u_charDirection(c as i32) } {
+            U_LEFT_TO_RIGHT => return Some(TextDirection::LeftToRight),
+            U_RIGHT_TO_LEFT | U_RIGHT_TO_LEFT_ARABIC => return Some(TextDirection::RightToLeft),
+            _ => continue,
+        }
+    }
+    None
+}
+
+// The first real script in `text`, skipping Common/Inherited/Unknown characters (digits, spaces, marks)
+fn detect_script(text: &str) -> Option<harfbuzz_sys::hb_script_t> {
+    unsafe {
+        let funcs = harfbuzz_sys::hb_unicode_funcs_get_default();
+        for c in text.chars() {
+            let script = harfbuzz_sys::hb_unicode_script(funcs, c as u32);
+            if script == harfbuzz_sys::HB_SCRIPT_COMMON
+                || script == harfbuzz_sys::HB_SCRIPT_INHERITED
+                || script == harfbuzz_sys::HB_SCRIPT_UNKNOWN
+            {
+                continue;
+            }
+            return Some(script);
+        }
+    }
+    None
+}
+
+// Function to shape a run with HarfBuzz using harfbuzz_sys directly with CTFont.
+// The direction comes from the run's text (detect_direction): Hebrew or Arabic runs shape RTL,
+// with glyphs in visual order. Set ShapeOptions::force_ltr for the old always-LTR behavior.
+pub fn shape_run_with_harfbuzz(run: &TextRun) -> Option<ShapingResult> {
+    shape_run_with_options(run, &ShapeOptions::default())
+}
+
+// Shape a run with HarfBuzz, applying `options`
+pub fn shape_run_with_options(run: &TextRun, options: &ShapeOptions) -> Option<ShapingResult> {
+    Shaper::new()?.shape(run, options)
+}
+
+// Shape a run that was itemized from `text`, passing the neighboring characters to HarfBuzz as
+// pre/post context. Cursive scripts like Arabic then pick the correct joining forms at run edges.
+// Like every HarfBuzz path, the buffer script is detected from the run's text (LATIN when it has
+// none) rather than always LATIN; set ShapeOptions::script_override to choose it.
+pub fn shape_run_in_context(run: &TextRun, text: &str, options: &ShapeOptions) -> Option<ShapingResult> {
+    Shaper::new()?.shape_in_context(run, text, options)
+}
+
+// Reusable HarfBuzz shaping state. Owns one hb_buffer that is cleared between runs instead of
+// creating and destroying a buffer per run, which cuts allocation churn when shaping paragraphs.
+pub struct Shaper {
+    buffer: *mut harfbuzz_sys::hb_buffer_t,
+}
+
+impl Shaper {
+    // Create a shaper; None if HarfBuzz could not allocate a buffer
+    pub fn new() -> Option<Self> {
+        let buffer = unsafe { harfbuzz_sys::hb_buffer_create() };
+        if buffer.is_null() || unsafe { harfbuzz_sys::hb_buffer_allocation_successful(buffer) } == 0 {
+            unsafe { harfbuzz_sys::hb_buffer_destroy(buffer) };
+            return None;
+        }
+        Some(Shaper { buffer })
+    }
+    
+    // Shape a run, reusing this shaper's buffer
+    pub fn shape(&mut self, run: &TextRun, options: &ShapeOptions) -> Option<ShapingResult> {
+        self.try_shape(run, options).ok()
+    }
+    
+    // Like shape, reporting why shaping failed
+    pub fn try_shape(&mut self, run: &TextRun, options: &ShapeOptions) -> Result<ShapingResult, ShapeError> {
+        unsafe {
+            harfbuzz_sys::hb_buffer_clear_contents(self.buffer);
+        }
+        shape_run_into_buffer(self.buffer, run, None, options)
+    }
+    
+    // Like shape, but with the surrounding characters of `text` as pre/post context
+    pub fn shape_in_context(&mut self, run: &TextRun, text: &str, options: &ShapeOptions) -> Option<ShapingResult> {
+        self.try_shape_in_context(run, text, options).ok()
+    }
+    
+    // Like shape_in_context, reporting why shaping failed
+    pub fn try_shape_in_context(&mut self, run: &TextRun, text: &str, options: &ShapeOptions) -> Result<ShapingResult, ShapeError> {
+        unsafe {
+            harfbuzz_sys::hb_buffer_clear_contents(self.buffer);
+        }
+        shape_run_into_buffer(self.buffer, run, Some(text), options)
+    }
+}
+
+// Why a run could not be shaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShapeError {
+    // The run has no font
+    NullFont,
+    // HarfBuzz could not allocate a buffer
+    BufferAllocation,
+    // HarfBuzz could not create a font from the run's CTFont
+    FontCreation,
+    // The run's text contains a NUL, which cannot be passed to HarfBuzz
+    InteriorNul,
+    // Shaping produced no glyphs
+    NoGlyphs,
+    // No installed font has the requested PostScript name
+    FontNotFound,
+    // The byte range is out of bounds or not on character boundaries
+    InvalidRange,
+    // A feature string entry is not valid hb_feature_from_string syntax
+    InvalidFeature,
+}
+
+impl std::fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            ShapeError::NullFont => "run has no font",
+            ShapeError::BufferAllocation => "could not allocate a HarfBuzz buffer",
+            ShapeError::FontCreation => "could not create a HarfBuzz font from the CTFont",
+            ShapeError::InteriorNul => "run text contains a NUL",
+            ShapeError::NoGlyphs => "shaping produced no glyphs",
+            ShapeError::FontNotFound => "font not found",
+            ShapeError::InvalidRange => "byte range is not a valid slice of the text",
+            ShapeError::InvalidFeature => "invalid feature string",
+        };
+        write!(f, "cannot shape run: {}", reason)
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+// Parse comma-separated features in CSS / hb_feature_from_string syntax, e.g. "liga on, smcp, tnum",
+// "-kern" or "aalt=2". Empty entries are skipped; any invalid entry fails the whole string.
+pub fn parse_features(features: &str) -> Result<Vec<harfbuzz_sys::hb_feature_t>, ShapeError> {
+    let mut out = Vec::new();
+    for entry in features.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let mut feature = harfbuzz_sys::hb_feature_t { tag: 0, value: 0, start: 0, end: 0 };
+        let parsed = unsafe {
+            harfbuzz_sys::hb_feature_from_string(entry.as_ptr() as *const i8, entry.len() as i32, &mut feature)
+        };
+        if parsed == 0 {
+            warn(format_args!("invalid feature {:?}", entry));
+            return Err(ShapeError::InvalidFeature);
+        }
+        out.push(feature);
+    }
+    Ok(out)
+}
+
+// Shape `run` with `options` plus the features in `features` (see parse_features)
+pub fn shape_with_feature_string(run: &TextRun, features: &str, options: &ShapeOptions) -> Result<ShapingResult, ShapeError> {
+    let mut options = options.clone();
+    options.features.extend(parse_features(features)?);
+    Shaper::new()
+        .ok_or(ShapeError::BufferAllocation)?
+        .try_shape(run, &options)
+}
+
+// Shape `byte_range` of `text` as one run in the font named `font_name` (PostScript name) with the
+// given direction and ISO 15924 script, for callers that itemize text themselves (e.g. with ICU).
+// The rest of `text` is passed to HarfBuzz as context; clusters are relative to the range.
+pub fn shape_range(
+    text: &str,
+    byte_range: Range<usize>,
+    font_name: &str,
+    size: f64,
+    direction: TextDirection,
+    script: [u8; 4],
+) -> Result<ShapingResult, ShapeError> {
+    let run_text = text.get(byte_range.clone()).ok_or(ShapeError::InvalidRange)?;
+    // CTFontCreateWithName substitutes a default font for unknown names, so check what came back
+    let font = match core_text::font::new_from_name(font_name, size) {
+        Ok(font) if font.postscript_name().eq_ignore_ascii_case(font_name) => font,
+        _ => return Err(ShapeError::FontNotFound),
+    };
+    let run = TextRun {
+        text: run_text.to_string(),
+        font_name: font.postscript_name(),
+        start_utf16: utf8_to_utf16_index(text, byte_range.start),
+        length_utf16: utf16_len(run_text),
+        font_size: font.pt_size(),
+        font: FontRef::from(font),
+        decorations: TextDecorations::default(),
+        style_id: None,
+        baseline_offset: 0.0,
+        status: RunStatus::default(),
+        coretext_glyph_count: None,
+    };
+    let options = ShapeOptions {
+        direction: Some(direction),
+        script_override: Some(script),
+        ..Default::default()
+    };
+    Shaper::new()
+        .ok_or(ShapeError::BufferAllocation)?
+        .try_shape_in_context(&run, text, &options)
+}
+
+// Width in points of the pair `a` `b` shaped with HarfBuzz in `font`, kerning included; compare
+// with the characters' widths on their own to get the pair's kerning
+pub fn pair_width(font: &FontRef, a: char, b: char) -> Result<f64, ShapeError> {
+    shaped_width(font, &[a, b].iter().collect::<String>())
+}
+
+// Total advance in points of `text` shaped as a single run in `font`, without fallback
+fn shaped_width(font: &FontRef, text: &str) -> Result<f64, ShapeError> {
+    let run = TextRun {
+        text: text.to_string(),
+        font_name: font.postscript_name().unwrap_or_default(),
+        start_utf16: 0,
+        length_utf16: utf16_len(text),
+        font_size: font.size(),
+        font: font.clone(),
+        decorations: TextDecorations::default(),
+        style_id: None,
+        baseline_offset: 0.0,
+        status: RunStatus::default(),
+        coretext_glyph_count: None,
+    };
+    let result = Shaper::new()
+        .ok_or(ShapeError::BufferAllocation)?
+        .try_shape(&run, &ShapeOptions::default())?;
+    Ok(result.cumulative_x().last().copied().unwrap_or(0.0))
+}
+
+// Which shaper produces a ShapingResult, see shape_run_with_backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShapingBackend {
+    // CoreText's own glyphs and positions for the run laid out on its own (CTRunGetGlyphs etc.)
+    CoreText,
+    #[default]
+    HarfBuzz,
+}
+
+// Shape `run` with the chosen backend, for cross-validating the two shapers or matching what
+// CoreText draws. Both report clusters as UTF-8 byte offsets into the run's text and positions in
+// position_scale units. `options` only apply to HarfBuzz; CoreText shapes with the font's defaults,
+// reports positions in the font's design units and, if its font lacks glyphs, may include
+// glyphs from the fallback font CoreText picked.
+pub fn shape_run_with_backend(run: &TextRun, options: &ShapeOptions, backend: ShapingBackend) -> Result<ShapingResult, ShapeError> {
+    match backend {
+        ShapingBackend::HarfBuzz => Shaper::new()
+            .ok_or(ShapeError::BufferAllocation)?
+            .try_shape(run, options),
+        ShapingBackend::CoreText => shape_with_coretext(run),
+    }
+}
+
+fn shape_with_coretext(run: &TextRun) -> Result<ShapingResult, ShapeError> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTRunGetAdvances(run: *const c_void, range: CFRange, buffer: *mut CGSize);
+    }
+    
+    if run.font.is_null() {
+        return Err(ShapeError::NullFont);
+    }
+    let font_size = run.font.size();
+    let units_per_em = run.font.units_per_em() as i32;
+    let to_units = |points: f64| {
+        if font_size > 0.0 {
+            (points * units_per_em as f64 / font_size).round() as i32
+        } else {
+            0
+        }
+    };
+    
+    let mut glyph_ids = Vec::new();
+    let mut cluster_indices = Vec::new();
+    let mut x_advances = Vec::new();
+    let mut y_advances = Vec::new();
+    let mut x_offsets = Vec::new();
+    let mut y_offsets = Vec::new();
+    let mut pen_x = 0.0;
+    for_each_coretext_run(run, |ct_run| {
+        let glyphs = ct_run.glyphs();
+        let positions = ct_run.positions();
+        let indices = ct_run.string_indices();
+        let mut advances = vec![CGSize::new(0.0, 0.0); glyphs.len()];
+        unsafe {
+            CTRunGetAdvances(ct_run.as_concrete_TypeRef() as *const c_void, CFRange::init(0, 0), advances.as_mut_ptr());
+        }
+        for i in 0..glyphs.len().min(positions.len()).min(indices.len()) {
+            glyph_ids.push(glyphs[i] as u32);
+            // String indices are UTF-16 offsets into the run's text; HarfBuzz clusters are UTF-8
+            cluster_indices.push(utf16_to_utf8_index(&run.text, indices[i].max(0) as usize) as u32);
+            x_advances.push(to_units(advances[i].width));
+            y_advances.push(to_units(advances[i].height));
+            // Positions are pen positions plus offsets, relative to the line origin
+            x_offsets.push(to_units(positions[i].x - pen_x));
+            y_offsets.push(to_units(positions[i].y));
+            pen_x += advances[i].width;
+        }
+    });
+    if glyph_ids.is_empty() {
+        return Err(ShapeError::NoGlyphs);
+    }
+    
+    let script = detect_script(&run.text).unwrap_or(harfbuzz_sys::HB_SCRIPT_LATIN);
+    Ok(ShapingResult {
+        run_text: run.text.clone(),
+        font_name: run.font_name.clone(),
+        glyph_count: glyph_ids.len(),
+        glyph_ids,
+        cluster_indices,
+        x_advances,
+        y_advances,
+        x_offsets,
+        y_offsets,
+        font_size,
+        position_scale: units_per_em,
+        script: unsafe { harfbuzz_sys::hb_script_to_iso15924_tag(script) }.to_be_bytes(),
+    })
+}
+
+// Consume `run`, shape it and release its font before returning, on success and error alike.
+// For callers that shape runs one at a time to cap how many fonts stay retained.
+pub fn shape_and_release(run: TextRun) -> Result<ShapingResult, ShapeError> {
+    let mut shaper = Shaper::new().ok_or(ShapeError::BufferAllocation)?;
+    shaper.try_shape(&run, &ShapeOptions::default())
+    // `run`, and with it the only retain it holds on its font, is dropped here
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        unsafe { harfbuzz_sys::hb_buffer_destroy(self.buffer) };
+    }
+}
+
+// Byte offset of `run` within `text` (the text it was itemized from), checked against the run's text
+fn utf8_offset_of_run(text: &str, run: &TextRun) -> Option<usize> {
+    let offset = utf8_offset(text, run.start_utf16)?;
+    if text[offset..].starts_with(run.text.as_str()) {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+// Shape `run` using the (empty) HarfBuzz `buffer`, leaving the glyphs in it
+// `context`, when given, is the full text the run was itemized from
+fn shape_run_into_buffer(
+    buffer: *mut harfbuzz_sys::hb_buffer_t,
+    run: &TextRun,
+    context: Option<&str>,
+    options: &ShapeOptions,
+) -> Result<ShapingResult, ShapeError> {
+    use harfbuzz_sys;
+    use std::ffi::CString;
+    
+    unsafe {
+        // Step 1: Validate font pointer before use
+        if run.font.is_null() {
+            return Err(ShapeError::NullFont);
+        }
+        
+        // Step 2: Borrow the run's CTFont; the run keeps it retained while we shape
+        let ct_font_ptr = run.font.as_ptr();
+        
+        // Step 3: Create harfbuzz font directly from CTFont pointer using CoreText integration
+        // hb_coretext_font_create creates a harfbuzz font from a CTFontRef
+        let font = harfbuzz_sys::coretext::hb_coretext_font_create(ct_font_ptr as *const _);
+        
+        if font.is_null() {
+            return Err(ShapeError::FontCreation);
+        }
+        
+        // Step 8: Add text to buffer
+        let text_cstring = match CString::new(run.text.as_str()) {
+            Ok(s) => s,
+            Err(_) => {
+                harfbuzz_sys::hb_font_destroy(font);
+                return Err(ShapeError::InteriorNul);
+            }
+        };
+        
+        // With context, hand HarfBuzz the whole paragraph and mark only the run as the item,
+        // so the characters on either side take part in joining decisions
+        let (context_bytes, item_offset, edges) = match context {
+            Some(context) => match utf8_offset_of_run(context, run) {
+                Some(offset) => (context.as_bytes(), offset, TextEdges::in_context(context, offset, run.text.len())),
+                None => {
+                    warn(format_args!("run {:?} not found in shaping context; shaping without it", run.text));
+                    (text_cstring.as_bytes(), 0, TextEdges::default())
+                }
+            },
+            None => (text_cstring.as_bytes(), 0, TextEdges::default()),
+        };
+        // hb_buffer_clear_contents keeps the previous run's flags, so always overwrite them
+        harfbuzz_sys::hb_buffer_set_flags(buffer, options.text_edges.unwrap_or(edges).hb_flags());
+        harfbuzz_sys::hb_buffer_add_utf8(
+            buffer,
+            context_bytes.as_ptr() as *const i8,
+            context_bytes.len() as i32,
+            item_offset as u32,
+            run.text.len() as i32,
+        );
+        
+        // A caller-supplied script also decides the direction (unless forced LTR)
+        let script_override = options
+            .script_override
+            .map(|tag| harfbuzz_sys::hb_script_from_string(tag.as_ptr() as *const i8, 4));
+        
+        // Set buffer direction and script
+        let direction = if options.force_ltr {
+            harfbuzz_sys::HB_DIRECTION_LTR
+        } else if let Some(direction) = options.direction {
+            match direction {
+                TextDirection::LeftToRight => harfbuzz_sys::HB_DIRECTION_LTR,
+                TextDirection::RightToLeft => harfbuzz_sys::HB_DIRECTION_RTL,
+            }
+        } else if let Some(script) = script_override {
+            match harfbuzz_sys::hb_script_get_horizontal_direction(script) {
+                harfbuzz_sys::HB_DIRECTION_INVALID => harfbuzz_sys::HB_DIRECTION_LTR,
+                direction => direction,
+            }
+        } else {
+            detect_direction(&run.text)
+        };
+        harfbuzz_sys::hb_buffer_set_direction(buffer, direction);
+        
+        // Detect script from text content - emoji fonts may need special handling
+        let script = if let Some(script) = script_override {
+            script
+        } else if run.font_name.contains("Emoji") || run.font_name.contains("emoji") {
+            // Use COMMON script for emoji
+            harfbuzz_sys::HB_SCRIPT_COMMON
+        } else {
+            // Use the run's own script so complex-script shapers (Arabic joining etc.) apply,
+            // defaulting to LATIN for script-neutral text. Runs used to always be shaped as
+            // LATIN, so only non-Latin runs see a change; script_override pins the script.
+            detect_script(&run.text).unwrap_or(harfbuzz_sys::HB_SCRIPT_LATIN)
+        };
+        harfbuzz_sys::hb_buffer_set_script(buffer, script);
+        let language = match options.language.as_deref().map(CString::new) {
+            Some(Ok(language)) => Some(language),
+            Some(Err(_)) => {
+                warn("language tag contains a NUL, shaping as English");
+                None
+            }
+            None => None,
+        };
+        let language_ptr = match &language {
+            Some(language) => language.as_ptr(),
+            None => b"en\0".as_ptr() as *const i8,
+        };
+        harfbuzz_sys::hb_buffer_set_language(buffer, harfbuzz_sys::hb_language_from_string(language_ptr, -1));
+        
+        #[link(name = "CoreText", kind = "framework")]
+        extern "C" {
+            fn CTFontGetSize(font: *const c_void) -> f64;
+        }
+        let font_size = CTFontGetSize(ct_font_ptr);
+        
+        // For pixel rounding, scale the hb_font to 64 units per device pixel
+        let pixel_scale = options.pixel_scale.filter(|&scale| scale > 0.0);
+        let expected_scale = if let Some(scale) = pixel_scale {
+            let units = (font_size * scale * 64.0).round() as i32;
+            harfbuzz_sys::hb_font_set_scale(font, units, units);
+            units
+        } else {
+            harfbuzz_sys::hb_face_get_upem(harfbuzz_sys::hb_font_get_face(font)) as i32
+        };
+        check_hb_font_scale(font, expected_scale);
+        
+        // Step 9: Shape the buffer
+        // Note: Some fonts (especially emoji fonts) may not support HarfBuzz shaping
+        // If shaping fails, we return an error gracefully
+        let mut features = options.figures.features();
+        features.extend_from_slice(&options.features);
+        harfbuzz_sys::hb_shape(font, buffer, features.as_ptr(), features.len() as u32);
+        
+        // Step 10: Get glyph information
+        let mut glyph_count: u32 = 0;
+        let glyph_infos = harfbuzz_sys::hb_buffer_get_glyph_infos(buffer, &mut glyph_count);
+        let glyph_positions = harfbuzz_sys::hb_buffer_get_glyph_positions(buffer, &mut glyph_count);
+        
+        if glyph_infos.is_null() || glyph_positions.is_null() || glyph_count == 0 {
+            harfbuzz_sys::hb_font_destroy(font);
+            return Err(ShapeError::NoGlyphs);
+        }
+        
+        let script = harfbuzz_sys::hb_script_to_iso15924_tag(harfbuzz_sys::hb_buffer_get_script(buffer)).to_be_bytes();
+        
+        // Step 11: Extract glyph data
+        let glyph_count_usize = glyph_count as usize;
+        let mut glyph_ids = Vec::with_capacity(glyph_count_usize);
+        let mut cluster_indices = Vec::with_capacity(glyph_count_usize);
+        let mut x_advances = Vec::with_capacity(glyph_count_usize);
+        let mut y_advances = Vec::with_capacity(glyph_count_usize);
+        let mut x_offsets = Vec::with_capacity(glyph_count_usize);
+        let mut y_offsets = Vec::with_capacity(glyph_count_usize);
+        
+        for i in 0..glyph_count_usize {
+            let info = *glyph_infos.add(i);
+            let pos = *glyph_positions.add(i);
+            
+            glyph_ids.push(info.codepoint);
+            // Clusters index the context; rebase them onto the run's own text
+            cluster_indices.push(info.cluster - item_offset as u32);
+            // HarfBuzz positions are in font units (the hb_font scale, upem by default)
+            x_advances.push(pos.x_advance);
+            y_advances.push(pos.y_advance);
+            x_offsets.push(pos.x_offset);
+            y_offsets.push(pos.y_offset);
+        }
+        
+        // Record the scale so positions can be converted to points later
+        let mut x_scale: i32 = 0;
+        let mut y_scale: i32 = 0;
+        harfbuzz_sys::hb_font_get_scale(font, &mut x_scale, &mut y_scale);
+        
+        if pixel_scale.is_some() {
+            round_to_multiple(&mut x_advances, 64);
+            round_to_multiple(&mut y_advances, 64);
+        }
+        
+        // Optionally rescale into the font's design units:
+        //   design = value * upem / scale
+        // where scale is the hb_font scale for that axis (x for x values, y for y values)
+        let mut position_scale = x_scale;
+        if options.design_units {
+            let upem = harfbuzz_sys::hb_face_get_upem(harfbuzz_sys::hb_font_get_face(font)) as i32;
+            to_design_units(&mut x_advances, upem, x_scale);
+            to_design_units(&mut x_offsets, upem, x_scale);
+            to_design_units(&mut y_advances, upem, y_scale);
+            to_design_units(&mut y_offsets, upem, y_scale);
+            position_scale = upem;
+        }
+        
+        // Clean up
+        harfbuzz_sys::hb_font_destroy(font);
+        
+        let mut result = ShapingResult {
+            run_text: run.text.clone(),
+            font_name: run.font_name.clone(),
+            glyph_count: glyph_count_usize,
+            glyph_ids,
+            cluster_indices,
+            x_advances,
+            y_advances,
+            x_offsets,
+            y_offsets,
+            font_size,
+            position_scale,
+            script,
+        };
+        if !options.advance_overrides.is_empty() {
+            apply_advance_overrides(&mut result, &options.advance_overrides);
+        }
+        Ok(result)
+    }
+}
+
+// See ShapeOptions::advance_overrides. Overrides are converted to the result's position units.
+fn apply_advance_overrides(result: &mut ShapingResult, overrides: &[(char, f64)]) {
+    let scale = result.points_per_unit();
+    if scale <= 0.0 {
+        return;
+    }
+    let mut previous_cluster = None;
+    for i in 0..result.glyph_count {
+        let cluster = result.cluster_indices[i];
+        let first_in_cluster = previous_cluster != Some(cluster);
+        previous_cluster = Some(cluster);
+        
+        let c = match result.run_text.get(cluster as usize..).and_then(|rest| rest.chars().next()) {
+            Some(c) => c,
+            None => continue,
+        };
+        if let Some(&(_, advance)) = overrides.iter().find(|(overridden, _)| *overridden == c) {
+            result.x_advances[i] = if first_in_cluster { (advance / scale).round() as i32 } else { 0 };
+        }
+    }
+}
+
+// The source text of glyph `glyph_idx` of `result`, shaped from `run`: all characters of its cluster,
+// from its cluster index up to the next larger one. A ligature gives all the characters it
+// replaces ("fi"); each glyph of a decomposed character gives that character. Works for either
+// glyph order; empty for an out-of-range index.
+pub fn source_text_for_glyph(run: &TextRun, result: &ShapingResult, glyph_idx: usize) -> String {
+    let start = match result.cluster_indices.get(glyph_idx) {
+        Some(&cluster) => cluster,
+        None => return String::new(),
+    };
+    let end = result
+        .cluster_indices
+        .iter()
+        .copied()
+        .filter(|&cluster| cluster > start)
+        .min()
+        .map_or(run.text.len(), |cluster| cluster as usize);
+    run.text.get(start as usize..end).unwrap_or("").to_string()
+}
+
+// Round each value to the nearest multiple of `unit`
+fn round_to_multiple(values: &mut [i32], unit: i32) {
+    for value in values.iter_mut() {
+        *value = (*value as f64 / unit as f64).round() as i32 * unit;
+    }
+}
+
+// One shaped glyph placed on a line, see positioned_glyphs_for_line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionedGlyph {
+    pub glyph_id: u32,
+    // Index of the glyph's run in the slice passed to positioned_glyphs_for_line
+    pub run_index: usize,
+    // HarfBuzz cluster (UTF-8 byte offset into the run's text)
+    pub cluster: u32,
+    // Glyph origin in points relative to the line origin (start of its baseline), y up
+    pub x: f64,
+    pub y: f64,
+    pub advance: f64,
+}
+
+// Shape the runs of one line and place every glyph relative to the line origin, accumulating x
+// across runs. `runs` must be in visual order, as lines come from CoreText (e.g. LineRunOrder::runs).
+// A run that fails to shape is skipped with a warning.
+pub fn positioned_glyphs_for_line(runs: &[TextRun]) -> Vec<PositionedGlyph> {
+    positioned_glyphs_at(runs, CGPoint::new(0.0, 0.0), CoordinateOrigin::BottomLeft)
+}
+
+// Where y = 0 is for functions that report container coordinates: line_origins and
+// positioned_glyphs_at. Every other position in the crate (to_vertex_buffer, CoreText glyph
+// positions, positioned_glyphs_for_line) is relative to a run or line origin with y up, as in CoreGraphics.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CoordinateOrigin {
+    // CoreGraphics convention: y = 0 at the container's bottom edge, growing upwards
+    #[default]
+    BottomLeft,
+    // Screen convention: y = 0 at the top of a container `height` points tall, growing downwards
+    TopLeft { height: f64 },
+}
+
+impl CoordinateOrigin {
+    // Convert a bottom-left based y coordinate into this origin
+    pub fn convert_y(self, y: f64) -> f64 {
+        match self {
+            CoordinateOrigin::BottomLeft => y,
+            CoordinateOrigin::TopLeft { height } => height - y,
+        }
+    }
+}
+
+// (ascent, descent) of a line box holding `runs` on one shared baseline: the largest ascent and
+// descent of their fonts, with each run raised by its baseline_offset. (0.0, 0.0) for no runs.
+pub fn line_extents(runs: &[TextRun]) -> (f64, f64) {
+    runs.iter().fold((0.0_f64, 0.0_f64), |(ascent, descent), run| {
+        (
+            ascent.max(run.font.ascent() + run.baseline_offset),
+            descent.max(run.font.descent() - run.baseline_offset),
+        )
+    })
+}
+
+// Baseline origins of the lines of `text` laid out in a `container`-sized rectangle, in `origin` coordinates
+pub fn line_origins(text: &str, font_size: f64, container: CGSize, options: &LayoutOptions, origin: CoordinateOrigin) -> Vec<CGPoint> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFrameGetLineOrigins(frame: *const c_void, range: CFRange, origins: *mut CGPoint);
+    }
+    
+    let path = layout_rect_path(container, options);
+    let frame = create_frame_in_path(text, font_size, &path, options);
+    let mut origins = vec![CGPoint::new(0.0, 0.0); frame.get_lines().len()];
+    if !origins.is_empty() {
+        unsafe {
+            CTFrameGetLineOrigins(frame.as_concrete_TypeRef() as *const c_void, CFRange::init(0, 0), origins.as_mut_ptr());
+        }
+    }
+    origins
+        .into_iter()
+        .map(|point| CGPoint::new(point.x, origin.convert_y(point.y)))
+        .collect()
+}
+
+// Selection highlight rectangles (x, y, width, height) for the UTF-16 `range` of `text` wrapped
+// at `width`, one per line the range touches. Bottom-left coordinates in a container as tall as
+// the text (measure_height) rounded up, plus a point so rounding never drops the last line; each
+// rect spans the line's ascent and descent.
+pub fn selection_rects(text: &str, font_size: f64, width: f64, range: Range<usize>) -> Vec<(f64, f64, f64, f64)> {
+    #[link(name = "CoreText", kind = "framework")]
+    extern "C" {
+        fn CTFrameGetLineOrigins(frame: *const c_void, range: CFRange, origins: *mut CGPoint);
+    }
+    
+    let options = LayoutOptions::default();
+    let height = measure_height_with_options(text, font_size, width, &options).ceil() + 1.0;
+    let path = layout_rect_path(CGSize::new(width, height), &options);
+    let frame = create_frame_in_path(text, font_size, &path, &options);
+    let lines = frame.get_lines();
+    let mut origins = vec![CGPoint::new(0.0, 0.0); lines.len()];
+    if !origins.is_empty() {
+        unsafe {
+            CTFrameGetLineOrigins(frame.as_concrete_TypeRef() as *const c_void, CFRange::init(0, 0), origins.as_mut_ptr());
+        }
+    }
+    
+    let mut rects = Vec::new();
+    for (line, origin) in lines.iter().zip(origins) {
+        let line_range = line.get_string_range();
+        let line_start = line_range.location.max(0) as usize;
+        let line_end = line_start + line_range.length.max(0) as usize;
+        let start = range.start.max(line_start);
+        let end = range.end.min(line_end);
+        if start >= end {
+            continue;
+        }
+        // Offsets can run right to left within RTL lines, so order them before measuring
+        let x0 = line.get_string_offset_for_string_index(start as isize);
+        let x1 = line.get_string_offset_for_string_index(end as isize);
+        let bounds = line.get_typographic_bounds();
+        rects.push((
+            origin.x + x0.min(x1),
+            origin.y - bounds.descent,
+            (x1 - x0).abs(),
+            bounds.ascent + bounds.descent,
+        ));
+    }
+    rects
+}
+
+// Like positioned_glyphs_for_line, for a line whose baseline starts at `line_origin` (bottom-left
+// container coordinates, e.g. from line_origins with BottomLeft), reporting glyphs in `origin` coordinates
+pub fn positioned_glyphs_at(runs: &[TextRun], line_origin: CGPoint, origin: CoordinateOrigin) -> Vec<PositionedGlyph> {
+    let mut out = Vec::new();
+    let mut shaper = match Shaper::new() {
+        Some(shaper) => shaper,
+        None => return out,
+    };
+    
+    let mut pen_x = 0.0;
+    for (run_index, run) in runs.iter().enumerate() {
+        let result = match shaper.shape(run, &ShapeOptions::default()) {
+            Some(result) => result,
+            None => {
+                warn(format_args!("failed to shape run {:?}, skipping it", run.text));
+                continue;
+            }
+        };
+        
+        let scale = result.points_per_unit();
+        let mut pen_y = 0.0;
+        for i in 0..result.glyph_count {
+            out.push(PositionedGlyph {
+                glyph_id: result.glyph_ids[i],
+                run_index,
+                cluster: result.cluster_indices[i],
+                x: line_origin.x + pen_x + result.x_offsets[i] as f64 * scale,
+                y: origin.convert_y(line_origin.y + pen_y + result.y_offsets[i] as f64 * scale),
+                advance: result.x_advances[i] as f64 * scale,
+            });
+            pen_x += result.x_advances[i] as f64 * scale;
+            pen_y += result.y_advances[i] as f64 * scale;
+        }
+    }
+    out
+}
+
+// Warn if the hb_font's scale is not `expected` on both axes. Positions are converted to points
+// assuming that scale, so a mismatch would make every advance silently off by a factor.
+unsafe fn check_hb_font_scale(font: *mut harfbuzz_sys::hb_font_t, expected: i32) -> bool {
+    let mut x_scale: i32 = 0;
+    let mut y_scale: i32 = 0;
+    harfbuzz_sys::hb_font_get_scale(font, &mut x_scale, &mut y_scale);
+    if x_scale != expected || y_scale != expected {
+        warn(format_args!(
+            "HarfBuzz font scale ({}, {}) does not match expected {}",
+            x_scale, y_scale, expected
+        ));
+        return false;
+    }
+    true
+}
+
+// SVG path data (the `d` attribute) for the outlines of a line of runs, shaped with HarfBuzz and laid
+// out from a pen at the origin. Units are points with SVG's y-down axis, so the baseline is y = 0
+// and glyphs extend to negative y. Runs that fail to shape, and glyphs without outlines, are skipped
+// but still advance the pen.
+pub fn line_to_svg_path(runs: &[TextRun]) -> String {
+    use std::fmt::Write;
+    
+    let mut out = String::new();
+    let mut pen_x = 0.0;
+    for run in runs {
+        let result = match shape_run_with_harfbuzz(run) {
+            Some(result) => result,
+            None => continue,
+        };
+        let font = unsafe { CTFont::wrap_under_get_rule(run.font.as_ptr() as *mut _) };
+        let scale = result.points_per_unit();
+        let mut pen_y = 0.0;
+        for i in 0..result.glyph_count {
+            let x = pen_x + result.x_offsets[i] as f64 * scale;
+            let y = pen_y + result.y_offsets[i] as f64 * scale;
+            // Flip y and move the outline to the glyph's position
+            let transform = CGAffineTransform::new(1.0, 0.0, 0.0, -1.0, x, -y);
+            if let Ok(path) = font.create_path_for_glyph(result.glyph_ids[i] as u16, &transform) {
+                path.apply(&|element: core_graphics::path::CGPathElementRef| {
+                    let command = match element.element_type {
+                        CGPathElementType::MoveToPoint => "M",
+                        CGPathElementType::AddLineToPoint => "L",
+                        CGPathElementType::AddQuadCurveToPoint => "Q",
+                        CGPathElementType::AddCurveToPoint => "C",
+                        CGPathElementType::CloseSubpath => "Z",
+                    };
+                    if !out.is_empty() {
+                        out.push(' ');
+                    }
+                    out.push_str(command);
+                    for point in element.points() {
+                        let _ = write!(out, " {:.2} {:.2}", point.x, point.y);
+                    }
+                });
+            }
+            pen_x += result.x_advances[i] as f64 * scale;
+            pen_y += result.y_advances[i] as f64 * scale;
+        }
+    }
+    out
+}
+
+// Rescale HarfBuzz positions from `scale` units per em to `upem` units per em, rounding to nearest
+fn to_design_units(values: &mut [i32], upem: i32, scale: i32) {
+    if scale == 0 || scale == upem {
+        return;
+    }
+    for value in values.iter_mut() {
+        *value = (*value as f64 * upem as f64 / scale as f64).round() as i32;
+    }
+}
+
+// FFI function that splits text into runs and shapes them with HarfBuzz
+#[no_mangle]
+pub extern "C" fn split_and_shape_text(text: *const i8, font_size: f64) {
+    use std::ffi::CStr;
+    
+    let text_str = unsafe {
+        CStr::from_ptr(text)
+            .to_str()
+            .unwrap_or("")
+    };
+    
+    split_and_shape_text_impl(text_str, font_size, &LayoutOptions::default());
+}
+
+// Like split_and_shape_text, but builds the base font from the PostScript name `font_name`.
+// A null or unknown name falls back to the system UI font (and logs a warning).
+#[no_mangle]
+pub extern "C" fn split_and_shape_text_with_font(text: *const i8, font_name: *const i8, font_size: f64) {
+    use std::ffi::CStr;
+    
+    let text_str = unsafe {
+        CStr::from_ptr(text)
+            .to_str()
+            .unwrap_or("")
+    };
+    let font_name = if font_name.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(font_name) }.to_str().ok().map(String::from)
+    };
+    
+    let options = LayoutOptions { font_name, ..Default::default() };
+    split_and_shape_text_impl(text_str, font_size, &options);
+}
+
+// Status codes returned by split_and_shape_text_with_status
+// Success; *out_run_count holds the number of runs found (0 for empty text)
+pub const CT_RUNS_OK: i32 = 0;
+// `text` was a null pointer; *out_run_count is left untouched
+pub const CT_RUNS_ERR_NULL_TEXT: i32 = -1;
+// `text` was not valid UTF-8; *out_run_count is left untouched
+pub const CT_RUNS_ERR_INVALID_UTF8: i32 = -2;
+// `text` was non-empty but CoreText produced no runs; *out_run_count is set to 0
+pub const CT_RUNS_ERR_NO_RUNS: i32 = -3;
+// A required output pointer was null
+pub const CT_RUNS_ERR_NULL_OUTPUT: i32 = -4;
+// An output buffer could not be allocated; nothing is left allocated and outputs are untouched
+pub const CT_RUNS_ERR_ALLOCATION: i32 = -5;
+
+// Allocator callbacks for the buffers FFI functions hand out (see ct_runs_set_allocator).
+// `alloc` returns `size` bytes aligned to `align`, or null on failure; `free` releases a block
+// with the size and alignment it was allocated with.
+pub type CtRunsAllocFn = extern "C" fn(size: usize, align: usize) -> *mut c_void;
+pub type CtRunsFreeFn = extern "C" fn(ptr: *mut c_void, size: usize, align: usize);
+
+static FFI_ALLOCATOR: std::sync::Mutex<Option<(CtRunsAllocFn, CtRunsFreeFn)>> = std::sync::Mutex::new(None);
+
+// Allocate the buffers returned by ct_shape_runs (the run array, font names and glyph arrays)
+// with `alloc` and release them in ct_shape_runs_free with `free`. Passing null for either resets
+// to the Rust global allocator. Each buffer records the `free` it must be released with, so the
+// allocator can be changed at any time, even while buffers are outstanding.
+#[no_mangle]
+pub extern "C" fn ct_runs_set_allocator(alloc: Option<CtRunsAllocFn>, free: Option<CtRunsFreeFn>) {
+    let hooks = match (alloc, free) {
+        (Some(alloc), Some(free)) => Some((alloc, free)),
+        _ => None,
+    };
+    *FFI_ALLOCATOR.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = hooks;
+}
+
+fn ffi_allocator() -> Option<(CtRunsAllocFn, CtRunsFreeFn)> {
+    *FFI_ALLOCATOR.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Stored in front of every FFI buffer: the free hook it was allocated with, None for the Rust allocator
+struct FfiBufferHeader {
+    free: Option<CtRunsFreeFn>,
+}
+
+// Layout of an FFI buffer of `len` values and the offset of the values past its header
+fn ffi_buffer_layout<T>(len: usize) -> Option<(std::alloc::Layout, usize)> {
+    let values = std::alloc::Layout::array::<T>(len).ok()?;
+    let (layout, offset) = std::alloc::Layout::new::<FfiBufferHeader>().extend(values).ok()?;
+    Some((layout.pad_to_align(), offset))
+}
+
+// Copy `values` into a buffer from the FFI allocator; null for an empty slice, Err if allocation failed
+fn ffi_alloc_slice<T: Copy>(values: &[T]) -> Result<*mut T, ()> {
+    if values.is_empty() {
+        return Ok(ptr::null_mut());
+    }
+    let (layout, offset) = ffi_buffer_layout::<T>(values.len()).ok_or(())?;
+    let hooks = ffi_allocator();
+    let block = match hooks {
+        Some((alloc, _)) => alloc(layout.size(), layout.align()) as *mut u8,
+        None => unsafe { std::alloc::alloc(layout) },
+    };
+    if block.is_null() {
+        return Err(());
+    }
+    unsafe {
+        ptr::write(block as *mut FfiBufferHeader, FfiBufferHeader { free: hooks.map(|(_, free)| free) });
+        let buffer = block.add(offset) as *mut T;
+        ptr::copy_nonoverlapping(values.as_ptr(), buffer, values.len());
+        Ok(buffer)
+    }
+}
+
+// Release a buffer of `len` values from ffi_alloc_slice through the allocator it came from; null is ignored
+unsafe fn ffi_free_slice<T>(buffer: *mut T, len: usize) {
+    if buffer.is_null() {
+        return;
+    }
+    let (layout, offset) = match ffi_buffer_layout::<T>(len) {
+        Some(layout) => layout,
+        None => return,
+    };
+    let block = (buffer as *mut u8).sub(offset);
+    match ptr::read(block as *const FfiBufferHeader).free {
+        Some(free) => free(block as *mut c_void, layout.size(), layout.align()),
+        None => std::alloc::dealloc(block, layout),
+    }
+}
+
+// A font name as a C string for handing across FFI. Guaranteed to be a valid NUL-terminated
+// string: any interior NUL (which a PostScript name should never contain) becomes U+FFFD.
+pub fn font_name_c_string(name: &str) -> std::ffi::CString {
+    let sanitized = if name.contains('\0') {
+        warn(format_args!("font name {:?} contains NUL, replacing it", name));
+        name.replace('\0', "\u{FFFD}")
+    } else {
+        name.to_string()
+    };
+    // No NULs remain, so this cannot fail
+    std::ffi::CString::new(sanitized).unwrap_or_default()
+}
+
+// Variant of split_and_shape_text that reports success/failure to C callers.
+// Returns one of the CT_RUNS_* status codes and, when `out_run_count` is non-null,
+// writes the number of runs through it so the caller can size its own buffers.
+#[no_mangle]
+pub extern "C" fn split_and_shape_text_with_status(
+    text: *const i8,
+    font_size: f64,
+    out_run_count: *mut usize,
+) -> i32 {
+    use std::ffi::CStr;
+    
+    if text.is_null() {
+        warn("text pointer is null");
+        return CT_RUNS_ERR_NULL_TEXT;
+    }
+    
+    let text_str = match unsafe { CStr::from_ptr(text) }.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            warn("text is not valid UTF-8");
+            return CT_RUNS_ERR_INVALID_UTF8;
+        }
+    };
+    
+    let run_count = split_and_shape_text_impl(text_str, font_size, &LayoutOptions::default());
+    if !out_run_count.is_null() {
+        unsafe { *out_run_count = run_count };
+    }
+    
+    if run_count == 0 && !text_str.is_empty() {
+        CT_RUNS_ERR_NO_RUNS
+    } else {
+        CT_RUNS_OK
+    }
+}
+
+// One shaped run handed to C/Swift by ct_shape_runs. Owned by the array it came in; release
+// the whole array with ct_shape_runs_free.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CShapeRun {
+    // PostScript name of the run's font, NUL-terminated
+    pub font_name: *mut std::os::raw::c_char,
+    pub utf16_start: usize,
+    pub utf16_length: usize,
+    // HarfBuzz glyphs of the run; null with a zero count when the run could not be shaped
+    pub glyphs: *mut GlyphInfo,
+    pub glyph_count: usize,
+    pub font_size: f64,
+    // Glyph positions are in 1/position_scale em (ShapingResult::position_scale)
+    pub position_scale: i32,
+}
+
+// Itemize and shape `text`, returning the runs as a C array through `out`/`out_len` (null and 0
+// for empty text). Returns one of the CT_RUNS_* status codes; outputs are only written on success
+// or CT_RUNS_ERR_NO_RUNS. Buffers come from the allocator set with ct_runs_set_allocator; free the
+// array with ct_shape_runs_free.
+#[no_mangle]
+pub extern "C" fn ct_shape_runs(
+    text: *const i8,
+    font_size: f64,
+    out: *mut *mut CShapeRun,
+    out_len: *mut usize,
+) -> i32 {
+    use std::ffi::CStr;
+    
+    if out.is_null() || out_len.is_null() {
+        warn("output pointer is null");
+        return CT_RUNS_ERR_NULL_OUTPUT;
+    }
+    if text.is_null() {
+        warn("text pointer is null");
+        return CT_RUNS_ERR_NULL_TEXT;
+    }
+    let text_str = match unsafe { CStr::from_ptr(text) }.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            warn("text is not valid UTF-8");
+            return CT_RUNS_ERR_INVALID_UTF8;
+        }
+    };
+    
+    let runs = collect_runs(text_str, font_size);
+    let mut shaper = Shaper::new();
+    let mut c_runs: Vec<CShapeRun> = Vec::with_capacity(runs.len());
+    for run in &runs {
+        let shaped = shaper.as_mut().and_then(|shaper| shaper.shape_in_context(run, text_str, &ShapeOptions::default()));
+        let (glyphs, position_scale) = match shaped {
+            Some(result) => (result.to_glyph_infos(), result.position_scale),
+            None => (Vec::new(), 0),
+        };
+        let name = font_name_c_string(&run.font_name);
+        let allocated = ffi_alloc_slice(name.as_bytes_with_nul()).and_then(|font_name| match ffi_alloc_slice(&glyphs) {
+            Ok(glyph_buffer) => Ok((font_name, glyph_buffer)),
+            Err(()) => {
+                unsafe { ffi_free_slice(font_name, name.as_bytes_with_nul().len()) };
+                Err(())
+            }
+        });
+        let (font_name, glyph_buffer) = match allocated {
+            Ok(buffers) => buffers,
+            Err(()) => {
+                // Release what the earlier runs already hold
+                for c_run in &c_runs {
+                    unsafe { free_c_shape_run(c_run) };
+                }
+                return CT_RUNS_ERR_ALLOCATION;
+            }
+        };
+        c_runs.push(CShapeRun {
+            font_name: font_name as *mut std::os::raw::c_char,
+            utf16_start: run.start_utf16,
+            utf16_length: run.length_utf16,
+            glyphs: glyph_buffer,
+            glyph_count: glyphs.len(),
+            font_size: run.font_size,
+            position_scale,
+        });
+    }
+    
+    let array = match ffi_alloc_slice(&c_runs) {
+        Ok(array) => array,
+        Err(()) => {
+            for c_run in &c_runs {
+                unsafe { free_c_shape_run(c_run) };
+            }
+            return CT_RUNS_ERR_ALLOCATION;
+        }
+    };
+    let run_count = c_runs.len();
+    unsafe {
+        *out_len = run_count;
+        *out = array;
+    }
+    if run_count == 0 && !text_str.is_empty() {
+        CT_RUNS_ERR_NO_RUNS
+    } else {
+        CT_RUNS_OK
+    }
+}
+
+// Release an array returned by ct_shape_runs, including its font names and glyph arrays.
+// `len` must be the length ct_shape_runs reported; a null array is ignored.
+#[no_mangle]
+pub extern "C" fn ct_shape_runs_free(runs: *mut CShapeRun, len: usize) {
+    if runs.is_null() {
+        return;
+    }
+    unsafe {
+        for run in std::slice::from_raw_parts(runs, len) {
+            free_c_shape_run(run);
+        }
+        ffi_free_slice(runs, len);
+    }
+}
+
+// Release the font name and glyph array owned by one CShapeRun
+unsafe fn free_c_shape_run(run: &CShapeRun) {
+    if !run.font_name.is_null() {
+        let name_len = std::ffi::CStr::from_ptr(run.font_name).to_bytes_with_nul().len();
+        ffi_free_slice(run.font_name as *mut u8, name_len);
+    }
+    ffi_free_slice(run.glyphs, run.glyph_count);
+}
+
+// Shaping options matching `options`: the layout locale becomes the shaping language, so locl
+// forms agree with the fallback fonts CoreText picked for it
+pub fn shape_options_for_layout(options: &LayoutOptions) -> ShapeOptions {
+    ShapeOptions {
+        language: options.locale.clone().filter(|locale| !locale.is_empty()),
+        ..Default::default()
+    }
+}
+
+// Split text into runs, shape each one and print the results; returns the number of runs
+fn split_and_shape_text_impl(text_str: &str, font_size: f64, options: &LayoutOptions) -> usize {
+    println!("=== Splitting and Shaping Text ===");
+    println!("Text: \"{}\"", text_str);
+    println!("Font size: {}", font_size);
+    println!("---");
+    
+    // Step 1: Split text into runs
+    let runs = collect_runs_with_options(text_str, font_size, options);
+    println!("Found {} runs", runs.len());
+    println!("---");
+    
+    // Step 2: Shape each run with HarfBuzz
+    let shape_options = shape_options_for_layout(options);
+    for (idx, run) in runs.iter().enumerate() {
+        println!("Run {}: \"{}\"", idx, run.text);
+        println!("  Font: {}", run.font_name);
+        println!("  ptr: {:p}", run.font.as_ptr());
+        println!("  UTF-16 range: {}..{}", run.start_utf16, run.start_utf16.saturating_add(run.length_utf16));
+        
+        if let Some(shaping_result) = shape_run_with_options(run, &shape_options) {
+            println!("  Shaping Result:");
+            println!("    Glyph count: {}", shaping_result.glyph_count);
+            println!("    Glyph IDs: {:?}", shaping_result.glyph_ids);
+            println!("    Cluster indices: {:?}", shaping_result.cluster_indices);
+            println!("    X advances: {:?}", shaping_result.x_advances);
+            println!("    Y advances: {:?}", shaping_result.y_advances);
+        } else {
+            println!("  Shaping failed");
+        }
+        println!("---");
+    }
+    
+    println!("=== Done ===");
+    runs.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Serializes tests that install FFI allocator hooks, which are process-wide
+    static FFI_ALLOCATOR_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    static HOOK_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static HOOK_FREES: AtomicUsize = AtomicUsize::new(0);
+    // Allocations after this many fail, to exercise cleanup paths
+    static HOOK_ALLOC_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    extern "C" fn counting_alloc(size: usize, align: usize) -> *mut c_void {
+        if HOOK_ALLOCS.load(Ordering::SeqCst) >= HOOK_ALLOC_LIMIT.load(Ordering::SeqCst) {
+            return ptr::null_mut();
+        }
+        HOOK_ALLOCS.fetch_add(1, Ordering::SeqCst);
+        unsafe { std::alloc::alloc(std::alloc::Layout::from_size_align(size, align).unwrap()) as *mut c_void }
+    }
+
+    extern "C" fn counting_free(ptr: *mut c_void, size: usize, align: usize) {
+        HOOK_FREES.fetch_add(1, Ordering::SeqCst);
+        unsafe { std::alloc::dealloc(ptr as *mut u8, std::alloc::Layout::from_size_align(size, align).unwrap()) }
+    }
+
+    // Install the counting hooks with `limit` allocations allowed, run `f`, then restore the
+    // Rust allocator; returns (allocations, frees)
+    fn with_counting_allocator(limit: usize, f: impl FnOnce()) -> (usize, usize) {
+        let _guard = FFI_ALLOCATOR_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        HOOK_ALLOCS.store(0, Ordering::SeqCst);
+        HOOK_FREES.store(0, Ordering::SeqCst);
+        HOOK_ALLOC_LIMIT.store(limit, Ordering::SeqCst);
+        ct_runs_set_allocator(Some(counting_alloc), Some(counting_free));
+        f();
+        ct_runs_set_allocator(None, None);
+        HOOK_ALLOC_LIMIT.store(usize::MAX, Ordering::SeqCst);
+        (HOOK_ALLOCS.load(Ordering::SeqCst), HOOK_FREES.load(Ordering::SeqCst))
+    }
+
+    // A single run covering `text` in the named font
+    fn run_in_font(text: &str, font_name: &str, font_size: f64) -> TextRun {
+        let font = core_text::font::new_from_name(font_name, font_size).expect("Font should exist");
+        TextRun {
+            text: text.to_string(),
+            font_name: font.postscript_name(),
+            start_utf16: 0,
+            length_utf16: text.encode_utf16().count(),
+            font_size: font.pt_size(),
+            font: FontRef::from(font),
+            decorations: TextDecorations::default(),
+            style_id: None,
+            baseline_offset: 0.0,
+            status: RunStatus::default(),
+            coretext_glyph_count: None,
+        }
+    }
+
+    #[test]
+    fn test_split_and_shape_text_basic() {
+        // Test with a simple ASCII string
+        let text = "Hello, World!";
+        let c_string = CString::new(text).expect("CString::new failed");
+        let text_ptr = c_string.as_ptr() as *const i8;
+        
+        // This should not panic
+        split_and_shape_text(text_ptr, 16.0);
+    }
+
+    #[test]
+    fn test_split_and_shape_text_unicode() {
+        // Test with Unicode characters - just test that runs are collected correctly
+        // We test collect_runs directly to avoid potential font pointer lifetime issues
+        let text = "Hello 世界";
+        let runs = collect_runs(text, 20.0);
+        
+        // Should have at least one run
+        assert!(!runs.is_empty(), "Should have at least one run for Unicode text");
+        
+        // Verify that all text is covered by runs
+        let total_utf16: usize = text.encode_utf16().count();
+        let mut covered: usize = 0;
+        
+        for run in &runs {
+            assert!(run.start_utf16 <= total_utf16, "Start UTF-16 index out of bounds");
+            assert!(run.start_utf16 + run.length_utf16 <= total_utf16, "End UTF-16 index out of bounds");
+            covered += run.length_utf16;
+            assert!(!run.font_name.is_empty(), "Font name should not be empty");
+        }
+        
+        // All UTF-16 code units should be covered
+        assert_eq!(covered, total_utf16, "All UTF-16 code units should be covered by runs");
+    }
+
+    #[test]
+    fn test_split_and_shape_text_empty() {
+        // Test with empty string
+        let text = "";
+        let c_string = CString::new(text).expect("CString::new failed");
+        let text_ptr = c_string.as_ptr() as *const i8;
+        
+        // This should not panic
+        split_and_shape_text(text_ptr, 12.0);
+    }
+
+    #[test]
+    fn test_split_and_shape_text_multiline() {
+        // Test with multiline text
+        let text = "Line 1\nLine 2\nLine 3";
+        let c_string = CString::new(text).expect("CString::new failed");
+        let text_ptr = c_string.as_ptr() as *const i8;
+        
+        // This should not panic
+        split_and_shape_text(text_ptr, 14.0);
+    }
+
+    #[test]
+    fn test_split_and_shape_text_emoji() {
+        // Test with multiline text
+        let text = "🤔💇‍♀️";
+        let c_string = CString::new(text).expect("CString::new failed");
+        let text_ptr = c_string.as_ptr() as *const i8;
+        
+        // This should not panic
+        split_and_shape_text(text_ptr, 14.0);
+    }
+
+
+    #[test]
+    fn test_split_and_shape_text_with_status() {
+        // Plain ASCII is fully covered by the system font, so it should come back as one run
+        let c_string = CString::new("Hello, World!").expect("CString::new failed");
+        let mut run_count: usize = usize::MAX;
+        
+        let status = split_and_shape_text_with_status(c_string.as_ptr(), 16.0, &mut run_count);
+        
+        assert_eq!(status, CT_RUNS_OK);
+        assert_eq!(run_count, 1, "ASCII text should produce a single run");
+    }
+
+    #[test]
+    fn test_split_and_shape_text_with_status_errors() {
+        let mut run_count: usize = 42;
+        
+        let status = split_and_shape_text_with_status(ptr::null(), 16.0, &mut run_count);
+        assert_eq!(status, CT_RUNS_ERR_NULL_TEXT);
+        assert_eq!(run_count, 42, "Count should be untouched on error");
+        
+        let empty = CString::new("").expect("CString::new failed");
+        let status = split_and_shape_text_with_status(empty.as_ptr(), 16.0, &mut run_count);
+        assert_eq!(status, CT_RUNS_OK);
+        assert_eq!(run_count, 0, "Empty text should report zero runs");
+    }
+
+    #[test]
+    fn test_ct_shape_runs_alloc_and_free() {
+        let text = CString::new("Hello 世界").expect("CString::new failed");
+        let expected = collect_runs("Hello 世界", 16.0);
+        let mut runs: *mut CShapeRun = ptr::null_mut();
+        let mut len: usize = 0;
+        
+        let (allocs, frees) = with_counting_allocator(usize::MAX, || {
+            let status = ct_shape_runs(text.as_ptr(), 16.0, &mut runs, &mut len);
+            assert_eq!(status, CT_RUNS_OK);
+            assert_eq!(len, expected.len());
+            
+            let c_runs = unsafe { std::slice::from_raw_parts(runs, len) };
+            for (c_run, run) in c_runs.iter().zip(&expected) {
+                let name = unsafe { std::ffi::CStr::from_ptr(c_run.font_name) };
+                assert_eq!(name.to_str(), Ok(run.font_name.as_str()));
+                assert_eq!((c_run.utf16_start, c_run.utf16_length), (run.start_utf16, run.length_utf16));
+                assert!(c_run.glyph_count > 0 && !c_run.glyphs.is_null());
+                let glyphs = unsafe { std::slice::from_raw_parts(c_run.glyphs, c_run.glyph_count) };
+                assert!(glyphs.iter().all(|glyph| glyph.id != 0), "Covered text should not shape to .notdef");
+            }
+            ct_shape_runs_free(runs, len);
+            
+            // Empty text gives no array; freeing it, or a null array, is a no-op
+            let empty = CString::new("").expect("CString::new failed");
+            assert_eq!(ct_shape_runs(empty.as_ptr(), 16.0, &mut runs, &mut len), CT_RUNS_OK);
+            assert!(runs.is_null());
+            assert_eq!(len, 0);
+            ct_shape_runs_free(runs, len);
+            ct_shape_runs_free(ptr::null_mut(), 0);
+            
+            assert_eq!(ct_shape_runs(ptr::null(), 16.0, &mut runs, &mut len), CT_RUNS_ERR_NULL_TEXT);
+            assert_eq!(ct_shape_runs(text.as_ptr(), 16.0, ptr::null_mut(), &mut len), CT_RUNS_ERR_NULL_OUTPUT);
+        });
+        assert!(allocs > 0);
+        assert_eq!(allocs, frees, "ct_shape_runs_free should release every buffer ct_shape_runs allocated");
+        
+        // Fail each allocation in turn: whatever was already allocated, including the glyph
+        // arrays of earlier runs, must be released and the outputs left untouched
+        for limit in 0..allocs {
+            let mut runs: *mut CShapeRun = ptr::null_mut();
+            let mut len: usize = usize::MAX;
+            let (partial_allocs, partial_frees) = with_counting_allocator(limit, || {
+                assert_eq!(ct_shape_runs(text.as_ptr(), 16.0, &mut runs, &mut len), CT_RUNS_ERR_ALLOCATION);
+            });
+            assert!(runs.is_null() && len == usize::MAX, "Outputs should not be written on allocation failure");
+            assert_eq!(partial_allocs, limit);
+            assert_eq!(partial_allocs, partial_frees, "Allocation failure after {} buffers leaked", limit);
+        }
+    }
+
+    #[test]
+    fn test_ct_runs_allocator_hooks() {
+        let text = CString::new("Hello 世界").expect("CString::new failed");
+        let run_count = collect_runs("Hello 世界", 16.0).len();
+        let mut seen_len = 0;
+        let (allocs, frees) = with_counting_allocator(usize::MAX, || {
+            let mut runs: *mut CShapeRun = ptr::null_mut();
+            let mut len: usize = 0;
+            assert_eq!(ct_shape_runs(text.as_ptr(), 16.0, &mut runs, &mut len), CT_RUNS_OK);
+            seen_len = len;
+            // The array plus a font name and a glyph array per run, all from the hooks
+            assert_eq!(HOOK_ALLOCS.load(Ordering::SeqCst), 1 + 2 * len);
+            ct_shape_runs_free(runs, len);
+        });
+        assert_eq!(seen_len, run_count);
+        assert!(allocs > 0);
+        assert_eq!(allocs, frees, "Every hook allocation should be freed through the hook");
+    }
+
+    #[test]
+    fn test_ct_runs_allocator_switch_with_outstanding_buffers() {
+        let text = CString::new("Hello").expect("CString::new failed");
+        let (allocs, frees) = with_counting_allocator(usize::MAX, || {
+            // Allocated through the hooks, freed after switching back to the Rust allocator
+            let mut runs: *mut CShapeRun = ptr::null_mut();
+            let mut len: usize = 0;
+            assert_eq!(ct_shape_runs(text.as_ptr(), 16.0, &mut runs, &mut len), CT_RUNS_OK);
+            ct_runs_set_allocator(None, None);
+            ct_shape_runs_free(runs, len);
+            
+            // Allocated by Rust, freed while the hooks are installed
+            assert_eq!(ct_shape_runs(text.as_ptr(), 16.0, &mut runs, &mut len), CT_RUNS_OK);
+            ct_runs_set_allocator(Some(counting_alloc), Some(counting_free));
+            ct_shape_runs_free(runs, len);
+        });
+        assert!(allocs > 0);
+        assert_eq!(allocs, frees, "Each buffer should go back to the allocator it came from");
+    }
+
+    #[test]
+    fn test_split_and_shape_text_with_font() {
+        let text = CString::new("Hello, World!").expect("CString::new failed");
+        let font_name = CString::new("Helvetica").expect("CString::new failed");
+        
+        // This should not panic
+        split_and_shape_text_with_font(text.as_ptr(), font_name.as_ptr(), 16.0);
+        split_and_shape_text_with_font(text.as_ptr(), ptr::null(), 16.0);
+        
+        let options = LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() };
+        let runs = collect_runs_with_options("Hello, World!", 16.0, &options);
+        assert_eq!(runs[0].font_name, "Helvetica");
+    }
+
+    #[test]
+    fn test_unknown_font_name_falls_back_to_system_font() {
+        let options = LayoutOptions { font_name: Some(String::from("NoSuchFont-Regular")), ..Default::default() };
+        let runs = collect_runs_with_options("Hello", 16.0, &options);
+        let system_runs = collect_runs("Hello", 16.0);
+        assert_eq!(runs[0].font_name, system_runs[0].font_name);
+    }
+
+    #[test]
+    fn test_collect_runs_basic() {
+        // Test the collect_runs function directly
+        let text = "Hello, World!";
+        let runs = collect_runs(text, 16.0);
+        
+        // Should have at least one run
+        assert!(!runs.is_empty(), "Should have at least one run");
+        
+        // Verify run properties
+        for run in &runs {
+            assert!(!run.text.is_empty() || run.length_utf16 == 0, "Run text should not be empty unless length is 0");
+            assert!(!run.font_name.is_empty(), "Font name should not be empty");
+            assert!(!run.font.is_null(), "Font should not be null");
+        }
+    }
+
+    #[test]
+    fn test_collect_runs_unicode() {
+        // Test with Unicode characters
+        let text = "Hello 世界";
+        let runs = collect_runs(text, 20.0);
+        
+        assert!(!runs.is_empty(), "Should have at least one run");
+        
+        // Verify UTF-16 ranges are valid
+        let total_utf16: usize = text.encode_utf16().count();
+        let mut covered: usize = 0;
+        
+        for run in &runs {
+            assert!(run.start_utf16 <= total_utf16, "Start UTF-16 index out of bounds");
+            assert!(run.start_utf16 + run.length_utf16 <= total_utf16, "End UTF-16 index out of bounds");
+            covered += run.length_utf16;
+        }
+        
+        // All UTF-16 code units should be covered
+        assert_eq!(covered, total_utf16, "All UTF-16 code units should be covered by runs");
+    }
+
+    #[test]
+    fn test_collect_runs_ligature_modes() {
+        // CoreText should split and measure "ffi" consistently whether or not ligatures are formed
+        let text = "ffi";
+        let mut results = Vec::new();
+        
+        for mode in [LigatureMode::Essential, LigatureMode::All] {
+            let options = LayoutOptions { ligatures: Some(mode), ..Default::default() };
+            let runs = collect_runs_with_options(text, 16.0, &options);
+            let run_text: String = runs.iter().map(|run| run.text.as_str()).collect();
+            let width = measure_width(text, 16.0, &options);
+            
+            assert_eq!(run_text, text, "Runs should cover the text for {:?}", mode);
+            assert!(width > 0.0, "Width should be positive for {:?}", mode);
+            assert_eq!(width, measure_width(text, 16.0, &options), "Measurement should be stable for {:?}", mode);
+            results.push(width);
+        }
+        
+        // Ligatures can only merge glyphs, so the ligated width should never exceed the unligated one
+        assert!(results[1] <= results[0] + 0.001, "Ligated width {} exceeds unligated width {}", results[1], results[0]);
+    }
+
+    // UTF-16 (location, length) of each line CoreText produced for `text`
+    fn line_ranges(text: &str, options: &LayoutOptions) -> Vec<(isize, isize)> {
+        create_frame(text, 16.0, options)
+            .get_lines()
+            .iter()
+            .map(|line| {
+                let range = line.get_string_range();
+                (range.location, range.length)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nbsp_stays_on_one_line() {
+        let text = "foo\u{00A0}bar";
+        let full_width = measure_width(text, 16.0, &LayoutOptions::default());
+        
+        // Wide enough for the whole string: one line
+        let options = LayoutOptions { max_width: Some(full_width + 1.0), ..Default::default() };
+        assert_eq!(line_ranges(text, &options).len(), 1, "NBSP text should fit on one line");
+        
+        // Narrower than the whole string: a regular space becomes the break point,
+        // while the NBSP must not be used as one
+        let narrow_width = (measure_width("foo", 16.0, &LayoutOptions::default()) + full_width) / 2.0;
+        let options = LayoutOptions { max_width: Some(narrow_width), ..Default::default() };
+        let spaced_lines = line_ranges("foo bar", &options);
+        let nbsp_lines = line_ranges(text, &options);
+        
+        assert_eq!(spaced_lines.len(), 2, "Regular space should wrap at this width");
+        assert_eq!(spaced_lines[0], (0, 4), "Regular space should be the break point");
+        // Without a break opportunity the first line either overflows with the whole string or,
+        // when CoreText falls back to breaking by character, ends inside "bar" - never at the NBSP
+        assert_eq!(nbsp_lines[0].0, 0);
+        assert!(
+            (5..=7).contains(&nbsp_lines[0].1),
+            "NBSP must not be used as a break point: {:?}",
+            nbsp_lines
+        );
+    }
+
+    #[test]
+    fn test_non_breaking_ranges_option() {
+        let text = "foo bar";
+        let full_width = measure_width(text, 16.0, &LayoutOptions::default());
+        let narrow_width = (measure_width("foo", 16.0, &LayoutOptions::default()) + full_width) / 2.0;
+        let options = LayoutOptions {
+            max_width: Some(narrow_width),
+            non_breaking_ranges: vec![Range { start: 0, end: 7 }],
+            ..Default::default()
+        };
+        
+        let lines = line_ranges(text, &options);
+        assert_ne!(lines[0], (0, 4), "Protected space must not be used as a break point");
+        
+        // Run text still reflects the caller's original characters
+        let runs = collect_runs_with_options(text, 16.0, &options);
+        let run_text: String = runs.iter().map(|run| run.text.as_str()).collect();
+        assert_eq!(run_text, text);
+    }
+
+    #[test]
+    fn test_protect_non_breaking_ranges() {
+        assert_eq!(protect_non_breaking_ranges("a b c", &[Range { start: 0, end: 3 }]), "a\u{00A0}b c");
+        assert_eq!(protect_non_breaking_ranges("a b", &[Range { start: 2, end: 100 }]), "a b", "Out-of-range ends are clamped");
+    }
+
+    #[test]
+    fn test_measure_height_wrapped_is_taller() {
+        let text = "Hello world Hello world";
+        let full_width = measure_width(text, 16.0, &LayoutOptions::default());
+        
+        let one_line = measure_height(text, 16.0, full_width + 10.0);
+        let two_lines = measure_height(text, 16.0, full_width * 0.6);
+        
+        assert!(one_line > 0.0, "One line should have a positive height");
+        assert!(two_lines > one_line, "Wrapped text ({}) should be taller than one line ({})", two_lines, one_line);
+    }
+
+    #[test]
+    fn test_measure_height_empty() {
+        assert_eq!(measure_height("", 16.0, 100.0), 0.0);
+        
+        let options = LayoutOptions { empty_text_line_height: true, ..Default::default() };
+        let line_height = measure_height_with_options("", 16.0, 100.0, &options);
+        assert!(line_height > 0.0, "Empty text should measure one line when requested");
+        assert!(
+            (line_height - measure_height("x", 16.0, 100.0)).abs() < 1.0,
+            "Empty line height should match a single line of text"
+        );
+    }
+
+    #[test]
+    fn test_suggest_size_fits_short_string() {
+        let text = "Hello";
+        let constraints = CGSize::new(1000.0, 1000.0);
+        
+        let (size, fit_range) = suggest_size(text, 16.0, constraints);
+        
+        assert!(size.width > 0.0, "Suggested width should be positive");
+        assert!(size.width < constraints.width, "Suggested width should be less than the constraint");
+        assert!(size.height < constraints.height, "Suggested height should be less than the constraint");
+        assert_eq!(fit_range, 0..text.encode_utf16().count(), "The whole string should fit");
+    }
+
+    #[test]
+    fn test_find_split_combining_marks() {
+        // "é" as e + U+0301 COMBINING ACUTE ACCENT, with the mark in its own run
+        let runs = vec![
+            run_in_font("e", "Helvetica", 16.0),
+            TextRun { start_utf16: 1, ..run_in_font("\u{0301}x", "Courier", 16.0) },
+        ];
+        
+        let splits = find_split_combining_marks(&runs);
+        assert_eq!(splits, vec![SplitCombiningMark { run_index: 1, mark_utf16: 1 }]);
+        
+        // A run starting with a base character is not a split
+        let runs = vec![runs[0].clone(), TextRun { text: String::from("x"), length_utf16: 1, ..runs[1].clone() }];
+        assert!(find_split_combining_marks(&runs).is_empty());
+    }
+
+    #[test]
+    fn test_merge_split_combining_marks() {
+        // Both runs use the system font, which covers U+0301, so the mark is moved back
+        let font = create_base_font(16.0);
+        let in_system_font = |run: TextRun| TextRun { font_name: font.postscript_name(), font: FontRef::from(font.clone()), ..run };
+        let runs = vec![
+            in_system_font(run_in_font("e", "Helvetica", 16.0)),
+            in_system_font(TextRun { start_utf16: 1, ..run_in_font("\u{0301}", "Helvetica", 16.0) }),
+        ];
+        
+        let merged = merge_split_combining_marks(runs);
+        assert_eq!(merged.len(), 1, "Mark-only run should be merged away");
+        assert_eq!(merged[0].text, "e\u{0301}");
+        assert_eq!((merged[0].start_utf16, merged[0].length_utf16), (0, 2));
+    }
+
+    #[test]
+    fn test_collect_runs_in_path_line_counts() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        let options = LayoutOptions::default();
+        let small = CGPath::from_rect(CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(60.0, 1000.0)), None);
+        let large = CGPath::from_rect(CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(1000.0, 1000.0)), None);
+        
+        let small_lines = create_frame_in_path(text, 16.0, &small, &options).get_lines().len();
+        let large_lines = create_frame_in_path(text, 16.0, &large, &options).get_lines().len();
+        assert_eq!(large_lines, 1, "Text should fit on one line in the large rectangle");
+        assert!(small_lines > large_lines, "Small rectangle should need more lines ({} vs {})", small_lines, large_lines);
+        
+        // Both layouts still cover the full text
+        for path in [&small, &large] {
+            let runs = collect_runs_in_path(text, 16.0, path, &options);
+            let covered: usize = runs.iter().map(|run| run.length_utf16).sum();
+            assert_eq!(covered, text.encode_utf16().count());
+        }
+    }
+
+    #[test]
+    fn test_to_glyph_infos_matches_arrays() {
+        let runs = collect_runs("Hello", 16.0);
+        let result = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        
+        let infos = result.to_glyph_infos();
+        assert_eq!(infos.len(), result.glyph_count);
+        for (i, info) in infos.iter().enumerate() {
+            assert_eq!(info.id, result.glyph_ids[i]);
+            assert_eq!(info.cluster, result.cluster_indices[i]);
+            assert_eq!(info.x_advance, result.x_advances[i]);
+            assert_eq!(info.y_advance, result.y_advances[i]);
+            assert_eq!(info.x_offset, result.x_offsets[i]);
+            assert_eq!(info.y_offset, result.y_offsets[i]);
+        }
+        assert_eq!(std::mem::size_of::<GlyphInfo>(), 24);
+    }
+
+    #[test]
+    fn test_to_vertex_buffer_latin() {
+        let runs = collect_runs("Hello", 16.0);
+        assert_eq!(runs.len(), 1, "ASCII text should produce a single run");
+        let result = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        
+        let vertices = result.to_vertex_buffer();
+        assert_eq!(vertices.len(), result.glyph_count);
+        assert_eq!(vertices[0].x, 0.0, "First glyph should start at the pen origin");
+        for pair in vertices.windows(2) {
+            assert!(pair[1].x > pair[0].x, "x should increase for LTR Latin: {:?}", vertices);
+        }
+        
+        // Advances are in points, so the whole word is a few font sizes wide at most
+        let total: f32 = vertices.iter().map(|v| v.advance).sum();
+        assert!(total > 0.0 && total < 16.0 * 5.0, "Unexpected total advance {}", total);
+    }
+
+    #[test]
+    fn test_force_ltr_glyph_order() {
+        let text = "abc אבג";
+        let hebrew_run = |runs: Vec<TextRun>| runs.into_iter().find(|run| run.text.contains('א')).expect("Hebrew run");
+        let forced = ShapeOptions { force_ltr: true, ..Default::default() };
+        
+        let first = shape_run_with_options(&hebrew_run(collect_runs(text, 16.0)), &forced).expect("Shaping should succeed");
+        let second = shape_run_with_options(&hebrew_run(collect_runs(text, 16.0)), &forced).expect("Shaping should succeed");
+        assert_eq!(first.glyph_ids, second.glyph_ids, "Forced LTR should be deterministic");
+        assert_eq!(first.cluster_indices, second.cluster_indices);
+        assert!(
+            first.cluster_indices.windows(2).all(|pair| pair[0] <= pair[1]),
+            "Forced LTR should keep glyphs in logical order: {:?}",
+            first.cluster_indices
+        );
+        
+        // Without forcing, the Hebrew run is shaped RTL and its glyphs come out in visual order
+        let detected = shape_run_with_harfbuzz(&hebrew_run(collect_runs(text, 16.0))).expect("Shaping should succeed");
+        assert!(
+            detected.cluster_indices.windows(2).all(|pair| pair[0] >= pair[1]),
+            "Detected RTL should reverse the glyph order: {:?}",
+            detected.cluster_indices
+        );
+    }
+
+    #[test]
+    fn test_default_direction_unchanged_for_latin() {
+        // Direction detection must not change how Latin or script-neutral text shapes
+        let forced = ShapeOptions { force_ltr: true, ..Default::default() };
+        for text in ["Hello, world", "office 123", "42 + 7 = 49"] {
+            let detected = shape_run_with_harfbuzz(&run_in_font(text, "Helvetica", 16.0)).expect("Shaping should succeed");
+            let ltr = shape_run_with_options(&run_in_font(text, "Helvetica", 16.0), &forced).expect("Shaping should succeed");
+            assert_eq!(detected.glyph_ids, ltr.glyph_ids, "{:?}", text);
+            assert_eq!(detected.cluster_indices, ltr.cluster_indices, "{:?}", text);
+            assert_eq!(detected.x_advances, ltr.x_advances, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_default_script_unchanged_for_latin() {
+        // Script detection must leave Latin and script-neutral text shaped as LATIN
+        let latin = ShapeOptions { script_override: Some(*b"Latn"), ..Default::default() };
+        for text in ["Hello, world", "office 123", "42 + 7 = 49"] {
+            let detected = shape_run_with_harfbuzz(&run_in_font(text, "Helvetica", 16.0)).expect("Shaping should succeed");
+            let pinned = shape_run_with_options(&run_in_font(text, "Helvetica", 16.0), &latin).expect("Shaping should succeed");
+            assert_eq!(&detected.script, b"Latn", "{:?}", text);
+            assert_eq!(detected.glyph_ids, pinned.glyph_ids, "{:?}", text);
+            assert_eq!(detected.x_advances, pinned.x_advances, "{:?}", text);
+        }
+        
+        // The same holds when the run is shaped in context
+        let text = "Hello, world";
+        let runs = collect_runs(text, 16.0);
+        let in_context = shape_run_in_context(&runs[0], text, &ShapeOptions::default()).expect("Shaping should succeed");
+        assert_eq!(&in_context.script, b"Latn");
+    }
+
+    #[test]
+    fn test_collect_runs_from_attributed_string() {
+        let helvetica = core_text::font::new_from_name("Helvetica", 16.0).expect("Helvetica should exist");
+        let courier = core_text::font::new_from_name("Courier", 16.0).expect("Courier should exist");
+        let font_key = unsafe { kCTFontAttributeName as *const _ };
+        
+        let mut attributed_string = CFMutableAttributedString::new();
+        attributed_string.replace_str(&CFString::new("HelloWorld"), CFRange::init(0, 0));
+        attributed_string.set_attribute(CFRange::init(0, 5), font_key, &helvetica);
+        attributed_string.set_attribute(CFRange::init(5, 5), font_key, &courier);
+        
+        let runs = collect_runs_from_attributed_string(
+            attributed_string.as_concrete_TypeRef(),
+            CGSize::new(1000.0, 1000.0),
+        );
+        
+        assert_eq!(runs.len(), 2, "Two font ranges should produce two runs");
+        assert_eq!((runs[0].text.as_str(), runs[0].font_name.as_str()), ("Hello", "Helvetica"));
+        assert_eq!((runs[1].text.as_str(), runs[1].font_name.as_str()), ("World", "Courier"));
+    }
+
+    #[test]
+    fn test_run_decorations() {
+        let helvetica = core_text::font::new_from_name("Helvetica", 16.0).expect("Helvetica should exist");
+        let font_key = unsafe { kCTFontAttributeName as *const _ };
+        let underline_key = unsafe { kCTUnderlineStyleAttributeName as *const _ };
+        let strikethrough_key = CFString::from_static_string("NSStrikethrough");
+
+        let mut attributed_string = CFMutableAttributedString::new();
+        attributed_string.replace_str(&CFString::new("HelloWorld"), CFRange::init(0, 0));
+        attributed_string.set_attribute(CFRange::init(0, 10), font_key, &helvetica);
+        attributed_string.set_attribute(CFRange::init(0, 5), underline_key, &CFNumber::from(9i32));
+        attributed_string.set_attribute(CFRange::init(7, 3), strikethrough_key.as_concrete_TypeRef(), &CFNumber::from(1i32));
+
+        let runs = collect_runs_from_attributed_string(
+            attributed_string.as_concrete_TypeRef(),
+            CGSize::new(1000.0, 1000.0),
+        );
+
+        let decorated: Vec<(&str, TextDecorations)> = runs.iter().map(|r| (r.text.as_str(), r.decorations)).collect();
+        let plain = TextDecorations::default();
+        assert_eq!(decorated, vec![
+            ("Hello", TextDecorations { underline: UnderlineStyle::Double, ..plain }),
+            ("Wo", plain),
+            ("rld", TextDecorations { strikethrough: true, ..plain }),
+        ]);
+    }
+
+    #[test]
+    fn test_collect_styled_runs() {
+        let helvetica = core_text::font::new_from_name("Helvetica", 16.0).expect("Helvetica should exist");
+        let font_key = unsafe { kCTFontAttributeName as *const _ };
+        let style_key = CFString::from_static_string("AppStyleId");
+
+        let mut attributed_string = CFMutableAttributedString::new();
+        attributed_string.replace_str(&CFString::new("HelloWorld!"), CFRange::init(0, 0));
+        attributed_string.set_attribute(CFRange::init(0, 11), font_key, &helvetica);
+        attributed_string.set_attribute(CFRange::init(0, 5), style_key.as_concrete_TypeRef(), &CFNumber::from(7i64));
+        attributed_string.set_attribute(CFRange::init(5, 5), style_key.as_concrete_TypeRef(), &CFNumber::from(42i64));
+
+        let runs = collect_styled_runs(attributed_string.as_concrete_TypeRef(), CGSize::new(1000.0, 1000.0), "AppStyleId");
+        let styles: Vec<(&str, Option<u64>)> = runs.iter().map(|r| (r.text.as_str(), r.style_id)).collect();
+        assert_eq!(styles, vec![("Hello", Some(7)), ("World", Some(42)), ("!", None)]);
+
+        // Without the key, runs carry no style id
+        let runs = collect_runs_from_attributed_string(attributed_string.as_concrete_TypeRef(), CGSize::new(1000.0, 1000.0));
+        assert!(runs.iter().all(|r| r.style_id.is_none()));
+    }
+
+    #[test]
+    fn test_coretext_advances_match_harfbuzz() {
+        let runs = collect_runs("Hello", 16.0);
+        assert_eq!(runs.len(), 1, "ASCII text should produce a single run");
+        
+        let coretext = coretext_advances(&runs[0]);
+        let harfbuzz = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        
+        assert_eq!(coretext.len(), harfbuzz.glyph_count, "Both shapers should produce the same glyph count");
+        let scale = harfbuzz.points_per_unit();
+        for (i, &(width, _height)) in coretext.iter().enumerate() {
+            let hb_width = harfbuzz.x_advances[i] as f64 * scale;
+            assert!((width - hb_width).abs() < 0.5, "Glyph {}: CoreText {} vs HarfBuzz {}", i, width, hb_width);
+        }
+    }
+
+    #[test]
+    fn test_coretext_glyphs_match_harfbuzz_count() {
+        let runs = collect_coretext_runs("Hello, World!", 16.0, &LayoutOptions::default());
+        assert_eq!(runs.len(), 1, "ASCII text should produce a single run");
+        
+        let coretext_glyphs = runs[0].coretext_glyphs();
+        let harfbuzz = shape_run_with_harfbuzz(&runs[0].run).expect("Shaping should succeed");
+        
+        assert_eq!(coretext_glyphs.len(), harfbuzz.glyph_count, "Glyph counts should match for ASCII");
+        assert!(coretext_glyphs.iter().all(|&glyph| glyph != 0), "ASCII should not map to .notdef");
+    }
+
+    #[test]
+    fn test_text_run_from_raw_lone_surrogate() {
+        // A range covering only the high surrogate of "😀" cannot be decoded
+        let text_utf16: Vec<u16> = "😀".encode_utf16().collect();
+        let raw_run = || RunRaw {
+            utf16_location: 0,
+            utf16_length: 1,
+            postscript_name: String::from("AppleColorEmoji"),
+            font_ptr: ptr::null(),
+            ct_run: ptr::null(),
+            line_index: 0,
+            font_size: 16.0,
+            decorations: TextDecorations::default(),
+            baseline_offset: 0.0,
+            status: RunStatus::default(),
+            glyph_count: 0,
+        };
+        
+        let lossy = text_run_from_raw(raw_run(), &text_utf16, InvalidUtf16::Lossy).expect("Lossy keeps the run");
+        assert_eq!(lossy.text, "\u{FFFD}");
+        let empty = text_run_from_raw(raw_run(), &text_utf16, InvalidUtf16::Empty).expect("Empty keeps the run");
+        assert_eq!(empty.text, "");
+        assert_eq!(empty.length_utf16, 1, "The UTF-16 range is kept as reported");
+        assert!(text_run_from_raw(raw_run(), &text_utf16, InvalidUtf16::Skip).is_none(), "Skip drops the run");
+        assert_eq!(InvalidUtf16::default(), InvalidUtf16::Lossy);
+    }
+
+    #[test]
+    fn test_design_unit_advances_independent_of_size() {
+        // Use a fixed font: the system UI font switches optical variants with size
+        let options = ShapeOptions { design_units: true, ..Default::default() };
+        let shape_at = |font_size: f64| {
+            shape_run_with_options(&run_in_font("Hello", "Helvetica", font_size), &options).expect("Shaping should succeed")
+        };
+        
+        let small = shape_at(12.0);
+        let large = shape_at(48.0);
+        
+        assert_eq!(small.position_scale, large.position_scale, "Same font should report the same design units");
+        assert_eq!(small.glyph_ids, large.glyph_ids);
+        for (a, b) in small.x_advances.iter().zip(&large.x_advances) {
+            assert!((a - b).abs() <= 1, "Design-unit advances should not depend on size: {} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_to_design_units() {
+        let mut values = vec![1024, -512, 0];
+        to_design_units(&mut values, 2048, 1024);
+        assert_eq!(values, vec![2048, -1024, 0]);
+    }
+
+    #[test]
+    fn test_collect_run_order_bidi() {
+        // The digits inside the Hebrew span get their own (LTR) embedding level,
+        // so the Hebrew runs on either side swap places visually
+        let text = "abc אבג 123 דהו def";
+        let lines = collect_run_order(text, 16.0, &LayoutOptions::default());
+        assert_eq!(lines.len(), 1);
+        
+        let line = &lines[0];
+        let identity: Vec<usize> = (0..line.runs.len()).collect();
+        assert_ne!(line.logical_to_visual, identity, "Visual order should differ from logical order");
+        
+        // Following the permutation yields runs in increasing logical order
+        let logical_starts: Vec<usize> = line.logical_to_visual.iter().map(|&v| line.runs[v].start_utf16).collect();
+        assert!(logical_starts.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", logical_starts);
+        
+        // And the inverse permutation maps back
+        for (visual, &logical) in line.visual_to_logical().iter().enumerate() {
+            assert_eq!(line.logical_to_visual[logical], visual);
+        }
+    }
+
+    #[test]
+    fn test_collect_run_order_ltr_is_identity() {
+        let lines = collect_run_order("Hello 世界", 16.0, &LayoutOptions::default());
+        let identity: Vec<usize> = (0..lines[0].runs.len()).collect();
+        assert_eq!(lines[0].logical_to_visual, identity);
+    }
+
+    #[test]
+    fn test_run_font_size_mixed_scripts() {
+        let runs = collect_runs("Hello 世界 🌍", 20.0);
+        assert!(runs.len() >= 2, "Mixed scripts should need fallback runs");
+        
+        for run in &runs {
+            assert!(
+                (run.font_size - 20.0).abs() < 0.01,
+                "Run \"{}\" in {} reports size {}",
+                run.text, run.font_name, run.font_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_fonts_used_mixed_document() {
+        let text = "Hello 😀 world\n世界 and 😀 again\nHello";
+        let fonts = fonts_used(text, 16.0);
+        
+        assert!(fonts.len() >= 3, "Latin, emoji and CJK should need three fonts: {:?}", fonts);
+        for (i, (name, font)) in fonts.iter().enumerate() {
+            assert!(!font.is_null(), "Font should not be null");
+            assert!(fonts[..i].iter().all(|(other, _)| other != name), "{} listed twice", name);
+        }
+        
+        // The first run is Latin, so the base font comes first
+        let runs = collect_runs("Hello", 16.0);
+        assert_eq!(fonts[0].0, runs[0].font_name);
+    }
+
+    #[test]
+    fn test_column_tab_stops() {
+        let rows = ["a\tb", "wide first column\tc"];
+        let gap = 8.0;
+        
+        let stops = column_tab_stops(&rows, 16.0, gap);
+        assert_eq!(stops.len(), 1, "Two columns need one stop");
+        
+        let widest = measure_width("wide first column", 16.0, &LayoutOptions::default());
+        assert!((stops[0] - (widest + gap)).abs() < 0.001, "Stop should follow the widest first cell");
+        
+        // Laying out with those stops puts the second column at the stop on every row
+        let options = LayoutOptions { tab_stops: stops.clone(), ..Default::default() };
+        for row in rows {
+            let second_column = row.encode_utf16().position(|unit| unit == '\t' as u16).unwrap() + 1;
+            let lines = create_frame(row, 16.0, &options).get_lines();
+            let x = lines[0].get_string_offset_for_string_index(second_column as isize);
+            assert!((x - stops[0]).abs() < 0.5, "Row {:?}: column starts at {} instead of {}", row, x, stops[0]);
+        }
+    }
+
+    #[test]
+    fn test_verify_coverage() {
+        let text = "Hello 世界";
+        let runs = collect_runs(text, 16.0);
+        assert_eq!(verify_coverage(text, &runs), Ok(()));
+        
+        let run = |start_utf16: usize, length_utf16: usize| TextRun {
+            text: String::new(),
+            start_utf16,
+            length_utf16,
+            ..run_in_font("", "Helvetica", 16.0)
+        };
+        
+        // "Hello" is 5 units; leave 2..3 uncovered
+        let error = verify_coverage("Hello", &[run(0, 2), run(3, 2)]).unwrap_err();
+        assert_eq!(error.gaps, vec![Range { start: 2, end: 3 }]);
+        assert!(error.overlaps.is_empty());
+        
+        // Overlap, trailing gap and a run past the end
+        let error = verify_coverage("Hello", &[run(0, 3), run(2, 1), run(7, 2)]).unwrap_err();
+        assert_eq!(error.overlaps, vec![Range { start: 2, end: 3 }]);
+        assert_eq!(error.gaps, vec![Range { start: 3, end: 5 }]);
+        assert_eq!(error.out_of_bounds, vec![Range { start: 7, end: 9 }]);
+    }
+
+    #[test]
+    fn test_shaper_reuses_buffer() {
+        let text = "Hello 世界 مرحبا 🌍";
+        let expected: Vec<Vec<u32>> = collect_runs(text, 16.0)
+            .iter()
+            .map(|run| shape_run_with_harfbuzz(run).expect("Shaping should succeed").glyph_ids)
+            .collect();
+
+        let mut shaper = Shaper::new().expect("Shaper should be created");
+        for _ in 0..50 {
+            let runs = collect_runs(text, 16.0);
+            assert_eq!(runs.len(), expected.len());
+            for (run, glyph_ids) in runs.iter().zip(&expected) {
+                let result = shaper.shape(run, &ShapeOptions::default()).expect("Shaping should succeed");
+                assert_eq!(&result.glyph_ids, glyph_ids, "Reused buffer should shape {:?} identically", run.text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shape_run_in_context_joins_across_runs() {
+        // Beh Yeh Teh: Yeh is medial and Teh final only when shaped with their neighbors
+        let text = "بيت";
+        let glyph_at = |result: &ShapingResult, cluster: u32| {
+            let i = result.cluster_indices.iter().position(|&c| c == cluster).expect("Cluster should exist");
+            result.glyph_ids[i]
+        };
+        let whole = shape_run_with_harfbuzz(&run_in_font(text, "GeezaPro", 24.0)).expect("Shaping should succeed");
+
+        let head = run_in_font("بي", "GeezaPro", 24.0);
+        let isolated = shape_run_with_harfbuzz(&head).expect("Shaping should succeed");
+        let head = run_in_font("بي", "GeezaPro", 24.0);
+        let joined = shape_run_in_context(&head, text, &ShapeOptions::default()).expect("Shaping should succeed");
+        assert_eq!(glyph_at(&joined, 2), glyph_at(&whole, 2), "Yeh should take its medial form");
+        assert_ne!(glyph_at(&isolated, 2), glyph_at(&whole, 2), "Without context Yeh should be final");
+
+        let mut tail = run_in_font("ت", "GeezaPro", 24.0);
+        tail.start_utf16 = 2;
+        let joined = shape_run_in_context(&tail, text, &ShapeOptions::default()).expect("Shaping should succeed");
+        assert_eq!(joined.cluster_indices, vec![0], "Clusters should be relative to the run");
+        assert_eq!(glyph_at(&joined, 0), glyph_at(&whole, 4), "Teh should take its final form");
+    }
+
+    #[test]
+    fn test_as_glyph_slices() {
+        let runs = collect_runs("Hello world", 16.0);
+        let result = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        let slices = result.as_glyph_slices();
+        assert_eq!(slices.glyph_ids, result.glyph_ids.as_slice());
+        assert_eq!(slices.cluster_indices, result.cluster_indices.as_slice());
+        assert_eq!(slices.x_advances, result.x_advances.as_slice());
+        assert_eq!(slices.y_advances, result.y_advances.as_slice());
+        assert_eq!(slices.x_offsets, result.x_offsets.as_slice());
+        assert_eq!(slices.y_offsets, result.y_offsets.as_slice());
+        assert_eq!(slices.glyph_ids.as_ptr(), result.glyph_ids.as_ptr(), "Slices should not copy");
+    }
+
+    #[test]
+    fn test_shape_pixel_rounding() {
+        let options = ShapeOptions { pixel_scale: Some(1.0), ..Default::default() };
+        let result = shape_run_with_options(&run_in_font("Hello world", "Helvetica", 15.0), &options)
+            .expect("Shaping should succeed");
+        assert_eq!(result.position_scale, 15 * 64, "Positions should be in 1/64 pixels");
+        assert!(result.x_advances.iter().all(|&a| a % 64 == 0), "Each advance should be whole pixels");
+
+        let width: f64 = result.x_advances.iter().map(|&a| a as f64).sum::<f64>() * result.points_per_unit();
+        assert!((width - width.round()).abs() < 1e-9, "Rounded width {} should be an integer", width);
+    }
+
+    #[test]
+    fn test_split_at_boundaries() {
+        let runs = vec![run_in_font("Hello world", "Helvetica", 16.0)];
+        let font = runs[0].font.clone();
+        let split = split_at_boundaries(runs, &[5, 0, 11, 40]);
+
+        assert_eq!(split.len(), 2, "Only the interior boundary should split");
+        assert_eq!(split[0].text, "Hello");
+        assert_eq!((split[0].start_utf16, split[0].length_utf16), (0, 5));
+        assert_eq!(split[1].text, " world");
+        assert_eq!((split[1].start_utf16, split[1].length_utf16), (5, 6));
+        assert!(split.iter().all(|run| run.font == font), "Pieces should keep the run's font");
+        assert!(verify_coverage("Hello world", &split).is_ok());
+
+        for run in &split {
+            assert!(shape_run_with_harfbuzz(run).is_some());
+        }
+    }
+
+    #[test]
+    fn test_control_runs() {
+        // "a" then "bc" wrapped in LEFT-TO-RIGHT EMBEDDING ... POP DIRECTIONAL FORMATTING
+        let text = "a\u{202A}bc\u{202C}d";
+        let options = LayoutOptions { control_runs: true, ..Default::default() };
+        let runs = collect_runs_with_options(text, 16.0, &options);
+
+        let controls: Vec<&str> = runs.iter().filter(|r| r.is_control()).map(|r| r.text.as_str()).collect();
+        assert_eq!(controls, vec!["\u{202A}", "\u{202C}"], "LRE and PDF should be their own control runs");
+        assert!(runs.iter().filter(|r| !r.is_control()).all(|r| !r.text.chars().any(is_control_character)));
+        assert!(verify_coverage(text, &runs).is_ok());
+    }
+
+    #[test]
+    fn test_glyph_name() {
+        let font = core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist");
+        let character = 'A' as u16;
+        let mut glyph = 0u16;
+        assert!(unsafe { font.get_glyphs_for_characters(&character, &mut glyph, 1) });
+
+        let font = FontRef::from(font);
+        assert_eq!(glyph_name(&font, glyph).as_deref(), Some("A"));
+        assert_eq!(glyph_name(&FontRef::default(), glyph), None);
+    }
+
+    #[test]
+    fn test_utf16_utf8_index_round_trip() {
+        // 1-, 2-, 3- and 4-byte characters; the astral ones take two UTF-16 units
+        let text = "a\u{e9}\u{4e16}\u{1F30D}b\u{1D11E}";
+        for (byte_index, c) in text.char_indices() {
+            let utf16_index = utf8_to_utf16_index(text, byte_index);
+            assert_eq!(utf16_to_utf8_index(text, utf16_index), byte_index, "Round trip at {:?}", c);
+        }
+        assert_eq!(utf8_to_utf16_index(text, text.len()), text.encode_utf16().count());
+        assert_eq!(utf16_to_utf8_index(text, text.encode_utf16().count()), text.len());
+
+        // The globe starts at UTF-16 offset 3 and byte 6; its low surrogate maps back to its start
+        assert_eq!(utf16_to_utf8_index(text, 3), 6);
+        assert_eq!(utf16_to_utf8_index(text, 4), 6);
+        assert_eq!(utf8_to_utf16_index(text, 6), 3);
+        assert_eq!(utf8_to_utf16_index(text, 8), 3, "Mid-character bytes round down");
+        assert_eq!(utf16_to_utf8_index(text, 100), text.len());
+        assert_eq!(utf8_offset(text, 4), None);
+        assert_eq!(utf8_offset(text, 5), Some(10));
+    }
+
+    #[test]
+    fn test_positioned_glyphs_for_line() {
+        let first = shape_run_with_harfbuzz(&run_in_font("Hello", "Helvetica", 16.0)).expect("Shaping should succeed");
+        let first_width: f64 = first.x_advances.iter().map(|&a| a as f64 * first.points_per_unit()).sum();
+
+        let mut world = run_in_font(" World", "Courier", 16.0);
+        world.start_utf16 = 5;
+        let glyphs = positioned_glyphs_for_line(&[run_in_font("Hello", "Helvetica", 16.0), world]);
+
+        assert_eq!(glyphs.len(), 11);
+        assert_eq!(glyphs[0].x, 0.0, "The line starts at its origin");
+        let second = glyphs.iter().find(|g| g.run_index == 1).expect("Second run should have glyphs");
+        assert!((second.x - first_width).abs() < 1e-9, "Second run starts at {} not {}", second.x, first_width);
+        assert!(glyphs.windows(2).all(|pair| pair[0].x < pair[1].x), "x should increase along the line");
+    }
+
+    #[test]
+    fn test_create_cf_string_length() {
+        let text = "Hello 世界 🌍\u{0301}";
+        let cf_string = create_cf_string(text).expect("Valid text should convert");
+        assert_eq!(cf_string.char_len() as usize, text.encode_utf16().count());
+        assert!(create_cf_string("").is_some());
+
+        assert!(cf_string_length_matches(3, 3));
+        assert!(!cf_string_length_matches(2, 3), "A short CFString should be rejected");
+        assert!(!cf_string_length_matches(-1, 0), "A negative length should be rejected");
+    }
+
+    #[test]
+    fn test_shape_script_override() {
+        // Digits are script-neutral, so detection falls back to Latin
+        let detected = shape_run_with_harfbuzz(&run_in_font("123", "GeezaPro", 16.0)).expect("Shaping should succeed");
+        assert_eq!(&detected.script, b"Latn");
+
+        let options = ShapeOptions { script_override: Some(*b"Arab"), ..Default::default() };
+        let forced = shape_run_with_options(&run_in_font("123", "GeezaPro", 16.0), &options).expect("Shaping should succeed");
+        assert_eq!(&forced.script, b"Arab", "The override should be honored");
+        assert_eq!(forced.cluster_indices, vec![2, 1, 0], "Arabic script should shape right-to-left");
+    }
+
+    #[test]
+    fn test_measure_ascii_width() {
+        let font = FontRef::from(core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist"));
+        let text = "The quick brown fox jumps over the lazy dog";
+
+        let fast = measure_ascii_width(text, &font).expect("ASCII text should measure");
+        let options = LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() };
+        let full = measure_width(text, 16.0, &options);
+        assert!((fast - full).abs() < 1.0, "Fast path {} should match the layout width {}", fast, full);
+
+        assert_eq!(measure_ascii_width("", &font), Some(0.0));
+        assert_eq!(measure_ascii_width("Caf\u{e9}", &font), None, "Non-ASCII text should not measure");
+        assert_eq!(measure_ascii_width(text, &FontRef::default()), None);
+    }
+
+    #[test]
+    fn test_font_covers() {
+        let font = FontRef::from(core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist"));
+
+        assert!(font_covers(&font, "Hello, World!"));
+        assert!(font_covers(&font, ""));
+        assert!(!font_covers(&font, "🌍🎉"), "A Latin font should not cover emoji");
+        assert!(!font_covers(&font, "Hi 🌍"));
+        assert!(!font_covers(&FontRef::default(), "Hello"));
+    }
+
+    #[test]
+    fn test_collect_runs_all_whitespace() {
+        let text = "   ";
+        let runs = collect_runs(text, 16.0);
+        assert_eq!(runs.len(), 1, "Spaces should form a single run");
+        assert!(verify_coverage(text, &runs).is_ok(), "The run should cover every UTF-16 unit");
+        assert_eq!(runs[0].text, text);
+        assert_eq!(runs[0].font_name, create_base_font(16.0).postscript_name(), "Spaces should use the base font");
+
+        let result = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        assert_eq!(result.glyph_count, 3);
+        assert!(result.x_advances.iter().all(|&advance| advance > 0), "Space glyphs should advance");
+    }
+
+    #[test]
+    fn test_collect_lines() {
+        let lines = collect_lines("Hello 世界\nWorld", 16.0, 1000.0);
+        assert_eq!(lines.len(), 2, "A newline should start a second line");
+
+        let line_text: Vec<String> = lines
+            .iter()
+            .map(|runs| runs.iter().map(|run| run.text.as_str()).collect())
+            .collect();
+        assert_eq!(line_text, vec!["Hello 世界\n", "World"]);
+
+        // A narrow width wraps the words onto separate lines
+        assert!(collect_lines("Hello World", 16.0, 50.0).len() >= 2);
+    }
+
+    #[test]
+    fn test_collect_ct_lines() {
+        let text = "Hello 世界\nWorld";
+        let lines = collect_ct_lines(text, 16.0, &LayoutOptions::default());
+        assert_eq!(lines.len(), 2);
+        let ct_runs: usize = lines.iter().map(|line| line.glyph_runs().len() as usize).sum();
+        assert_eq!(ct_runs, collect_runs(text, 16.0).len());
+        assert_eq!(lines[1].get_string_range().location, 9);
+        assert!(lines[0].get_typographic_bounds().width > 0.0);
+    }
+
+    #[test]
+    fn test_coretext_shaping_positions() {
+        let runs = collect_coretext_runs("AB", 16.0, &LayoutOptions::default());
+        assert_eq!(runs.len(), 1);
+
+        let shaping = runs[0].coretext_shaping();
+        assert_eq!(shaping.glyph_count, 2);
+        assert_eq!(shaping.string_indices, vec![0, 1]);
+        assert_eq!(shaping.positions.len(), 2);
+        assert!(shaping.positions[0].x < shaping.positions[1].x, "B should be placed after A");
+    }
+
+    #[test]
+    fn test_collect_runs_chunked_offsets() {
+        let text: String = (0..2000).map(|i| format!("Paragraph {} Hello 世界 🌍 مرحبا\n", i)).collect();
+        let runs = collect_runs_chunked(&text, 16.0, &LayoutOptions::default());
+        assert!(verify_coverage(&text, &runs).is_ok(), "Chunked runs should tile the whole text");
+
+        let text_utf16: Vec<u16> = text.encode_utf16().collect();
+        for run in &runs {
+            let slice = &text_utf16[run.start_utf16..run.start_utf16 + run.length_utf16];
+            assert_eq!(run.text, String::from_utf16_lossy(slice), "Run text should be at its global offset");
+        }
+
+        // Small inputs itemize the same as the whole-text path
+        let small = "Hello 世界\nSecond line 🌍\nThird";
+        let ranges = |runs: &[TextRun]| runs.iter().map(|r| (r.start_utf16, r.length_utf16, r.text.clone())).collect::<Vec<_>>();
+        assert_eq!(
+            ranges(&collect_runs_chunked(small, 16.0, &LayoutOptions::default())),
+            ranges(&collect_runs(small, 16.0))
+        );
+    }
+
+    #[test]
+    fn test_font_ref_accessors_and_retain_counts() {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFGetRetainCount(cf: *const c_void) -> isize;
+        }
+
+        let font = core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist");
+        let font_ptr = font.as_concrete_TypeRef() as *const c_void;
+        let base = unsafe { CFGetRetainCount(font_ptr) };
+
+        let font_ref = FontRef::from(font.clone());
+        assert_eq!(font_ref.as_ptr(), font_ptr);
+        assert_eq!(font_ref.postscript_name().as_deref(), Some("Helvetica"));
+        assert_eq!(font_ref.size(), 16.0);
+        assert_eq!(font_ref.units_per_em(), 2048);
+        assert_eq!(unsafe { CFGetRetainCount(font_ptr) }, base + 1);
+
+        let copy = font_ref.clone();
+        assert_eq!(unsafe { CFGetRetainCount(font_ptr) }, base + 2, "Clone should retain");
+        drop(copy);
+        drop(font_ref);
+        assert_eq!(unsafe { CFGetRetainCount(font_ptr) }, base, "Drop should release every retain");
+
+        // Runs keep their font alive, so a run can be shaped repeatedly
+        let runs = collect_runs("Hello", 16.0);
+        assert!(shape_run_with_harfbuzz(&runs[0]).is_some());
+        assert!(shape_run_with_harfbuzz(&runs[0]).is_some());
+
+        let null = FontRef::default();
+        assert!(null.is_null());
+        assert_eq!((null.postscript_name(), null.size(), null.units_per_em()), (None, 0.0, 0));
+    }
+
+    #[test]
+    fn test_hyphenation_points() {
+        let word = "internationalization";
+        let points = hyphenation_points(word, "en_US");
+        assert!(!points.is_empty(), "A long English word should have hyphenation points");
+        assert!(points.iter().all(|&p| p > 0 && p < word.len()), "Points should be inside the word: {:?}", points);
+        assert!(points.windows(2).all(|pair| pair[0] < pair[1]), "Points should be ascending");
+
+        assert!(hyphenation_points("", "en_US").is_empty());
+    }
+
+    #[test]
+    fn test_justify_line() {
+        let text = "Hello brave new world";
+        let natural = measure_width(text, 16.0, &LayoutOptions::default());
+        let target = (natural * 1.3).round();
+
+        let justified = justify_line(text, 16.0, target, &LayoutOptions::default()).expect("Line should justify");
+        assert!((justified.natural_width - natural).abs() < 1e-6);
+        assert_eq!(justified.factor, 1.0);
+
+        let total: f64 = justified.runs.iter().flat_map(|run| run.advances.iter()).sum();
+        assert!((total - target).abs() < 0.5, "Justified advances sum to {} not {}", total, target);
+        assert!((justified.width - target).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_font_name_c_string() {
+        assert_eq!(font_name_c_string("Helvetica").to_str(), Ok("Helvetica"));
+
+        let crafted = font_name_c_string("Bad\0Name");
+        assert_eq!(crafted.to_str(), Ok("Bad\u{FFFD}Name"), "Interior NULs should be replaced");
+        assert_eq!(crafted.as_bytes_with_nul().iter().filter(|&&b| b == 0).count(), 1);
+    }
+
+    #[test]
+    fn test_retry_tofu_runs() {
+        // Cherokee forced into a Latin font renders as tofu
+        let text = "ᏣᎳᎩ ᎦᏬᏂᎯᏍᏗ";
+        let before = missing_glyph_count(run_in_font(text, "Helvetica", 16.0).font.as_ptr(), text).unwrap();
+        assert!(before > 0, "Helvetica should not cover Cherokee");
+
+        let retried = retry_tofu_runs(vec![run_in_font(text, "Helvetica", 16.0)]);
+        let after = missing_glyph_count(retried[0].font.as_ptr(), text).unwrap();
+        assert!(after < before, "Retry should reduce missing glyphs ({} -> {})", before, after);
+        assert_ne!(retried[0].font_name, "Helvetica");
+
+        // Covered runs are left alone
+        let latin = retry_tofu_runs(vec![run_in_font("Hello", "Helvetica", 16.0)]);
+        assert_eq!(latin[0].font_name, "Helvetica");
+    }
+
+    #[test]
+    fn test_shaping_result_pretty() {
+        let result = shape_run_with_harfbuzz(&run_in_font("Hello", "Helvetica", 16.0)).expect("Shaping should succeed");
+        let table = result.pretty();
+        assert!(table.contains("5 glyphs"), "Table should report the glyph count:\n{}", table);
+        assert!(table.contains("Helvetica"), "Table should name the font:\n{}", table);
+        assert_eq!(table.lines().count(), 2 + result.glyph_count, "Header lines plus one row per glyph");
+    }
+
+    #[test]
+    fn test_top_left_origin_flips_y() {
+        let container = CGSize::new(400.0, 300.0);
+        let top_left = CoordinateOrigin::TopLeft { height: container.height };
+        let options = LayoutOptions::default();
+
+        let bottom = line_origins("Hello\nWorld", 16.0, container, &options, CoordinateOrigin::BottomLeft);
+        let top = line_origins("Hello\nWorld", 16.0, container, &options, top_left);
+        assert_eq!(bottom.len(), 2);
+        for (b, t) in bottom.iter().zip(&top) {
+            assert_eq!(t.x, b.x);
+            assert!((t.y - (container.height - b.y)).abs() < 1e-9, "y should be inverted against the height");
+        }
+        assert!(top[0].y < top[1].y, "In top-left coordinates later lines are further down");
+
+        let glyphs_up = positioned_glyphs_at(&[run_in_font("Hello", "Helvetica", 16.0)], bottom[0], CoordinateOrigin::BottomLeft);
+        let glyphs_down = positioned_glyphs_at(&[run_in_font("Hello", "Helvetica", 16.0)], bottom[0], top_left);
+        assert!((glyphs_up[0].y - bottom[0].y).abs() < 1e-9);
+        assert!((glyphs_down[0].y - (container.height - glyphs_up[0].y)).abs() < 1e-9);
+        assert!((glyphs_down[0].y - top[0].y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cascade_list() {
+        let system = FontRef::from(create_base_font(16.0));
+        let cascade = cascade_list(&system, &["en"]);
+        assert!(!cascade.is_empty(), "The system font should have fallbacks");
+        assert!(
+            cascade.iter().any(|name| name.contains("PingFang") || name.contains("Hiragino") || name == "AppleColorEmoji"),
+            "Cascade should include a CJK or emoji font: {:?}",
+            cascade
+        );
+        assert!(cascade_list(&FontRef::default(), &["en"]).is_empty());
+    }
+
+    #[test]
+    fn test_shape_and_release_leaves_no_retains() {
+        #[link(name = "CoreFoundation", kind = "framework")]
+        extern "C" {
+            fn CFGetRetainCount(cf: *const c_void) -> isize;
+        }
+
+        let font = core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist");
+        let font_ptr = font.as_concrete_TypeRef() as *const c_void;
+        let base = unsafe { CFGetRetainCount(font_ptr) };
+
+        let runs: Vec<TextRun> = ["Hello", "a\0b", "World"]
+            .iter()
+            .map(|text| TextRun { font: FontRef::from(font.clone()), ..run_in_font(text, "Helvetica", 16.0) })
+            .collect();
+        assert_eq!(unsafe { CFGetRetainCount(font_ptr) }, base + 3);
+
+        let results: Vec<Result<ShapingResult, ShapeError>> = runs.into_iter().map(shape_and_release).collect();
+        assert_eq!(results[0].as_ref().map(|r| r.glyph_ids.len()), Ok(5));
+        assert_eq!(results[1].as_ref().err(), Some(&ShapeError::InteriorNul));
+        assert!(results[2].is_ok());
+
+        // Both the shaped runs and the failed one released their font
+        assert_eq!(unsafe { CFGetRetainCount(font_ptr) }, base);
+        assert_eq!(shape_and_release(TextRun { font: FontRef::default(), ..run_in_font("x", "Helvetica", 16.0) }).err(), Some(ShapeError::NullFont));
+    }
+
+    #[test]
+    fn test_explain_splits_latin_emoji() {
+        let reasons = explain_splits("Hello 😀", 16.0);
+        assert_eq!(reasons, vec![SplitReason::Start, SplitReason::FontChange]);
+
+        let reasons = explain_splits("Hello 😀 world", 16.0);
+        assert_eq!(reasons.len(), collect_runs("Hello 😀 world", 16.0).len());
+        assert!(reasons[1..].iter().all(|r| *r == SplitReason::FontChange), "{:?}", reasons);
+
+        assert!(explain_splits("", 16.0).is_empty());
+    }
+
+    #[test]
+    fn test_baseline_offset_runs() {
+        let options = LayoutOptions { baseline_offsets: vec![(1..2, 4.0)], ..Default::default() };
+        let runs = collect_runs_with_options("x2 y", 16.0, &options);
+        let offsets: Vec<(&str, f64)> = runs.iter().map(|r| (r.text.as_str(), r.baseline_offset)).collect();
+        assert_eq!(offsets, vec![("x", 0.0), ("2", 4.0), (" y", 0.0)]);
+
+        // Chunked layout applies an offset only in the paragraph holding its range
+        let text = "x2\nx2\n";
+        let options = LayoutOptions { baseline_offsets: vec![(4..5, 4.0)], ..Default::default() };
+        let runs = collect_runs_chunked(text, 16.0, &options);
+        let raised: Vec<(usize, usize)> = runs.iter().filter(|r| r.baseline_offset != 0.0).map(|r| (r.start_utf16, r.length_utf16)).collect();
+        assert_eq!(raised, vec![(4, 1)], "{:?}", runs);
+
+        // Without offsets every run sits on the baseline
+        assert!(collect_runs("x2 y", 16.0).iter().all(|r| r.baseline_offset == 0.0));
+    }
+
+    #[test]
+    fn test_cumulative_x() {
+        let runs = collect_runs("Hello", 16.0);
+        let result = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        let xs = result.cumulative_x();
+        assert_eq!(xs.len(), result.glyph_count + 1);
+
+        let scale = result.font_size / result.position_scale as f64;
+        let total: i64 = result.x_advances.iter().map(|&a| a as i64).sum();
+        assert!((xs[result.glyph_count] - total as f64 * scale).abs() < 1e-9);
+        assert!((xs[0] - result.x_offsets[0] as f64 * scale).abs() < 1e-9);
+        assert!(xs.windows(2).all(|w| w[0] <= w[1]), "Latin pen positions should not go backwards: {:?}", xs);
+    }
+
+    #[test]
+    fn test_collect_truncated_runs_one_line() {
+        let text = "The quick brown fox jumps over the lazy dog, again and again and again";
+        let options = LayoutOptions { max_width: Some(120.0), max_lines: Some(1), ..Default::default() };
+        let (runs, truncated) = collect_truncated_runs(text, 16.0, &options);
+        assert!(truncated, "A long string limited to one line should be truncated");
+        let last = runs.last().expect("The truncated line should have runs");
+        assert!(last.text.ends_with('\u{2026}'), "Last run should be the ellipsis: {:?}", runs);
+        assert_eq!(last.start_utf16 + last.length_utf16, text.encode_utf16().count(), "The ellipsis stands for the rest of the text");
+
+        // Text that fits is returned as is
+        let (runs, truncated) = collect_truncated_runs("Hi", 16.0, &options);
+        assert!(!truncated);
+        assert_eq!(runs.iter().map(|r| r.text.as_str()).collect::<String>(), "Hi");
+    }
+
+    #[test]
+    fn test_coretext_glyph_count() {
+        let options = LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() };
+        let runs = collect_runs_with_options("Hello", 16.0, &options);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].coretext_glyph_count, Some(5));
+        
+        // Split pieces no longer match the CTRun, so their count is unknown
+        let pieces = split_at_boundaries(runs, &[2]);
+        assert_eq!(pieces.len(), 2);
+        assert!(pieces.iter().all(|piece| piece.coretext_glyph_count.is_none()), "{:?}", pieces);
+        
+        // Runs of a truncated line come from its own CTRuns, and the ellipsis counts the token's glyphs
+        let text = "The quick brown fox jumps over the lazy dog, again and again and again";
+        let options = LayoutOptions { max_width: Some(120.0), max_lines: Some(1), ..Default::default() };
+        let (runs, _) = collect_truncated_runs(text, 16.0, &options);
+        let ellipsis = runs.last().expect("The truncated line should have runs");
+        assert_eq!(ellipsis.coretext_glyph_count, Some(1));
+        assert!(runs.iter().all(|run| run.coretext_glyph_count.is_some_and(|count| count > 0)), "{:?}", runs);
+    }
+
+    #[test]
+    fn test_create_ui_font_types() {
+        let system = create_ui_font(UIFontType::System, 13.0).expect("System UI font should exist");
+        let bold = create_ui_font(UIFontType::EmphasizedSystem, 13.0).expect("Emphasized system font should exist");
+        assert_ne!(system.postscript_name(), bold.postscript_name());
+        assert_eq!(system.size(), 13.0);
+
+        // The UI font type picks the base font for layout
+        let options = LayoutOptions { ui_font: UIFontType::EmphasizedSystem, ..Default::default() };
+        let runs = collect_runs_with_options("Hello", 13.0, &options);
+        assert_eq!(runs[0].font_name, bold.postscript_name().unwrap());
+    }
+
+    #[test]
+    fn test_coretext_string_indices_ligature() {
+        let run = run_in_font("fi", "Helvetica", 16.0);
+        let indices = coretext_string_indices(&run);
+        match indices.len() {
+            // Ligated: the single glyph comes from the "f"
+            1 => assert_eq!(indices, vec![0]),
+            _ => assert_eq!(indices, vec![0, 1]),
+        }
+
+        // Indices are relative to the source text, not the run
+        let shifted = TextRun { start_utf16: 3, ..run };
+        assert!(coretext_string_indices(&shifted).iter().all(|&i| (3..5).contains(&i)));
+    }
+
+    #[test]
+    fn test_harfbuzz_advances_in_points() {
+        // Helvetica's "H" is 722/1000 em wide
+        for size in [16.0, 32.0] {
+            let result = shape_run_with_harfbuzz(&run_in_font("H", "Helvetica", size)).expect("Shaping should succeed");
+            let advance = result.x_advances[0] as f64 * result.font_size / result.position_scale as f64;
+            assert!((advance - 0.722 * size).abs() < 0.01, "H at {}pt advanced {}pt", size, advance);
+        }
+
+        let run = run_in_font("H", "Helvetica", 16.0);
+        unsafe {
+            let font = harfbuzz_sys::coretext::hb_coretext_font_create(run.font.as_ptr() as *const _);
+            let upem = harfbuzz_sys::hb_face_get_upem(harfbuzz_sys::hb_font_get_face(font)) as i32;
+            assert!(check_hb_font_scale(font, upem), "A fresh CoreText hb_font should be scaled to upem");
+            assert!(!check_hb_font_scale(font, upem * 2));
+            harfbuzz_sys::hb_font_destroy(font);
+        }
+    }
+
+    #[test]
+    fn test_line_to_svg_path() {
+        let path = line_to_svg_path(&[run_in_font("A", "Helvetica", 16.0)]);
+        assert!(path.starts_with("M "), "{}", path);
+        assert!(path.contains(" L ") || path.contains(" Q ") || path.contains(" C "), "{}", path);
+        assert!(path.contains('Z'), "Outlines should be closed: {}", path);
+
+        // The second glyph is drawn after the first one's advance
+        let runs = [run_in_font("A", "Helvetica", 16.0), run_in_font("A", "Helvetica", 16.0)];
+        assert_eq!(line_to_svg_path(&runs).matches('M').count(), 2 * path.matches('M').count());
+        assert!(line_to_svg_path(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_measure_width_trailing_whitespace() {
+        let options = LayoutOptions::default();
+        let trimmed_options = LayoutOptions { exclude_trailing_whitespace: true, ..Default::default() };
+        let full = measure_width("abc   ", 16.0, &options);
+        let trimmed = measure_width("abc   ", 16.0, &trimmed_options);
+        let space = measure_width(" ", 16.0, &options);
+        assert!((full - trimmed - 3.0 * space).abs() < 0.01, "full {} trimmed {} space {}", full, trimmed, space);
+        assert!((trimmed - measure_width("abc", 16.0, &options)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_diff_runs_after_insert() {
+        let old = collect_runs("Hello 😀 world", 16.0);
+        let new = collect_runs("Hellox 😀 world", 16.0);
+        assert_eq!(old.len(), new.len());
+
+        let diff = diff_runs(&old, &new);
+        assert_eq!(diff.removed, 0..1);
+        assert_eq!(diff.added, 0..1);
+        let unchanged: Vec<(usize, usize)> = (1..old.len()).map(|i| (i, i)).collect();
+        assert_eq!(diff.unchanged, unchanged, "Runs after the edit only moved");
+        // The changed runs come with their UTF-16 ranges and fonts
+        assert_eq!((old[0].text.as_str(), new[0].text.as_str()), ("Hello ", "Hellox "));
+        assert_eq!(diff.removed_runs, vec![(0..6, old[0].font_name.clone())]);
+        assert_eq!(diff.added_runs, vec![(0..7, new[0].font_name.clone())]);
+
+        let same = diff_runs(&old, &old);
+        assert_eq!(same.unchanged.len(), old.len());
+        assert!(same.added.is_empty() && same.removed.is_empty());
+        assert!(same.added_runs.is_empty() && same.removed_runs.is_empty());
+    }
+
+    #[test]
+    fn test_run_status_flags() {
+        // Hebrew with numbers: the Hebrew runs are RTL, the digits are laid out LTR
+        let runs = collect_runs("\u{05E9}\u{05DC}\u{05D5}\u{05DD} 123 \u{05E2}\u{05D5}\u{05DC}\u{05DD}", 16.0);
+        assert!(runs.iter().any(|r| r.status.right_to_left));
+        for run in &runs {
+            let has_hebrew = run.text.chars().any(|c| ('\u{0590}'..='\u{05FF}').contains(&c));
+            let has_digits = run.text.chars().any(|c| c.is_ascii_digit());
+            if has_hebrew {
+                assert!(run.status.right_to_left, "Hebrew run should be RTL: {:?}", run);
+            } else if has_digits {
+                assert!(!run.status.right_to_left, "Digit run should be LTR: {:?}", run);
+            }
+        }
+
+        // Devanagari KI: the vowel sign is drawn before the consonant it follows
+        let runs = collect_runs("\u{0915}\u{093F}", 16.0);
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].status.non_monotonic, "Reordered vowel sign should be flagged: {:?}", runs[0].status);
+        assert!(!runs[0].status.right_to_left);
+        let indices = coretext_string_indices(&runs[0]);
+        assert!(indices.windows(2).any(|w| w[0] > w[1]), "Glyph order should not follow characters: {:?}", indices);
+    }
+
+    #[test]
+    fn test_utf16_len_matches_cfstring() {
+        for text in ["", "Hello", "caf\u{e9}", "\u{1F600}", "a\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}b", "\u{10348}\u{1D11E}"] {
+            assert_eq!(utf16_len(text), CFString::new(text).char_len() as usize, "{:?}", text);
+            assert_eq!(utf16_len(text), text.encode_utf16().count());
+        }
+        assert_eq!(utf16_len("\u{1F600}"), 2);
+    }
+
+    #[test]
+    fn test_tabular_figures_equal_advances() {
+        let runs = collect_runs("0123456789", 16.0);
+        assert_eq!(runs.len(), 1);
+        let options = ShapeOptions {
+            figures: FigureStyle { spacing: Some(FigureSpacing::Tabular), ..Default::default() },
+            ..Default::default()
+        };
+        let result = shape_run_with_options(&runs[0], &options).expect("Shaping should succeed");
+        assert_eq!(result.glyph_count, 10);
+        assert!(result.x_advances.iter().all(|&a| a == result.x_advances[0]), "Tabular digits: {:?}", result.x_advances);
+
+        assert!(FigureStyle::default().features().is_empty());
+        let both = FigureStyle { spacing: Some(FigureSpacing::Proportional), case: Some(FigureCase::Oldstyle) };
+        let tags: Vec<u32> = both.features().iter().map(|f| f.tag).collect();
+        assert_eq!(tags, vec![u32::from_be_bytes(*b"pnum"), u32::from_be_bytes(*b"onum")]);
+    }
+
+    #[test]
+    fn test_font_attribute_key() {
+        let key = font_attribute_key().expect("kCTFontAttributeName should be linked");
+        assert_eq!(key.to_string(), "NSFont");
+        assert_eq!(key.as_concrete_TypeRef() as *const c_void, unsafe { kCTFontAttributeName });
+
+        // The key works for building attributed strings by hand
+        let helvetica = core_text::font::new_from_name("Helvetica", 16.0).expect("Helvetica should exist");
+        let mut attributed_string = CFMutableAttributedString::new();
+        attributed_string.replace_str(&CFString::new("Hello"), CFRange::init(0, 0));
+        attributed_string.set_attribute(CFRange::init(0, 5), key.as_concrete_TypeRef(), &helvetica);
+        let runs = collect_runs_from_attributed_string(attributed_string.as_concrete_TypeRef(), CGSize::new(1000.0, 1000.0));
+        assert_eq!(runs[0].font_name, "Helvetica");
+    }
+
+    #[test]
+    fn test_split_emoji() {
+        let runs = split_emoji(vec![run_in_font("hi\u{1F600}bye", "Helvetica", 16.0)]);
+        let pieces: Vec<(&str, usize, usize)> = runs.iter().map(|r| (r.text.as_str(), r.start_utf16, r.length_utf16)).collect();
+        assert_eq!(pieces, vec![("hi", 0, 2), ("\u{1F600}", 2, 2), ("bye", 4, 3)]);
+        assert!(runs.iter().all(|r| r.font_name == "Helvetica"), "Pieces should keep the run's font");
+
+        // ZWJ sequences and skin tones stay whole
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{1F44D}\u{1F3FD}";
+        let runs = split_emoji(vec![run_in_font(&format!("a{}b", family), "Helvetica", 16.0)]);
+        let texts: Vec<&str> = runs.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["a", family, "b"]);
+    }
+
+    #[test]
+    fn test_path_transform_scales_line_origins() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        let container = CGSize::new(200.0, 100.0);
+        let plain = line_origins(text, 16.0, container, &LayoutOptions::default(), CoordinateOrigin::BottomLeft);
+        let options = LayoutOptions {
+            path_transform: Some(CGAffineTransform::new(2.0, 0.0, 0.0, 2.0, 0.0, 0.0)),
+            ..Default::default()
+        };
+        let scaled = line_origins(text, 16.0, container, &options, CoordinateOrigin::BottomLeft);
+
+        // The layout box is now 400x200: lines wrap later and the first baseline is as far
+        // below the (doubled) top as before
+        assert!(scaled.len() < plain.len(), "plain {:?} scaled {:?}", plain, scaled);
+        assert!((scaled[0].x - plain[0].x).abs() < 0.01);
+        assert!(((2.0 * container.height - scaled[0].y) - (container.height - plain[0].y)).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_font_table() {
+        let font = FontRef::from(core_text::font::new_from_name("Helvetica", 16.0).expect("Font should exist"));
+        let head = font_table(&font, *b"head").expect("Every font has a head table");
+        assert_eq!(head.len(), 54);
+        assert_eq!(&head[12..16], &[0x5F, 0x0F, 0x3C, 0xF5], "head.magicNumber");
+        assert_eq!(u16::from_be_bytes([head[18], head[19]]) as u32, font.units_per_em());
+
+        assert!(font_table(&font, *b"zzzz").is_none());
+        assert!(font_table(&FontRef::default(), *b"head").is_none());
+    }
+
+    #[test]
+    fn test_shaping_is_reordered() {
+        let latin = shape_run_with_harfbuzz(&collect_runs("Hello", 16.0)[0]).expect("Shaping should succeed");
+        assert!(!latin.is_reordered());
+
+        let hebrew = collect_runs("\u{05E9}\u{05DC}\u{05D5}\u{05DD}", 16.0);
+        let rtl = shape_run_with_harfbuzz(&hebrew[0]).expect("Shaping should succeed");
+        assert!(rtl.is_reordered(), "RTL glyphs are in visual order: {:?}", rtl.cluster_indices);
+        let ltr = shape_run_with_options(&hebrew[0], &ShapeOptions { force_ltr: true, ..Default::default() }).unwrap();
+        assert!(!ltr.is_reordered());
+
+        // Devanagari KI draws the vowel sign first, but HarfBuzz gives both glyphs the syllable's cluster
+        let indic = shape_run_with_harfbuzz(&collect_runs("\u{0915}\u{093F}", 16.0)[0]).expect("Shaping should succeed");
+        assert!(!indic.is_reordered(), "{:?}", indic.cluster_indices);
+        assert!(indic.cluster_indices.iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn test_strip_line_terminators() {
+        let options = LayoutOptions { strip_line_terminators: true, ..Default::default() };
+        let runs = collect_runs_with_options("abc\n", 16.0, &options);
+        assert_eq!(runs.len(), 1);
+        assert_eq!((runs[0].text.as_str(), runs[0].start_utf16, runs[0].length_utf16), ("abc", 0, 3));
+
+        let runs = collect_runs_with_options("ab\r\ncd\n\n", 16.0, &options);
+        let texts: String = runs.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, "abcd");
+        assert!(runs.iter().all(|r| utf16_len(&r.text) == r.length_utf16));
+
+        // Without the option the newline stays in the run
+        assert_eq!(collect_runs("abc\n", 16.0)[0].text, "abc\n");
+    }
+
+    #[test]
+    fn test_shape_range_sub_range() {
+        let text = "caf\u{e9} Hello world";
+        let start = text.find("Hello").unwrap();
+        let result = shape_range(text, start..start + 5, "Helvetica", 16.0, TextDirection::LeftToRight, *b"Latn")
+            .expect("Shaping should succeed");
+        assert_eq!(result.run_text, "Hello");
+        assert_eq!(result.font_name, "Helvetica");
+        assert_eq!(result.glyph_count, 5);
+        assert_eq!(result.cluster_indices, vec![0, 1, 2, 3, 4]);
+        assert_eq!(&result.script, b"Latn");
+
+        let rtl = shape_range(text, start..start + 5, "Helvetica", 16.0, TextDirection::RightToLeft, *b"Latn").unwrap();
+        assert_eq!(rtl.cluster_indices, vec![4, 3, 2, 1, 0]);
+
+        assert_eq!(shape_range(text, 4..5, "Helvetica", 16.0, TextDirection::LeftToRight, *b"Latn").err(), Some(ShapeError::InvalidRange));
+        assert_eq!(shape_range(text, 0..3, "NoSuchFont-Regular", 16.0, TextDirection::LeftToRight, *b"Latn").err(), Some(ShapeError::FontNotFound));
+    }
+
+    #[test]
+    fn test_validated_run_range() {
+        assert_eq!(validated_run_range(CFRange::init(1, 3), 5), Some((1, 3)));
+        assert_eq!(validated_run_range(CFRange::init(0, 5), 5), Some((0, 5)));
+        assert_eq!(validated_run_range(CFRange::init(-1, 2), 5), None, "Negative locations are rejected");
+        assert_eq!(validated_run_range(CFRange::init(0, -1), 5), None, "Negative lengths are rejected");
+        assert_eq!(validated_run_range(CFRange::init(4, 2), 5), None, "Ranges past the text are rejected");
+    }
+
+    #[test]
+    fn test_text_edges_dotted_circle() {
+        // A combining acute with no base, in a Latin font that has a dotted circle glyph. Unlike
+        // Indic broken clusters, which always get a dotted circle, this depends only on BOT.
+        let run = run_in_font("\u{0301}", "Arial Unicode MS", 16.0);
+        let fragment = shape_run_with_harfbuzz(&run).expect("Shaping should succeed");
+        let edges = TextEdges { beginning_of_text: true, end_of_text: true };
+        let whole = shape_run_with_options(&run, &ShapeOptions { text_edges: Some(edges), ..Default::default() })
+            .expect("Shaping should succeed");
+        // At the beginning of the text HarfBuzz shows the mark on a dotted circle
+        assert_eq!(fragment.glyph_count, 1, "{:?}", fragment.glyph_ids);
+        assert_eq!(whole.glyph_count, 2, "{:?}", whole.glyph_ids);
+        
+        // Flags from one shape do not leak into the next one on a reused buffer
+        let mut shaper = Shaper::new().expect("Shaper should be created");
+        let with_edges = shaper.shape(&run, &ShapeOptions { text_edges: Some(edges), ..Default::default() })
+            .expect("Shaping should succeed");
+        let without_edges = shaper.shape(&run, &ShapeOptions::default()).expect("Shaping should succeed");
+        assert_eq!((with_edges.glyph_count, without_edges.glyph_count), (2, 1));
+
+        // In context, edges come from the run's position
+        assert_eq!(TextEdges::in_context("ab\ncd", 0, 2), TextEdges { beginning_of_text: true, end_of_text: true });
+        assert_eq!(TextEdges::in_context("abcd", 1, 2), TextEdges::default());
+        assert_eq!(TextEdges::in_context("abcd", 2, 2), TextEdges { beginning_of_text: false, end_of_text: true });
+    }
+
+    #[test]
+    fn test_ink_bounds_overhang() {
+        let run = run_in_font("f", "Zapfino", 24.0);
+        let advance: f64 = coretext_advances(&run).iter().map(|(width, _)| width).sum();
+        let (x, _, width, height) = ink_bounds(&run);
+        assert!(width > advance, "Zapfino's f should ink past its advance: ink {} advance {}", width, advance);
+        assert!(x < 0.0 || x + width > advance);
+        assert!(height > 0.0);
+
+        assert_eq!(ink_bounds(&run_in_font(" ", "Helvetica", 16.0)), (0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn test_ink_bounds_font_fallback() {
+        // CoreText draws the emoji in Apple Color Emoji; measuring its glyph in Helvetica would be wrong
+        let latin = ink_bounds(&run_in_font("A", "Helvetica", 24.0));
+        let emoji = ink_bounds(&run_in_font("\u{1F600}", "AppleColorEmoji", 24.0));
+        let mixed = ink_bounds(&run_in_font("A\u{1F600}", "Helvetica", 24.0));
+        assert!(emoji.2 > 0.0 && emoji.3 > 0.0);
+        assert!(mixed.2 >= latin.2 + emoji.2 * 0.9, "mixed {:?} latin {:?} emoji {:?}", mixed, latin, emoji);
+        assert!(mixed.3 >= emoji.3 * 0.9, "mixed {:?} emoji {:?}", mixed, emoji);
+    }
 
+    #[test]
+    fn test_line_height_multiple_and_paragraph_spacing() {
+        let text = "First line\nSecond line";
+        let single = measure_height_with_options(text, 16.0, 500.0, &LayoutOptions::default());
+        let double_options = LayoutOptions { line_height_multiple: Some(2.0), ..Default::default() };
+        let double = measure_height_with_options(text, 16.0, 500.0, &double_options);
+        let ratio = double / single;
+        assert!((1.7..=2.3).contains(&ratio), "single {} double {}", single, double);
 
-// Function to shape a run with HarfBuzz using harfbuzz_sys directly with CTFont
-pub fn shape_run_with_harfbuzz(run: &TextRun) -> Option<ShapingResult> {
-    use harfbuzz_sys;
-    use std::ffi::CString;
-    
-    unsafe {
-        // Step 1: Validate font pointer before use
-        if run.font_ptr == 0 {
-            return None;
-        }
+        let spaced_options = LayoutOptions { paragraph_spacing: Some(10.0), ..Default::default() };
+        let spaced = measure_height_with_options(text, 16.0, 500.0, &spaced_options);
+        assert!(spaced - single >= 9.5, "single {} spaced {}", single, spaced);
+    }
+
+    #[test]
+    fn test_pinned_line_height() {
+        let text = "First line\nSecond \u{4E16}\u{754C} line\nThird \u{1F600}";
+        let options = LayoutOptions { min_line_height: Some(30.0), max_line_height: Some(30.0), ..Default::default() };
         
-        // Step 2: Get font pointer (already retained in collect_runs_from_frame)
-        #[link(name = "CoreFoundation", kind = "framework")]
-        extern "C" {
-            fn CFRelease(cf: *const c_void);
+        let height = measure_height_with_options(text, 16.0, 500.0, &options);
+        assert!((height - 90.0).abs() < 0.5, "Three lines of 30pt, got {}", height);
+        let origins = line_origins(text, 16.0, CGSize::new(500.0, height), &options, CoordinateOrigin::default());
+        assert_eq!(origins.len(), 3);
+        for pair in origins.windows(2) {
+            let line_height = pair[0].y - pair[1].y;
+            assert!((line_height - 30.0).abs() < 0.01, "Line height {}", line_height);
         }
+    }
+
+    #[test]
+    fn test_fonts_in_family() {
+        let helvetica = fonts_in_family("Helvetica");
+        assert!(helvetica.iter().any(|name| name == "Helvetica"), "{:?}", helvetica);
+        assert!(helvetica.iter().any(|name| name == "Helvetica-Bold"), "{:?}", helvetica);
+        assert!(fonts_in_family("No Such Family").is_empty());
+    }
+
+    #[test]
+    fn test_try_collect_runs_errors() {
+        let options = LayoutOptions::default();
+        let runs = try_collect_runs_with_options("Hello \u{1F600}", 16.0, &options).expect("Itemization should succeed");
+        assert_eq!(runs.len(), collect_runs_with_options("Hello \u{1F600}", 16.0, &options).len());
+        assert!(try_collect_runs_with_options("", 16.0, &options).unwrap().is_empty());
+
+        // A run CoreText's output lost is reported rather than silently missing
+        let mut runs = collect_runs("Hello \u{1F600}", 16.0);
+        let dropped = runs.pop().unwrap();
+        let error = check_itemization("Hello \u{1F600}", runs).unwrap_err();
+        let gap = dropped.start_utf16..dropped.start_utf16 + dropped.length_utf16;
+        assert_eq!(error, ItemizeError::Incomplete(CoverageError { gaps: vec![gap], ..Default::default() }));
+        assert!(error.to_string().starts_with("cannot itemize"));
+    }
+
+    #[test]
+    fn test_selection_rects_across_lines() {
+        let text = "Hello world";
+        // Narrow enough to wrap as "Hello " / "world"
+        let rects = selection_rects(text, 16.0, 50.0, 2..9);
+        assert_eq!(rects.len(), 2);
+        let (first, second) = (rects[0], rects[1]);
+        assert!(first.2 > 0.0 && second.2 > 0.0);
+        assert!(first.3 > 0.0 && second.3 > 0.0);
+        // The first line sits above the second in bottom-left coordinates
+        assert!(first.1 > second.1);
+        // The second line's selection starts at the line's left edge
+        assert!(second.0.abs() < 0.5);
+
+        assert!(selection_rects(text, 16.0, 50.0, 3..3).is_empty());
+        assert_eq!(selection_rects(text, 16.0, 1000.0, 0..utf16_len(text)).len(), 1);
         
-        // Cast u64 back to pointer (this is already a retained reference)
-        let ct_font_ptr = run.font_ptr as usize as *const c_void;
-        
-        // Step 3: Create harfbuzz font directly from CTFont pointer using CoreText integration
-        // hb_coretext_font_create creates a harfbuzz font from a CTFontRef
-        let font = harfbuzz_sys::coretext::hb_coretext_font_create(ct_font_ptr as *const _);
+        // The last line of a multi-line paragraph still fits in the container
+        let paragraph = "one two three four five six seven eight nine ten";
+        let last_word = paragraph.rfind("ten").unwrap()..paragraph.len();
+        let first = selection_rects(paragraph, 16.0, 80.0, 0..3);
+        let last = selection_rects(paragraph, 16.0, 80.0, last_word);
+        assert_eq!((first.len(), last.len()), (1, 1), "first {:?} last {:?}", first, last);
+        assert!(last[0].2 > 0.0);
+        assert!(last[0].1 < first[0].1, "The last line should sit below the first: {:?} {:?}", last, first);
+    }
+
+    #[test]
+    fn test_script_fonts_override_fallback() {
+        let text = "Hello \u{645}\u{631}\u{62D}\u{628}\u{627}";
+        assert_eq!(script_ranges(text), vec![(*b"Latn", 0..6), (*b"Arab", 6..11)]);
         
-        if font.is_null() {
-            // Release the retained font if harfbuzz font creation failed
-            CFRelease(ct_font_ptr);
-            return None;
-        }
+        let options = LayoutOptions {
+            script_fonts: vec![(*b"Arab", String::from("GeezaPro")), (*b"Latn", String::from("Helvetica"))],
+            ..Default::default()
+        };
+        let runs = collect_runs_with_options(text, 16.0, &options);
+        let arabic = runs.iter().find(|run| run.text.contains('\u{645}')).expect("Arabic run");
+        assert_eq!(arabic.font_name, "GeezaPro");
+        let latin = runs.iter().find(|run| run.text.contains("Hello")).expect("Latin run");
+        assert_eq!(latin.font_name, "Helvetica");
         
-        // Step 7: Create harfbuzz buffer
-        let buffer = harfbuzz_sys::hb_buffer_create();
+        // An unknown font leaves CoreText's fallback in place
+        let options = LayoutOptions { script_fonts: vec![(*b"Arab", String::from("NoSuchFont-Regular"))], ..Default::default() };
+        let runs = collect_runs_with_options(text, 16.0, &options);
+        let fallback: Vec<String> = collect_runs(text, 16.0).into_iter().map(|run| run.font_name).collect();
+        assert_eq!(runs.into_iter().map(|run| run.font_name).collect::<Vec<_>>(), fallback);
+    }
+
+    #[test]
+    fn test_caret_skips_grapheme_clusters() {
+        // "a" + family (man ZWJ woman ZWJ girl ZWJ boy, 11 UTF-16 units) + "b"
+        let text = "a\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}b";
+        assert_eq!(utf16_len(text), 13);
+        assert_eq!(next_caret(text, 0), 1);
+        assert_eq!(next_caret(text, 1), 12);
+        assert_eq!(next_caret(text, 5), 12, "A caret inside the cluster moves to its end");
+        assert_eq!(next_caret(text, 12), 13);
+        assert_eq!(next_caret(text, 13), 13);
         
-        if buffer.is_null() {
-            harfbuzz_sys::hb_font_destroy(font);
-            CFRelease(ct_font_ptr);
-            println!("DEBUG: Failed to create harfbuzz buffer");
-            return None;
-        }
+        assert_eq!(prev_caret(text, 13), 12);
+        assert_eq!(prev_caret(text, 12), 1);
+        assert_eq!(prev_caret(text, 5), 1, "A caret inside the cluster moves to its start");
+        assert_eq!(prev_caret(text, 1), 0);
+        assert_eq!(prev_caret(text, 0), 0);
         
-        // Step 8: Add text to buffer
-        let text_cstring = match CString::new(run.text.as_str()) {
-            Ok(s) => s,
-            Err(_) => {
-                harfbuzz_sys::hb_buffer_destroy(buffer);
-                harfbuzz_sys::hb_font_destroy(font);
-                CFRelease(ct_font_ptr);
-                return None;
-            }
+        // Base plus combining mark
+        assert_eq!(next_caret("e\u{301}x", 0), 2);
+    }
+
+    #[test]
+    fn test_locale_fallback_and_shaping() {
+        // The language attribute steers fallback for Han characters shared by Chinese and Japanese
+        let han = "\u{76F4}\u{9AA8}";
+        let font_for = |locale: &str| {
+            let options = LayoutOptions { locale: Some(String::from(locale)), ..Default::default() };
+            collect_runs_with_options(han, 16.0, &options)[0].font_name.clone()
         };
+        assert_ne!(font_for("ja"), font_for("zh-Hans"));
         
-        let text_bytes = text_cstring.as_bytes_with_nul();
-        harfbuzz_sys::hb_buffer_add_utf8(
-            buffer,
-            text_bytes.as_ptr() as *const i8,
-            (text_bytes.len() - 1) as i32, // -1 to exclude null terminator
-            0,
-            -1,
-        );
+        // Turkish dotted capital I is a precomposed character in both cases: itemization and the
+        // system font's glyphs are the same with and without "tr". A difference only shows up with
+        // fonts that have Turkish locl features (e.g. keeping the dot of i inside an fi ligature).
+        let text = "\u{130}stanbul fi";
+        let turkish = LayoutOptions { locale: Some(String::from("tr")), ..Default::default() };
+        let runs = collect_runs_with_options(text, 16.0, &turkish);
+        assert_eq!(runs.len(), collect_runs(text, 16.0).len());
+        let default = shape_run_with_harfbuzz(&runs[0]).expect("Shaping should succeed");
+        let options = ShapeOptions { language: Some(String::from("tr")), ..Default::default() };
+        let localized = shape_run_with_options(&runs[0], &options).expect("Shaping should succeed");
+        assert_eq!(localized.glyph_ids[0], default.glyph_ids[0]);
+        assert_eq!(localized.cluster_indices[0], 0);
         
-        // Set buffer direction and script
-        harfbuzz_sys::hb_buffer_set_direction(buffer, harfbuzz_sys::HB_DIRECTION_LTR);
+        // The layout locale carries over to shaping
+        assert_eq!(shape_options_for_layout(&turkish).language.as_deref(), Some("tr"));
+        let empty = LayoutOptions { locale: Some(String::new()), ..Default::default() };
+        assert_eq!(shape_options_for_layout(&empty).language, None);
+        assert_eq!(shape_options_for_layout(&LayoutOptions::default()).language, None);
+    }
+
+    #[test]
+    fn test_isolate_bidi() {
+        // Hebrew name followed by a number in an LTR template: without an isolate the number is
+        // pulled into the RTL run and displayed before the name
+        let name = "\u{5E9}\u{5DC}\u{5D5}\u{5DD}";
+        let is_identity = |line: &LineRunOrder| line.logical_to_visual.iter().enumerate().all(|(i, &v)| i == v);
+        let leaked = collect_run_order(&format!("Owner: {} 42 items", name), 16.0, &LayoutOptions::default());
+        assert!(!is_identity(&leaked[0]));
         
-        // Detect script from text content - emoji fonts may need special handling
-        let script = if run.font_name.contains("Emoji") || run.font_name.contains("emoji") {
-            // Use COMMON script for emoji
-            harfbuzz_sys::HB_SCRIPT_COMMON
-        } else {
-            // Default to LATIN for other text
-            harfbuzz_sys::HB_SCRIPT_LATIN
-        };
-        harfbuzz_sys::hb_buffer_set_script(buffer, script);
-        harfbuzz_sys::hb_buffer_set_language(buffer, harfbuzz_sys::hb_language_from_string(
-            b"en\0".as_ptr() as *const i8,
-            -1,
-        ));
+        let isolated = isolate_bidi(name, None);
+        let template = format!("Owner: {} 42 items", isolated.text);
+        let lines = collect_run_order(&template, 16.0, &LayoutOptions::default());
+        assert_eq!(lines.len(), 1);
+        assert!(is_identity(&lines[0]), "The template should keep its logical order: {:?}", lines[0]);
         
-        // Step 9: Shape the buffer
-        // Note: Some fonts (especially emoji fonts) may not support HarfBuzz shaping
-        // If shaping fails, we return None gracefully
-        harfbuzz_sys::hb_shape(font, buffer, ptr::null(), 0);
+        // Controls are stripped and offsets map back to the original
+        assert!(contains_bidi_controls("\u{202E}abc"));
+        assert!(!contains_bidi_controls("abc\u{200F}"));
+        let sanitized = isolate_bidi("a\u{202E}b\u{1F600}", Some(TextDirection::LeftToRight));
+        assert_eq!(sanitized.text, "\u{2066}ab\u{1F600}\u{2069}");
+        assert!(!contains_bidi_controls(&sanitized.text[3..sanitized.text.len() - 3]));
+        assert_eq!(sanitized.original_utf16, vec![0, 0, 2, 3, 4, 5, 5]);
+        assert_eq!(sanitized.original_utf16.len(), utf16_len(&sanitized.text) + 1);
+    }
+
+    #[test]
+    fn test_font_charset() {
+        let runs = collect_runs_with_options("Hello", 16.0, &LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() });
+        let charset = font_charset(&runs[0].font);
+        let contains = |c: u32| charset.iter().any(|&(first, last)| first <= c && c <= last);
+        assert!(contains(0x41));
+        assert!(!contains(0x4E16), "Helvetica has no CJK ideographs");
+        assert!(charset.windows(2).all(|pair| pair[0].1 + 1 < pair[1].0), "Ranges should be sorted and merged");
+        assert!(font_charset(&FontRef::default()).is_empty());
         
-        // Step 10: Get glyph information
-        let mut glyph_count: u32 = 0;
-        let glyph_infos = harfbuzz_sys::hb_buffer_get_glyph_infos(buffer, &mut glyph_count);
-        let glyph_positions = harfbuzz_sys::hb_buffer_get_glyph_positions(buffer, &mut glyph_count);
+        // A supplementary-plane member after the BMP bitmap
+        let mut bitmap = vec![0u8; 8192 + 8193];
+        bitmap[0x41 >> 3] |= 1 << (0x41 & 7);
+        bitmap[0x42 >> 3] |= 1 << (0x42 & 7);
+        bitmap[8192] = 1;
+        bitmap[8193 + (0xF600 >> 3)] |= 1;
+        assert_eq!(charset_ranges(&bitmap), vec![(0x41, 0x42), (0x1F600, 0x1F600)]);
+    }
+
+    #[test]
+    fn test_advance_overrides() {
+        let text = "a\u{E000}b";
+        let runs = collect_runs(text, 16.0);
+        let run = runs.iter().find(|run| run.text.contains('\u{E000}')).expect("PUA run");
+        let plain = shape_run_with_harfbuzz(run).expect("Shaping should succeed");
         
-        if glyph_infos.is_null() || glyph_positions.is_null() || glyph_count == 0 {
-            harfbuzz_sys::hb_buffer_destroy(buffer);
-            harfbuzz_sys::hb_font_destroy(font);
-            CFRelease(ct_font_ptr);
-            return None;
+        let options = ShapeOptions { advance_overrides: vec![('\u{E000}', 20.0)], ..Default::default() };
+        let result = shape_run_with_options(run, &options).expect("Shaping should succeed");
+        let scale = result.font_size / result.position_scale as f64;
+        let pua_cluster = run.text.find('\u{E000}').unwrap() as u32;
+        for i in 0..result.glyph_count {
+            if result.cluster_indices[i] == pua_cluster {
+                assert!((result.x_advances[i] as f64 * scale - 20.0).abs() < 0.01);
+            } else {
+                assert_eq!(result.x_advances[i], plain.x_advances[i], "Other glyphs keep their advance");
+            }
         }
-        
-        // Step 11: Extract glyph data
-        let glyph_count_usize = glyph_count as usize;
-        let mut glyph_ids = Vec::with_capacity(glyph_count_usize);
-        let mut cluster_indices = Vec::with_capacity(glyph_count_usize);
-        let mut x_advances = Vec::with_capacity(glyph_count_usize);
-        let mut y_advances = Vec::with_capacity(glyph_count_usize);
-        
-        for i in 0..glyph_count_usize {
-            let info = *glyph_infos.add(i);
-            let pos = *glyph_positions.add(i);
-            
-            glyph_ids.push(info.codepoint);
-            cluster_indices.push(info.cluster);
-            // HarfBuzz positions are in 26.6 fixed point, convert to i32
-            x_advances.push(pos.x_advance);
-            y_advances.push(pos.y_advance);
+    }
+
+    #[test]
+    fn test_paragraph_direction() {
+        assert_eq!(paragraph_direction("\u{645}\u{631}\u{62D}\u{628}\u{627} hello"), Some(TextDirection::RightToLeft));
+        assert_eq!(paragraph_direction("hello \u{645}\u{631}\u{62D}\u{628}\u{627}"), Some(TextDirection::LeftToRight));
+        assert_eq!(paragraph_direction("123 (\u{5E9}\u{5DC}\u{5D5}\u{5DD})"), Some(TextDirection::RightToLeft));
+        assert_eq!(paragraph_direction("123 !?"), None);
+        assert_eq!(paragraph_direction(""), None);
+        // Isolated text and later paragraphs don't count
+        assert_eq!(paragraph_direction("\u{2068}\u{5E9}\u{2069} ok"), Some(TextDirection::LeftToRight));
+        assert_eq!(paragraph_direction("42\n\u{5E9}"), None);
+        // Directional marks are strong; Arabic-Indic digits are AN, which is not
+        assert_eq!(paragraph_direction("\u{200F}abc"), Some(TextDirection::RightToLeft));
+        assert_eq!(paragraph_direction("\u{200E}\u{5E9}"), Some(TextDirection::LeftToRight));
+        assert_eq!(paragraph_direction("\u{0663} abc"), Some(TextDirection::LeftToRight));
+    }
+
+    #[test]
+    fn test_degenerate_runs_skipped() {
+        // Ranges as CoreText might report them: a zero-length run and a repeated range
+        let reported = [(0, 5), (5, 0), (5, 3), (5, 3), (8, 2)];
+        let mut kept: Vec<(usize, usize)> = Vec::new();
+        for &range in &reported {
+            if !is_degenerate_run(range, kept.last().copied()) {
+                kept.push(range);
+            }
         }
+        assert_eq!(kept, vec![(0, 5), (5, 3), (8, 2)]);
         
-        // Clean up
-        harfbuzz_sys::hb_buffer_destroy(buffer);
-        harfbuzz_sys::hb_font_destroy(font);
-        CFRelease(ct_font_ptr); // Release the font we retained in collect_runs_from_frame
-        
-        Some(ShapingResult {
-            run_text: run.text.clone(),
-            font_name: run.font_name.clone(),
-            glyph_count: glyph_count_usize,
-            glyph_ids,
-            cluster_indices,
-            x_advances,
-            y_advances,
-        })
+        // Equal lengths at different locations are distinct runs
+        assert!(!is_degenerate_run((3, 3), Some((0, 3))));
+        assert!(verify_coverage("Hello 世界\nWorld", &collect_runs("Hello 世界\nWorld", 16.0)).is_ok());
     }
-}
 
-// FFI function that splits text into runs and shapes them with HarfBuzz
-#[no_mangle]
-pub extern "C" fn split_and_shape_text(text: *const i8, font_size: f64) {
-    use std::ffi::CStr;
-    
-    let text_str = unsafe {
-        CStr::from_ptr(text)
-            .to_str()
-            .unwrap_or("")
-    };
-    
-    println!("=== Splitting and Shaping Text ===");
-    println!("Text: \"{}\"", text_str);
-    println!("Font size: {}", font_size);
-    println!("---");
-    
-    // Step 1: Split text into runs
-    let runs = collect_runs(text_str, font_size);
-    println!("Found {} runs", runs.len());
-    println!("---");
-    
-    // Step 2: Shape each run with HarfBuzz
-    for (idx, run) in runs.iter().enumerate() {
-        println!("Run {}: \"{}\"", idx, run.text);
-        println!("  Font: {}", run.font_name);
-        println!("  ptr: 0x{:x}", run.font_ptr);
-        println!("  UTF-16 range: {}..{}", run.start_utf16, run.start_utf16 + run.length_utf16);
+    #[test]
+    fn test_glyph_for_char() {
+        let runs = collect_runs_with_options("A", 16.0, &LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() });
+        let helvetica = &runs[0].font;
+        assert!(matches!(glyph_for_char(helvetica, 'A'), Some(glyph) if glyph != 0));
+        assert_ne!(glyph_for_char(helvetica, 'A'), glyph_for_char(helvetica, 'B'));
+        assert_eq!(glyph_for_char(helvetica, '\u{4E16}'), None);
+        assert_eq!(glyph_for_char(helvetica, '\u{1F600}'), None);
+        assert_eq!(glyph_for_char(&FontRef::default(), 'A'), None);
         
-        if let Some(shaping_result) = shape_run_with_harfbuzz(run) {
-            println!("  Shaping Result:");
-            println!("    Glyph count: {}", shaping_result.glyph_count);
-            println!("    Glyph IDs: {:?}", shaping_result.glyph_ids);
-            println!("    Cluster indices: {:?}", shaping_result.cluster_indices);
-            println!("    X advances: {:?}", shaping_result.x_advances);
-            println!("    Y advances: {:?}", shaping_result.y_advances);
-        } else {
-            println!("  Shaping failed");
-        }
-        println!("---");
+        // Astral characters are looked up through their surrogate pair
+        let emoji = collect_runs("\u{1F600}", 16.0);
+        assert!(glyph_for_char(&emoji[0].font, '\u{1F600}').is_some());
     }
-    
-    println!("=== Done ===");
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::ffi::CString;
 
     #[test]
-    fn test_split_and_shape_text_basic() {
-        // Test with a simple ASCII string
-        let text = "Hello, World!";
-        let c_string = CString::new(text).expect("CString::new failed");
-        let text_ptr = c_string.as_ptr() as *const i8;
+    fn test_pair_width_kerning() {
+        let runs = collect_runs_with_options("AV", 16.0, &LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() });
+        let font = &runs[0].font;
+        let single = |c: char| shaped_width(font, &c.to_string()).unwrap();
         
-        // This should not panic
-        split_and_shape_text(text_ptr, 16.0);
+        let av = pair_width(font, 'A', 'V').unwrap();
+        assert!(av < single('A') + single('V') - 0.1, "AV should be kerned: {} vs {}", av, single('A') + single('V'));
+        let aa = pair_width(font, 'A', 'A').unwrap();
+        assert!((aa - 2.0 * single('A')).abs() < 0.01, "AA has no kerning");
+        assert_eq!(pair_width(&FontRef::default(), 'A', 'V'), Err(ShapeError::NullFont));
     }
 
     #[test]
-    fn test_split_and_shape_text_unicode() {
-        // Test with Unicode characters - just test that runs are collected correctly
-        // We test collect_runs directly to avoid potential font pointer lifetime issues
-        let text = "Hello 世界";
-        let runs = collect_runs(text, 20.0);
+    fn test_logical_order_runs() {
+        let text = "abc \u{5E9}\u{5DC}\u{5D5}\u{5DD} 12 \u{5E2}\u{5D5}\u{5DC}\u{5DD} def";
+        let visual = collect_runs(text, 16.0);
+        assert!(visual.windows(2).any(|pair| pair[0].start_utf16 > pair[1].start_utf16), "Expected visual reordering");
         
-        // Should have at least one run
-        assert!(!runs.is_empty(), "Should have at least one run for Unicode text");
+        let options = LayoutOptions { logical_order: true, ..Default::default() };
+        let logical = collect_runs_with_options(text, 16.0, &options);
+        assert_eq!(logical.len(), visual.len());
+        assert!(logical.windows(2).all(|pair| pair[0].start_utf16 < pair[1].start_utf16));
         
-        // Verify that all text is covered by runs
-        let total_utf16: usize = text.encode_utf16().count();
-        let mut covered: usize = 0;
+        // Path layout honors the option too
+        let path = CGPath::from_rect(CGRect::new(&CGPoint::new(0.0, 0.0), &CGSize::new(1000.0, 200.0)), None);
+        let in_path = collect_runs_in_path(text, 16.0, &path, &options);
+        assert_eq!(in_path.len(), visual.len());
+        assert!(in_path.windows(2).all(|pair| pair[0].start_utf16 < pair[1].start_utf16), "{:?}", in_path);
+    }
+
+    #[test]
+    fn test_line_extents_mixed_fonts() {
+        let large = collect_runs("Big", 32.0);
+        let small = collect_runs("small", 12.0);
+        let line: Vec<TextRun> = large.iter().chain(&small).cloned().collect();
         
-        for run in &runs {
-            assert!(run.start_utf16 <= total_utf16, "Start UTF-16 index out of bounds");
-            assert!(run.start_utf16 + run.length_utf16 <= total_utf16, "End UTF-16 index out of bounds");
-            covered += run.length_utf16;
-            assert!(!run.font_name.is_empty(), "Font name should not be empty");
-        }
+        let (ascent, descent) = line_extents(&line);
+        assert_eq!(ascent, large[0].font.ascent());
+        assert_eq!(descent, large[0].font.descent());
+        assert!(ascent > small[0].font.ascent());
         
-        // All UTF-16 code units should be covered
-        assert_eq!(covered, total_utf16, "All UTF-16 code units should be covered by runs");
+        // A raised run can stick out above the larger font
+        let mut raised = line.clone();
+        raised[1].baseline_offset = 40.0;
+        assert_eq!(line_extents(&raised).0, small[0].font.ascent() + 40.0);
+        assert_eq!(line_extents(&[]), (0.0, 0.0));
     }
 
     #[test]
-    fn test_split_and_shape_text_empty() {
-        // Test with empty string
-        let text = "";
-        let c_string = CString::new(text).expect("CString::new failed");
-        let text_ptr = c_string.as_ptr() as *const i8;
+    fn test_summarize() {
+        let text = "Hello 世界\nWorld \u{1F600}";
+        let summary = summarize(text, 16.0);
+        let lines = collect_lines(text, 16.0, f64::MAX);
+        let runs = collect_runs(text, 16.0);
         
-        // This should not panic
-        split_and_shape_text(text_ptr, 12.0);
+        assert_eq!(summary.line_count, 2);
+        assert_eq!(summary.line_count, lines.len());
+        assert_eq!(summary.run_count, runs.len());
+        assert_eq!(summary.utf16_length, utf16_len(text));
+        let names: Vec<String> = fonts_used(text, 16.0).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(summary.font_names, names);
+        assert!(summary.font_names.iter().any(|name| name.contains("Emoji")));
+        
+        assert_eq!(summarize("", 16.0), LayoutSummary::default());
     }
 
     #[test]
-    fn test_split_and_shape_text_multiline() {
-        // Test with multiline text
-        let text = "Line 1\nLine 2\nLine 3";
-        let c_string = CString::new(text).expect("CString::new failed");
-        let text_ptr = c_string.as_ptr() as *const i8;
+    fn test_shape_with_feature_string() {
+        let runs = collect_runs_with_options("AV", 16.0, &LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() });
+        let kerned = shape_with_feature_string(&runs[0], "", &ShapeOptions::default()).unwrap();
+        let unkerned = shape_with_feature_string(&runs[0], "kern off", &ShapeOptions::default()).unwrap();
+        assert!(
+            unkerned.x_advances.iter().sum::<i32>() > kerned.x_advances.iter().sum::<i32>(),
+            "Disabling kern should widen AV: {:?} vs {:?}", unkerned.x_advances, kerned.x_advances
+        );
+        assert_eq!(shape_run_with_harfbuzz(&runs[0]).unwrap().x_advances, kerned.x_advances);
         
-        // This should not panic
-        split_and_shape_text(text_ptr, 14.0);
+        let parsed = parse_features("liga on, smcp, tnum").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].tag, u32::from_be_bytes(*b"liga"));
+        assert_eq!(parsed[0].value, 1);
+        assert_eq!(parse_features("-kern").unwrap()[0].value, 0);
+        assert_eq!(parse_features("liga=").unwrap_err(), ShapeError::InvalidFeature);
+        assert!(shape_with_feature_string(&runs[0], "kern off, ???", &ShapeOptions::default()).is_err());
     }
 
     #[test]
-    fn test_split_and_shape_text_emoji() {
-        // Test with multiline text
-        let text = "🤔💇‍♀️";
-        let c_string = CString::new(text).expect("CString::new failed");
-        let text_ptr = c_string.as_ptr() as *const i8;
+    fn test_fallback_stats() {
+        let text = "Hello world, this is mostly Latin \u{4E16}\u{754C}";
+        let stats = fallback_stats(text, Some("Helvetica"), 16.0);
+        assert_eq!(stats.base_font, "Helvetica");
+        assert_eq!(stats.total_utf16, utf16_len(text));
+        assert_eq!(stats.base_utf16(), utf16_len(text) - 2);
+        assert_eq!(stats.fallback_utf16(), 2, "The CJK characters should fall back: {:?}", stats.per_font);
+        assert!(stats.per_font.iter().any(|(name, units)| *name != "Helvetica" && *units == 2));
+        assert!(stats.base_fraction() > 0.9 && stats.base_fraction() < 1.0);
         
-        // This should not panic
-        split_and_shape_text(text_ptr, 14.0);
+        assert_eq!(fallback_stats("", None, 16.0).base_fraction(), 1.0);
     }
 
+    #[test]
+    fn test_source_text_for_glyph() {
+        let runs = collect_runs_with_options("fit", 16.0, &LayoutOptions { font_name: Some(String::from("Helvetica")), ..Default::default() });
+        // Ask for ligatures, but like test_coretext_string_indices_ligature accept either outcome
+        let mut result = shape_with_feature_string(&runs[0], "liga", &ShapeOptions::default()).expect("Shaping should succeed");
+        match result.glyph_count {
+            // Ligated: the first glyph stands for both characters
+            2 => {
+                assert_eq!(source_text_for_glyph(&runs[0], &result, 0), "fi");
+                assert_eq!(source_text_for_glyph(&runs[0], &result, 1), "t");
+            }
+            _ => {
+                assert_eq!(result.glyph_count, 3, "{:?}", result.cluster_indices);
+                assert_eq!(source_text_for_glyph(&runs[0], &result, 0), "f");
+                assert_eq!(source_text_for_glyph(&runs[0], &result, 1), "i");
+                assert_eq!(source_text_for_glyph(&runs[0], &result, 2), "t");
+            }
+        }
+        assert_eq!(source_text_for_glyph(&runs[0], &result, result.glyph_count), "");
+        
+        // Two glyphs from one character both map back to it; RTL glyph order is handled
+        let mut run = runs[0].clone();
+        run.text = String::from("\u{E9}x");
+        result.glyph_count = 3;
+        result.cluster_indices = vec![2, 0, 0];
+        assert_eq!(source_text_for_glyph(&run, &result, 0), "x");
+        assert_eq!(source_text_for_glyph(&run, &result, 1), "\u{E9}");
+        assert_eq!(source_text_for_glyph(&run, &result, 2), "\u{E9}");
+    }
 
     #[test]
-    fn test_collect_runs_basic() {
-        // Test the collect_runs function directly
-        let text = "Hello, World!";
-        let runs = collect_runs(text, 16.0);
+    fn test_shaping_backends_agree_on_latin() {
+        let runs = collect_runs("Hello world", 16.0);
+        let options = ShapeOptions::default();
+        let harfbuzz = shape_run_with_backend(&runs[0], &options, ShapingBackend::HarfBuzz).expect("HarfBuzz shaping");
+        let coretext = shape_run_with_backend(&runs[0], &options, ShapingBackend::CoreText).expect("CoreText shaping");
         
-        // Should have at least one run
-        assert!(!runs.is_empty(), "Should have at least one run");
+        assert_eq!(coretext.glyph_count, harfbuzz.glyph_count);
+        assert_eq!(coretext.glyph_ids, harfbuzz.glyph_ids);
+        assert_eq!(coretext.cluster_indices, harfbuzz.cluster_indices);
+        assert_eq!(coretext.script, *b"Latn");
+        // Positions are in different units, so compare the total width in points
+        let width = |result: &ShapingResult| *result.cumulative_x().last().unwrap();
+        assert!((width(&coretext) - width(&harfbuzz)).abs() < 1.0, "{} vs {}", width(&coretext), width(&harfbuzz));
         
-        // Verify run properties
-        for run in &runs {
-            assert!(!run.text.is_empty() || run.length_utf16 == 0, "Run text should not be empty unless length is 0");
-            assert!(!run.font_name.is_empty(), "Font name should not be empty");
-            assert!(run.font_ptr != 0, "Font pointer should not be zero");
-        }
+        let mut fontless = runs[0].clone();
+        fontless.font = FontRef::default();
+        assert_eq!(shape_run_with_backend(&fontless, &options, ShapingBackend::CoreText).unwrap_err(), ShapeError::NullFont);
     }
 
     #[test]
-    fn test_collect_runs_unicode() {
-        // Test with Unicode characters
-        let text = "Hello 世界";
-        let runs = collect_runs(text, 20.0);
+    fn test_content_hash() {
+        let runs = collect_runs("Hello", 16.0);
+        let first = shape_run_with_harfbuzz(&runs[0]).unwrap();
+        let second = shape_run_with_harfbuzz(&collect_runs("Hello", 16.0)[0]).unwrap();
+        assert_eq!(first.content_hash(), second.content_hash());
         
-        assert!(!runs.is_empty(), "Should have at least one run");
+        let other = shape_run_with_harfbuzz(&collect_runs("Hellp", 16.0)[0]).unwrap();
+        assert_ne!(first.content_hash(), other.content_hash());
+        let larger = shape_run_with_harfbuzz(&collect_runs("Hello", 24.0)[0]).unwrap();
+        assert_ne!(first.content_hash(), larger.content_hash());
         
-        // Verify UTF-16 ranges are valid
-        let total_utf16: usize = text.encode_utf16().count();
-        let mut covered: usize = 0;
+        // Only the geometry counts, not the text it came from
+        let mut relabeled = second;
+        relabeled.run_text = String::from("something else");
+        assert_eq!(first.content_hash(), relabeled.content_hash());
+    }
+
+    #[test]
+    fn test_text_run_from_raw_overflow() {
+        // A corrupt location near usize::MAX overflows when the length is added
+        let text_utf16: Vec<u16> = "Hello".encode_utf16().collect();
+        let raw_run = RunRaw {
+            utf16_location: usize::MAX,
+            utf16_length: 2,
+            postscript_name: String::from("Helvetica"),
+            font_ptr: ptr::null(),
+            ct_run: ptr::null(),
+            line_index: 0,
+            font_size: 16.0,
+            decorations: TextDecorations::default(),
+            baseline_offset: 0.0,
+            status: RunStatus::default(),
+            glyph_count: 0,
+        };
         
-        for run in &runs {
-            assert!(run.start_utf16 <= total_utf16, "Start UTF-16 index out of bounds");
-            assert!(run.start_utf16 + run.length_utf16 <= total_utf16, "End UTF-16 index out of bounds");
-            covered += run.length_utf16;
-        }
+        assert!(text_run_from_raw(raw_run, &text_utf16, InvalidUtf16::default()).is_none(), "Overflowing range should be rejected");
+    }
+
+    #[test]
+    fn test_text_run_from_raw_valid() {
+        let text_utf16: Vec<u16> = "Hello".encode_utf16().collect();
+        let raw_run = RunRaw {
+            utf16_location: 1,
+            utf16_length: 3,
+            postscript_name: String::from("Helvetica"),
+            font_ptr: ptr::null(),
+            ct_run: ptr::null(),
+            line_index: 0,
+            font_size: 16.0,
+            decorations: TextDecorations::default(),
+            baseline_offset: 0.0,
+            status: RunStatus::default(),
+            glyph_count: 0,
+        };
         
-        // All UTF-16 code units should be covered
-        assert_eq!(covered, total_utf16, "All UTF-16 code units should be covered by runs");
+        let run = text_run_from_raw(raw_run, &text_utf16, InvalidUtf16::default()).expect("Valid range should convert");
+        assert_eq!(run.text, "ell");
+        assert_eq!(run.start_utf16, 1);
+        assert_eq!(run.length_utf16, 3);
     }
 }
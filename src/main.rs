@@ -84,7 +84,7 @@ fn split_str_into_runs(text: &str, font_size: f64) {
         
         CFAttributedStringSetAttribute(
             attributed_string.as_concrete_TypeRef() as *mut c_void,
-            CFRange::init(0, text_length as i64),
+            CFRange::init(0, text_length),
             font_key.as_concrete_TypeRef() as *const c_void,
             font.as_concrete_TypeRef() as *const c_void,
         );
@@ -198,8 +198,18 @@ fn split_str_into_runs(text: &str, font_size: f64) {
                 
                 // Convert UTF-16 indices to UTF-8 byte indices
                 let text_utf16: Vec<u16> = text.encode_utf16().collect();
-                let run_text = if start_utf16 + length_utf16 <= text_utf16.len() {
-                    let utf16_slice = &text_utf16[start_utf16..start_utf16 + length_utf16];
+                let end_utf16 = match start_utf16.checked_add(length_utf16) {
+                    Some(end) => end,
+                    None => {
+                        println!(
+                            "  Run {}: skipped, UTF-16 range overflows: start {} + length {}",
+                            run_idx, start_utf16, length_utf16
+                        );
+                        continue;
+                    }
+                };
+                let run_text = if end_utf16 <= text_utf16.len() {
+                    let utf16_slice = &text_utf16[start_utf16..end_utf16];
                     match String::from_utf16(utf16_slice) {
                         Ok(s) => s,
                         Err(_) => String::from(""),